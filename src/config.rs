@@ -0,0 +1,139 @@
+use std::{fs, io, str::FromStr};
+use thiserror::Error;
+
+pub static CONFIG_PATH: &str = "/system_ext/etc/notcatd.conf";
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("Cannot read config: {0}")]
+    Io(#[from] io::Error),
+    #[error("Syntax error on line {0}: {1}")]
+    Syntax(usize, String),
+    #[error("Invalid value '{2}' for '{1}' in [{0}]")]
+    InvalidValue(String, String, String),
+    /// A section needs an optional feature this binary was built without.
+    #[cfg_attr(feature = "scripting", allow(dead_code))]
+    #[error("Cannot use [{0}]: built without {1}")]
+    Unsupported(String, &'static str),
+}
+
+/// One `[kind name]` block of the config file. Keys may repeat.
+#[derive(Debug)]
+pub struct Section {
+    pub kind: String,
+    pub name: Option<String>,
+    entries: Vec<(String, String)>,
+}
+
+impl Section {
+    pub fn title(&self) -> String {
+        match &self.name {
+            Some(name) => format!("{} {}", self.kind, name),
+            None => self.kind.clone(),
+        }
+    }
+
+    /// Last value of `key`, so later lines override earlier ones.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.entries
+            .iter()
+            .rev()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Every value of a repeatable `key`, in file order.
+    pub fn get_all<'a>(&'a self, key: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.entries
+            .iter()
+            .filter(move |(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn get_parsed<T: FromStr>(&self, key: &str) -> Result<Option<T>, ConfigError> {
+        match self.get(key) {
+            Some(value) => value
+                .parse()
+                .map(Some)
+                .map_err(|_| self.invalid(key, value)),
+            None => Ok(None),
+        }
+    }
+
+    /// Comma separated list value, empty when the key is absent.
+    pub fn get_list(&self, key: &str) -> Vec<&str> {
+        self.get(key)
+            .map(|v| {
+                v.split(',')
+                    .map(str::trim)
+                    .filter(|s| !s.is_empty())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    pub fn invalid(&self, key: &str, value: &str) -> ConfigError {
+        ConfigError::InvalidValue(self.title(), key.to_string(), value.to_string())
+    }
+}
+
+/// Daemon configuration in a simple INI dialect:
+///
+/// ```text
+/// # comment
+/// [kind optional-name]
+/// key = value
+/// ```
+#[derive(Debug, Default)]
+pub struct Config {
+    sections: Vec<Section>,
+}
+
+impl Config {
+    pub fn load(path: &str) -> Result<Self, ConfigError> {
+        Config::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn parse(text: &str) -> Result<Self, ConfigError> {
+        let mut sections: Vec<Section> = Vec::new();
+        for (index, raw_line) in text.lines().enumerate() {
+            let line = raw_line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+                continue;
+            }
+            if let Some(header) = line.strip_prefix('[') {
+                let header = header
+                    .strip_suffix(']')
+                    .ok_or_else(|| ConfigError::Syntax(index + 1, String::from("missing ']'")))?;
+                let mut parts = header.split_whitespace();
+                let kind = parts
+                    .next()
+                    .ok_or_else(|| ConfigError::Syntax(index + 1, String::from("empty section")))?;
+                sections.push(Section {
+                    kind: kind.to_string(),
+                    name: parts.next().map(str::to_string),
+                    entries: Vec::new(),
+                });
+                continue;
+            }
+            let (key, value) = line.split_once('=').ok_or_else(|| {
+                ConfigError::Syntax(index + 1, String::from("expected key = value"))
+            })?;
+            let section = sections.last_mut().ok_or_else(|| {
+                ConfigError::Syntax(index + 1, String::from("key outside of a section"))
+            })?;
+            section
+                .entries
+                .push((key.trim().to_string(), value.trim().to_string()));
+        }
+        Ok(Config { sections })
+    }
+
+    pub fn sections<'a>(&'a self, kind: &'a str) -> impl Iterator<Item = &'a Section> + 'a {
+        self.sections.iter().filter(move |s| s.kind == kind)
+    }
+
+    pub fn section(&self, kind: &str) -> Option<&Section> {
+        self.sections.iter().find(|s| s.kind == kind)
+    }
+}
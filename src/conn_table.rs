@@ -0,0 +1,395 @@
+use crate::{log::*, log_def::*, prot_handler::ProtocolHandler, stats};
+use nix::{sys::epoll::*, unistd::close};
+use std::{
+    collections::HashMap,
+    os::unix::io::RawFd,
+    sync::atomic::Ordering,
+    time::{Duration, Instant},
+};
+
+static MAX_CLIENTS: usize = 64;
+static HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+static IDLE_TIMEOUT: Duration = Duration::from_secs(600);
+
+#[derive(Debug, Clone, Copy)]
+pub struct ConnectionLimits {
+    pub max_clients: usize,
+    pub handshake_timeout: Duration,
+    pub idle_timeout: Option<Duration>, // None disables idle disconnects
+}
+
+impl Default for ConnectionLimits {
+    fn default() -> Self {
+        ConnectionLimits {
+            max_clients: MAX_CLIENTS,
+            handshake_timeout: HANDSHAKE_TIMEOUT,
+            idle_timeout: Some(IDLE_TIMEOUT),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    Handshake,
+    Active,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseReason {
+    Disconnected,
+    HungUp,
+    ReadError,
+    ProtocolError,
+    HandshakeTimeout,
+    IdleTimeout,
+}
+
+impl CloseReason {
+    /// The client went away without a clean shutdown of its socket.
+    pub fn is_abnormal(&self) -> bool {
+        matches!(
+            self,
+            CloseReason::HungUp | CloseReason::ReadError | CloseReason::ProtocolError
+        )
+    }
+}
+
+struct Connection {
+    state: ConnectionState,
+    accepted_at: Instant,
+    last_activity: Instant,
+}
+
+impl Connection {
+    fn deadline(&self, limits: &ConnectionLimits) -> Option<Instant> {
+        match self.state {
+            ConnectionState::Handshake => Some(self.accepted_at + limits.handshake_timeout),
+            ConnectionState::Active => limits.idle_timeout.map(|t| self.last_activity + t),
+        }
+    }
+}
+
+/// Owns the lifecycle of every accepted client: epoll registration, state,
+/// timeouts and teardown. All client removal goes through `close`.
+pub struct ConnectionTable {
+    epfd: RawFd,
+    limits: ConnectionLimits,
+    connections: HashMap<RawFd, Connection>,
+}
+
+impl ConnectionTable {
+    pub fn new(epfd: RawFd, limits: ConnectionLimits) -> Self {
+        ConnectionTable {
+            epfd,
+            limits,
+            connections: HashMap::new(),
+        }
+    }
+
+    /// Registers a freshly accepted client. Returns false if the client was
+    /// rejected, in which case its fd has already been closed.
+    pub fn accept(&mut self, fd: RawFd, now: Instant) -> bool {
+        if self.connections.len() >= self.limits.max_clients {
+            logw!(
+                LOG_TAG,
+                "[ConnectionTable] Client limit {} reached, rejecting {}",
+                self.limits.max_clients,
+                fd
+            );
+            stats::global()
+                .server
+                .rejected
+                .fetch_add(1, Ordering::Relaxed);
+            close_fd(fd);
+            return false;
+        }
+        let mut ev = EpollEvent::new(EpollFlags::EPOLLIN | EpollFlags::EPOLLET, fd as u64);
+        if let Err(e) = epoll_ctl(self.epfd, EpollOp::EpollCtlAdd, fd, &mut ev) {
+            loge!(
+                LOG_TAG,
+                "[ConnectionTable] Error adding client {} to epoll: {}",
+                fd,
+                e
+            );
+            close_fd(fd);
+            return false;
+        }
+        self.connections.insert(
+            fd,
+            Connection {
+                state: ConnectionState::Handshake,
+                accepted_at: now,
+                last_activity: now,
+            },
+        );
+        stats::global()
+            .server
+            .accepted
+            .fetch_add(1, Ordering::Relaxed);
+        logv!(LOG_TAG, "[ConnectionTable] Added client {}", fd);
+        true
+    }
+
+    /// Records activity on `fd`. Once the protocol handler has registered the
+    /// client, the connection leaves the handshake state.
+    pub fn touch(&mut self, fd: RawFd, now: Instant, handshake_done: bool) {
+        if let Some(conn) = self.connections.get_mut(&fd) {
+            conn.last_activity = now;
+            if handshake_done && conn.state == ConnectionState::Handshake {
+                conn.state = ConnectionState::Active;
+            }
+        }
+    }
+
+    pub fn close(&mut self, fd: RawFd, reason: CloseReason, prot_handler: &mut ProtocolHandler) {
+        if self.connections.remove(&fd).is_none() {
+            return;
+        }
+        logv!(
+            LOG_TAG,
+            "[ConnectionTable] Closing client {}: {:?}",
+            fd,
+            reason
+        );
+        let server_stats = &stats::global().server;
+        server_stats.closed.fetch_add(1, Ordering::Relaxed);
+        if matches!(
+            reason,
+            CloseReason::HandshakeTimeout | CloseReason::IdleTimeout
+        ) {
+            server_stats.timeouts.fetch_add(1, Ordering::Relaxed);
+        }
+        if reason.is_abnormal() {
+            prot_handler.report_abnormal_close(fd, reason);
+        }
+        prot_handler.remove_fd(fd);
+        if let Err(e) = epoll_ctl(self.epfd, EpollOp::EpollCtlDel, fd, None) {
+            loge!(
+                LOG_TAG,
+                "[ConnectionTable] Error removing client {}: {}",
+                fd,
+                e
+            );
+        }
+        close_fd(fd);
+    }
+
+    /// Closes every connection whose handshake or idle deadline has passed
+    /// and returns them with the reason.
+    pub fn expire(
+        &mut self,
+        now: Instant,
+        prot_handler: &mut ProtocolHandler,
+    ) -> Vec<(RawFd, CloseReason)> {
+        let expired: Vec<(RawFd, CloseReason)> = self
+            .connections
+            .iter()
+            .filter(|(_, conn)| conn.deadline(&self.limits).is_some_and(|d| d <= now))
+            .map(|(fd, conn)| {
+                let reason = match conn.state {
+                    ConnectionState::Handshake => CloseReason::HandshakeTimeout,
+                    ConnectionState::Active => CloseReason::IdleTimeout,
+                };
+                (*fd, reason)
+            })
+            .collect();
+        for (fd, reason) in &expired {
+            logw!(
+                LOG_TAG,
+                "[ConnectionTable] Client {} timed out: {:?}",
+                fd,
+                reason
+            );
+            self.close(*fd, *reason, prot_handler);
+        }
+        expired
+    }
+
+    /// Milliseconds until the nearest deadline, in the form `epoll_wait` expects.
+    pub fn next_timeout(&self, now: Instant) -> isize {
+        match self
+            .connections
+            .values()
+            .filter_map(|conn| conn.deadline(&self.limits))
+            .min()
+        {
+            Some(deadline) => {
+                let wait = deadline.saturating_duration_since(now);
+                // round up so we never wake just before the deadline
+                (wait.as_millis() + 1).min(isize::MAX as u128) as isize
+            }
+            None => -1,
+        }
+    }
+
+    #[cfg(test)]
+    pub fn state(&self, fd: RawFd) -> Option<ConnectionState> {
+        self.connections.get(&fd).map(|conn| conn.state)
+    }
+
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.connections.len()
+    }
+}
+
+fn close_fd(fd: RawFd) {
+    match close(fd) {
+        Ok(_) => logv!(LOG_TAG, "[ConnectionTable] Closed client {}", fd),
+        Err(e) => loge!(
+            LOG_TAG,
+            "[ConnectionTable] Error closing client {}: {}",
+            fd,
+            e
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::record_queue::{self, QueueSettings};
+    use std::os::unix::io::IntoRawFd;
+    use std::os::unix::net::UnixStream;
+
+    fn limits() -> ConnectionLimits {
+        ConnectionLimits {
+            max_clients: 2,
+            handshake_timeout: Duration::from_secs(5),
+            idle_timeout: Some(Duration::from_secs(60)),
+        }
+    }
+
+    fn table(limits: ConnectionLimits) -> ConnectionTable {
+        ConnectionTable::new(epoll_create1(EpollCreateFlags::empty()).unwrap(), limits)
+    }
+
+    fn handler() -> ProtocolHandler {
+        let (sender, _) = record_queue::channel(QueueSettings::default());
+        ProtocolHandler::new(sender)
+    }
+
+    /// One end of a socket pair; the other end is dropped.
+    fn client_fd() -> RawFd {
+        let (ours, _) = UnixStream::pair().unwrap();
+        ours.into_raw_fd()
+    }
+
+    #[test]
+    fn accept_up_to_max_clients() {
+        let mut table = table(limits());
+        let now = Instant::now();
+        let (first, second, third) = (client_fd(), client_fd(), client_fd());
+        assert!(table.accept(first, now));
+        assert!(table.accept(second, now));
+        assert!(!table.accept(third, now));
+        assert_eq!(table.len(), 2);
+        assert_eq!(table.state(first), Some(ConnectionState::Handshake));
+        assert_eq!(table.state(third), None);
+    }
+
+    #[test]
+    fn handshake_deadline() {
+        let mut table = table(limits());
+        let mut handler = handler();
+        let start = Instant::now();
+        let fd = client_fd();
+        table.accept(fd, start);
+        let before = start + Duration::from_millis(4999);
+        assert!(table.expire(before, &mut handler).is_empty());
+        let after = start + Duration::from_secs(5);
+        assert_eq!(
+            table.expire(after, &mut handler),
+            vec![(fd, CloseReason::HandshakeTimeout)]
+        );
+        assert_eq!(table.len(), 0);
+    }
+
+    #[test]
+    fn touch_resets_idle_deadline() {
+        let mut table = table(limits());
+        let mut handler = handler();
+        let start = Instant::now();
+        let fd = client_fd();
+        table.accept(fd, start);
+        table.touch(fd, start + Duration::from_secs(1), true);
+        assert_eq!(table.state(fd), Some(ConnectionState::Active));
+        table.touch(fd, start + Duration::from_secs(50), false);
+        // the handshake deadline no longer applies, the idle one moved on
+        assert!(
+            table
+                .expire(start + Duration::from_secs(100), &mut handler)
+                .is_empty()
+        );
+        assert_eq!(
+            table.expire(start + Duration::from_secs(110), &mut handler),
+            vec![(fd, CloseReason::IdleTimeout)]
+        );
+    }
+
+    #[test]
+    fn touch_before_handshake_keeps_handshake_deadline() {
+        let mut table = table(limits());
+        let mut handler = handler();
+        let start = Instant::now();
+        let fd = client_fd();
+        table.accept(fd, start);
+        table.touch(fd, start + Duration::from_secs(4), false);
+        assert_eq!(
+            table.expire(start + Duration::from_secs(5), &mut handler),
+            vec![(fd, CloseReason::HandshakeTimeout)]
+        );
+    }
+
+    #[test]
+    fn idle_timeout_disabled() {
+        let mut table = table(ConnectionLimits {
+            idle_timeout: None,
+            ..limits()
+        });
+        let mut handler = handler();
+        let start = Instant::now();
+        let fd = client_fd();
+        table.accept(fd, start);
+        table.touch(fd, start, true);
+        assert!(
+            table
+                .expire(start + Duration::from_secs(86400), &mut handler)
+                .is_empty()
+        );
+        assert_eq!(table.next_timeout(start), -1);
+    }
+
+    #[test]
+    fn close_is_idempotent() {
+        let mut table = table(limits());
+        let mut handler = handler();
+        let now = Instant::now();
+        let (fd, other) = (client_fd(), client_fd());
+        table.accept(fd, now);
+        table.accept(other, now);
+        table.close(fd, CloseReason::Disconnected, &mut handler);
+        table.close(fd, CloseReason::Disconnected, &mut handler);
+        assert_eq!(table.len(), 1);
+        assert_eq!(table.state(fd), None);
+        assert_eq!(table.state(other), Some(ConnectionState::Handshake));
+    }
+
+    #[test]
+    fn next_timeout_picks_nearest_deadline() {
+        let mut table = table(limits());
+        let start = Instant::now();
+        assert_eq!(table.next_timeout(start), -1);
+        let (active, pending) = (client_fd(), client_fd());
+        table.accept(active, start);
+        table.touch(active, start, true);
+        assert_eq!(table.next_timeout(start), 60001);
+        table.accept(pending, start + Duration::from_secs(1));
+        // the handshake of `active` is done: its idle deadline is at 60s
+        assert_eq!(table.next_timeout(start + Duration::from_secs(2)), 4001);
+        table.touch(pending, start + Duration::from_secs(2), true);
+        assert_eq!(table.next_timeout(start + Duration::from_secs(2)), 58001);
+        // a passed deadline means no wait
+        assert_eq!(table.next_timeout(start + Duration::from_secs(100)), 1);
+    }
+}
@@ -0,0 +1,127 @@
+use crate::{
+    log::*, log_def::*, msg_filter::metrics, msg_sink::local_file, msg_source::child::Supervisor,
+    msg_srv::MessageServer, msg_srv::init_listener_fd, ring_buffer::RingBuffer, stats,
+};
+use nix::sys::socket::{getsockopt, sockopt::PeerCredentials};
+use std::{
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    os::unix::io::FromRawFd,
+    os::unix::net::{UnixListener, UnixStream},
+    sync::Arc,
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+static CTL_SOCKET_NAME: &str = "notcat_ctl";
+static CTL_READ_TIMEOUT: Duration = Duration::from_secs(2);
+static CTL_WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+static DEFAULT_TRACE_LIMIT: usize = 1000;
+/// Peers allowed to run control commands: root and system (`spawn` runs a
+/// command line as notcatd).
+static CTL_ALLOWED_UIDS: [u32; 2] = [0, 1000];
+
+/// Answers one-line text commands received on the control socket.
+pub struct ControlHandler {
+    supervisor: Supervisor,
+    ring_buffer: Arc<RingBuffer>,
+}
+
+impl ControlHandler {
+    pub fn new(supervisor: Supervisor, ring_buffer: Arc<RingBuffer>) -> Self {
+        ControlHandler {
+            supervisor,
+            ring_buffer,
+        }
+    }
+
+    /// Runs one command and writes its response to `out`. `trace` writes its
+    /// results as it finds them; the other responses are written whole.
+    pub fn handle_command(&mut self, line: &str, out: &mut impl Write) -> io::Result<()> {
+        let mut args = line.split_whitespace();
+        let response = match args.next() {
+            Some("stats") => stats::global().report(),
+            Some("metrics") => metrics::exposition(),
+            Some("dump") => self.ring_buffer.dump_command(args),
+            Some("trace") => {
+                let trace_id = args.next();
+                let limit = match args.next() {
+                    Some(limit) => limit.parse().ok().filter(|limit| *limit > 0),
+                    None => Some(DEFAULT_TRACE_LIMIT),
+                };
+                match (trace_id, limit) {
+                    (Some(trace_id), Some(limit)) => {
+                        match local_file::query_trace(trace_id, limit, out) {
+                            Ok(found) if found == limit => {
+                                format!("(stopped after {} lines)\n", limit)
+                            }
+                            Ok(_) => String::new(),
+                            Err(e) => format!("{}\n", e),
+                        }
+                    }
+                    _ => String::from("usage: trace <trace id|traceparent> [limit]\n"),
+                }
+            }
+            Some("children") => self.supervisor.list(),
+            Some("spawn") => self.supervisor.spawn_command(args),
+            Some("stop") => match args.next() {
+                Some(name) => match self.supervisor.stop(name) {
+                    Ok(()) => format!("stopping {}\n", name),
+                    Err(e) => format!("{}\n", e),
+                },
+                None => String::from("usage: stop <name>\n"),
+            },
+            Some("help") | None => {
+                String::from("commands: stats, metrics, dump, trace, children, spawn, stop, help\n")
+            }
+            Some(cmd) => format!("unknown command: {}\n", cmd),
+        };
+        out.write_all(response.as_bytes())
+    }
+
+    fn serve(&mut self, stream: UnixStream) -> io::Result<()> {
+        let uid = getsockopt(&stream, PeerCredentials)?.uid();
+        if !CTL_ALLOWED_UIDS.contains(&uid) {
+            logw!(
+                LOG_TAG,
+                "[ControlServer] Refusing commands from uid {}",
+                uid
+            );
+            return (&stream).write_all(b"permission denied\n");
+        }
+        stream.set_read_timeout(Some(CTL_READ_TIMEOUT))?;
+        stream.set_write_timeout(Some(CTL_WRITE_TIMEOUT))?;
+        let mut line = String::new();
+        BufReader::new(&stream).read_line(&mut line)?;
+        let mut out = BufWriter::new(&stream);
+        self.handle_command(line.trim(), &mut out)?;
+        out.flush()
+    }
+}
+
+pub struct ControlServer;
+
+impl MessageServer<ControlHandler, JoinHandle<io::Result<()>>> for ControlServer {
+    fn run(mut handler: ControlHandler) -> io::Result<JoinHandle<io::Result<()>>> {
+        let listener_fd = init_listener_fd(CTL_SOCKET_NAME)?;
+        let listener = unsafe { UnixListener::from_raw_fd(listener_fd) };
+
+        let handle = thread::spawn(move || {
+            logv!(LOG_TAG, "[ControlServer] Listening on {}", CTL_SOCKET_NAME);
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        if let Err(e) = handler.serve(stream) {
+                            logw!(LOG_TAG, "[ControlServer] Error serving request: {}", e);
+                        }
+                    }
+                    Err(e) => {
+                        loge!(LOG_TAG, "[ControlServer] Error accepting client: {}", e);
+                    }
+                }
+            }
+            Ok(())
+        });
+
+        Ok(handle)
+    }
+}
@@ -1,6 +1,14 @@
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// UTC offset of the local timezone in milliseconds, with the unix minute it
+/// was looked up in.
+static UTC_OFFSET: Mutex<Option<(i64, i64)>> = Mutex::new(None);
+
 #[allow(dead_code)]
 #[repr(u8)]
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum LogPriority {
     Verbose,
     Debug,
@@ -9,8 +17,35 @@ pub enum LogPriority {
     Error,
     Fatal,
 }
+impl FromStr for LogPriority {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "v" | "verbose" => Ok(LogPriority::Verbose),
+            "d" | "debug" => Ok(LogPriority::Debug),
+            "i" | "info" => Ok(LogPriority::Info),
+            "w" | "warn" => Ok(LogPriority::Warn),
+            "e" | "error" => Ok(LogPriority::Error),
+            "f" | "fatal" => Ok(LogPriority::Fatal),
+            _ => Err(()),
+        }
+    }
+}
+impl LogPriority {
+    pub fn name(self) -> &'static str {
+        match self {
+            LogPriority::Verbose => "verbose",
+            LogPriority::Debug => "debug",
+            LogPriority::Info => "info",
+            LogPriority::Warn => "warn",
+            LogPriority::Error => "error",
+            LogPriority::Fatal => "fatal",
+        }
+    }
+}
 #[allow(dead_code)]
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct LogTimeStamp {
     pub year: u16,
     pub month: u8,
@@ -20,11 +55,338 @@ pub struct LogTimeStamp {
     pub second: u8,
     pub millisecond: u16,
 }
+impl LogTimeStamp {
+    /// Current wall-clock time in the local timezone.
+    pub fn now() -> Self {
+        let since_epoch = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default();
+        LogTimeStamp::from_unix(
+            since_epoch.as_secs() as i64,
+            since_epoch.subsec_millis() as u16,
+        )
+    }
+
+    /// Name and value of the first field outside of its calendar range.
+    pub fn invalid_field(&self) -> Option<(&'static str, u16)> {
+        if !(1..=12).contains(&self.month) {
+            Some(("month", self.month as u16))
+        } else if self.day == 0 || self.day > days_in_month(self.year, self.month) {
+            Some(("day", self.day as u16))
+        } else if self.hour > 23 {
+            Some(("hour", self.hour as u16))
+        } else if self.minute > 59 {
+            Some(("minute", self.minute as u16))
+        } else if self.second > 59 {
+            Some(("second", self.second as u16))
+        } else if self.millisecond > 999 {
+            Some(("millisecond", self.millisecond))
+        } else {
+            None
+        }
+    }
+
+    /// Moves every field to the nearest valid value.
+    pub fn clamped(self) -> Self {
+        let month = self.month.clamp(1, 12);
+        LogTimeStamp {
+            year: self.year,
+            month,
+            day: self.day.clamp(1, days_in_month(self.year, month)),
+            hour: self.hour.min(23),
+            minute: self.minute.min(59),
+            second: self.second.min(59),
+            millisecond: self.millisecond.min(999),
+        }
+    }
+
+    #[cfg_attr(not(feature = "scripting"), allow(dead_code))]
+    pub fn from_unix_ms(ms: i64) -> Self {
+        LogTimeStamp::from_unix(ms.div_euclid(1000), ms.rem_euclid(1000) as u16)
+    }
+
+    /// Milliseconds since the epoch, reading the fields as local time.
+    #[cfg_attr(not(feature = "scripting"), allow(dead_code))]
+    pub fn to_unix_ms(&self) -> i64 {
+        let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+        tm.tm_year = self.year as i32 - 1900;
+        tm.tm_mon = self.month as i32 - 1;
+        tm.tm_mday = self.day as i32;
+        tm.tm_hour = self.hour as i32;
+        tm.tm_min = self.minute as i32;
+        tm.tm_sec = self.second as i32;
+        tm.tm_isdst = -1;
+        let secs = unsafe { libc::mktime(&mut tm) };
+        secs as i64 * 1000 + self.millisecond as i64
+    }
+
+    /// Milliseconds of the fields on a local wall-clock scale, counted from
+    /// 1970-01-01 00:00 local time without a timezone lookup. Comparable with
+    /// `wall_clock_ms`.
+    pub fn to_wall_ms(&self) -> i64 {
+        let days = days_from_civil(self.year as i64, self.month as i64, self.day as i64);
+        let secs =
+            ((days * 24 + self.hour as i64) * 60 + self.minute as i64) * 60 + self.second as i64;
+        secs * 1000 + self.millisecond as i64
+    }
+
+    /// Inverse of `to_wall_ms`.
+    pub fn from_wall_ms(ms: i64) -> Self {
+        let (days, ms) = (ms.div_euclid(86_400_000), ms.rem_euclid(86_400_000));
+        let (year, month, day) = civil_from_days(days);
+        LogTimeStamp {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: (ms / 3_600_000) as u8,
+            minute: (ms / 60_000 % 60) as u8,
+            second: (ms / 1000 % 60) as u8,
+            millisecond: (ms % 1000) as u16,
+        }
+    }
+
+    pub fn from_unix(secs: i64, millisecond: u16) -> Self {
+        let time = secs as libc::time_t;
+        let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+        unsafe {
+            libc::localtime_r(&time, &mut tm);
+        }
+        LogTimeStamp {
+            year: (tm.tm_year + 1900) as u16,
+            month: (tm.tm_mon + 1) as u8,
+            day: tm.tm_mday as u8,
+            hour: tm.tm_hour as u8,
+            minute: tm.tm_min as u8,
+            second: tm.tm_sec as u8,
+            millisecond,
+        }
+    }
+}
+
+/// Current time on the `LogTimeStamp::to_wall_ms` scale. The UTC offset is
+/// looked up at most once a minute, so a DST change shows within a minute.
+pub fn wall_clock_ms() -> i64 {
+    let since_epoch = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let secs = since_epoch.as_secs() as i64;
+    let mut cached = UTC_OFFSET.lock().unwrap();
+    let offset = match *cached {
+        Some((minute, offset)) if minute == secs / 60 => offset,
+        _ => {
+            let time = secs as libc::time_t;
+            let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+            unsafe {
+                libc::localtime_r(&time, &mut tm);
+            }
+            let offset = tm.tm_gmtoff as i64 * 1000;
+            *cached = Some((secs / 60, offset));
+            offset
+        }
+    };
+    since_epoch.as_millis() as i64 + offset
+}
+
+/// Days since 1970-01-01 of a proleptic Gregorian date.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// Year, month and day of a count of days since 1970-01-01.
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+fn days_in_month(year: u16, month: u8) -> u8 {
+    match month {
+        2 if year.is_multiple_of(4) && (!year.is_multiple_of(100) || year.is_multiple_of(400)) => {
+            29
+        }
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// W3C trace context (`traceparent`) a message belongs to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceContext {
+    pub trace_id: [u8; 16],
+    pub span_id: [u8; 8],
+    pub flags: u8,
+}
+
+impl TraceContext {
+    /// Reads the 25 byte wire form: trace id, span id, flags. All zero ids,
+    /// which W3C reserves as invalid, mean the message has no trace.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != 25 {
+            return None;
+        }
+        let context = TraceContext {
+            trace_id: bytes[0..16].try_into().ok()?,
+            span_id: bytes[16..24].try_into().ok()?,
+            flags: bytes[24],
+        };
+        context.is_valid().then_some(context)
+    }
+
+    /// Parses `00-<32 hex trace id>-<16 hex span id>-<2 hex flags>`.
+    pub fn parse_traceparent(text: &str) -> Option<Self> {
+        let mut parts = text.split('-');
+        let version = parts.next()?;
+        let (trace_id, span_id, flags) = (parts.next()?, parts.next()?, parts.next()?);
+        if version.len() != 2 || version == "ff" || flags.len() != 2 {
+            return None;
+        }
+        let context = TraceContext {
+            trace_id: decode_hex(trace_id)?,
+            span_id: decode_hex(span_id)?,
+            flags: decode_hex::<1>(flags)?[0],
+        };
+        context.is_valid().then_some(context)
+    }
+
+    /// Looks for a `traceparent=` token in free text.
+    pub fn find_in(text: &str) -> Option<Self> {
+        let start = text.find("traceparent=")? + "traceparent=".len();
+        let value = text[start..].split(|c: char| c.is_whitespace() || c == ',' || c == ';');
+        TraceContext::parse_traceparent(value.into_iter().next()?.trim_matches('"'))
+    }
+
+    fn is_valid(&self) -> bool {
+        self.trace_id != [0; 16] && self.span_id != [0; 8]
+    }
+
+    pub fn trace_id_hex(&self) -> String {
+        encode_hex(&self.trace_id)
+    }
+
+    pub fn span_id_hex(&self) -> String {
+        encode_hex(&self.span_id)
+    }
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn decode_hex<const N: usize>(text: &str) -> Option<[u8; N]> {
+    if text.len() != N * 2 || !text.is_ascii() {
+        return None;
+    }
+    let mut bytes = [0; N];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&text[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(bytes)
+}
+
 #[allow(dead_code)]
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct LogMessage {
     pub pid: u32,
+    pub uid: Option<u32>, // peer credentials, when known
     pub priority: LogPriority,
     pub timestamp: LogTimeStamp,
+    pub tag: Option<String>, // set by ingest sources, native clients have no tag
     pub message: String,
+    /// Structured `key`, `value` pairs extracted from the text, in order.
+    pub fields: Vec<(String, String)>,
+    pub trace: Option<TraceContext>,
+}
+
+impl LogMessage {
+    /// An Info record of the daemon itself, such as a periodic summary.
+    pub fn daemon(text: String) -> Self {
+        LogMessage {
+            pid: std::process::id(),
+            uid: None,
+            priority: LogPriority::Info,
+            timestamp: LogTimeStamp::now(),
+            tag: Some(crate::log::LOG_TAG.to_string()),
+            message: text,
+            fields: Vec::new(),
+            trace: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stamp(fields: (u16, u8, u8, u8, u8, u8, u16)) -> LogTimeStamp {
+        let (year, month, day, hour, minute, second, millisecond) = fields;
+        LogTimeStamp {
+            year,
+            month,
+            day,
+            hour,
+            minute,
+            second,
+            millisecond,
+        }
+    }
+
+    #[test]
+    fn wall_ms_round_trip() {
+        for (fields, ms) in [
+            ((1970, 1, 1, 0, 0, 0, 0), 0),
+            ((1969, 12, 31, 23, 59, 59, 999), -1),
+            ((2000, 2, 29, 12, 0, 0, 0), 951_825_600_000),
+            ((2024, 12, 31, 23, 59, 59, 999), 1_735_689_599_999),
+            ((2100, 3, 1, 0, 0, 0, 1), 4_107_542_400_001),
+        ] {
+            let timestamp = stamp(fields);
+            assert_eq!(timestamp.to_wall_ms(), ms, "{:?}", fields);
+            let back = LogTimeStamp::from_wall_ms(ms);
+            assert_eq!(
+                (
+                    back.year,
+                    back.month,
+                    back.day,
+                    back.hour,
+                    back.minute,
+                    back.second,
+                    back.millisecond
+                ),
+                fields
+            );
+        }
+    }
+
+    #[test]
+    fn wall_clock_matches_local_time() {
+        let before = LogTimeStamp::now().to_wall_ms();
+        let wall = wall_clock_ms();
+        let after = LogTimeStamp::now().to_wall_ms();
+        // `now` drops nothing but the sub-millisecond part
+        assert!(
+            before <= wall && wall <= after + 1000,
+            "{} {} {}",
+            before,
+            wall,
+            after
+        );
+    }
 }
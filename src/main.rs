@@ -1,60 +1,203 @@
+mod config;
+mod conn_table;
+mod ctl_srv;
 mod log;
 mod log_def;
+mod metrics_srv;
+mod msg_filter;
 mod msg_proc;
 mod msg_sink;
+mod msg_source;
 mod msg_srv;
+mod policy;
 #[allow(unused_imports)]
 mod prot_handler;
+mod record_queue;
+mod ring_buffer;
+mod snapshot;
+mod stats;
+mod trigger;
 use crate::log::*;
 use crate::log_def::LogPriority;
 
 use crate::msg_sink::SinkType;
 use crate::msg_sink::SinkTypeOrdinal;
-use crate::prot_handler::LogPacket;
 use crate::prot_handler::ProtocolHandler;
-use msg_proc::{MessageProcessor, OutputHandler};
-use msg_srv::{EpollServer, MessageServer};
+use config::{CONFIG_PATH, Config};
+use ctl_srv::{ControlHandler, ControlServer};
+use metrics_srv::{MetricsHttpConfig, MetricsServer};
+use msg_filter::PipelineSet;
+use msg_proc::{LogRecord, OutputConfig, OutputHandler};
+use msg_source::SourceType;
+use msg_source::child::Supervisor;
+use msg_srv::{EpollServer, MessageServer, ServerConfig};
+use record_queue::QueueSettings;
+use snapshot::SnapshotConfig;
 
+use tokio::signal::unix::{SignalKind, signal};
 use tokio::task;
 
 #[tokio::main]
 async fn main() {
+    #[cfg(feature = "scripting")]
+    {
+        let args: Vec<String> = std::env::args().skip(1).collect();
+        if args.first().map(String::as_str) == Some(msg_filter::script::TEST_FLAG) {
+            std::process::exit(msg_filter::script::test_main(&args[1..]));
+        }
+    }
+
     logi!(LOG_TAG, "Daemon is starting");
 
-    let (tx, rx) = tokio::sync::mpsc::unbounded_channel::<LogPacket>();
+    let config = match Config::load(CONFIG_PATH) {
+        Ok(config) => config,
+        Err(e) => {
+            logw!(LOG_TAG, "Using default configuration: {}", e);
+            Config::default()
+        }
+    };
 
-    let prot_handler = ProtocolHandler::new(tx.clone());
+    let queue_settings = match QueueSettings::from_config(&config) {
+        Ok(queue_settings) => queue_settings,
+        Err(e) => {
+            loge!(LOG_TAG, "Invalid channel configuration: {}", e);
+            QueueSettings::default()
+        }
+    };
+    let (tx, rx) = record_queue::channel(queue_settings);
 
-    let server_handle = match EpollServer::run(prot_handler) {
-        Ok(handle) => handle,
+    let server_config = match ServerConfig::from_config(&config) {
+        Ok(server_config) => server_config,
         Err(e) => {
-            loge!(LOG_TAG, "Error starting server: {}", e);
-            return;
+            loge!(LOG_TAG, "Invalid server configuration: {}", e);
+            ServerConfig::default()
         }
     };
 
-    let sink_vec = vec![
+    let mut sink_vec = vec![
         SinkType::new(SinkTypeOrdinal::LocalFileType).unwrap(),
         SinkType::new(SinkTypeOrdinal::AndroidNativeType).unwrap(),
     ];
+    for sink in sink_vec.iter_mut() {
+        if let Err(e) = sink.configure(&config) {
+            loge!(LOG_TAG, "Invalid {} sink configuration: {}", sink.name(), e);
+        }
+    }
 
-    let receiver_handle = OutputHandler::run(sink_vec, rx);
+    let sink_names: Vec<_> = sink_vec.iter().map(|sink| sink.name()).collect();
+    let mut output_config = match OutputConfig::from_config(&config, &sink_names) {
+        Ok(output_config) => output_config,
+        Err(e) if PipelineSet::redacts(&config) => {
+            loge!(
+                LOG_TAG,
+                "Invalid output configuration with redaction enabled, refusing to start: {}",
+                e
+            );
+            return;
+        }
+        Err(e) => {
+            loge!(LOG_TAG, "Invalid output configuration: {}", e);
+            OutputConfig::default()
+        }
+    };
 
-    match task::spawn_blocking(move || {
-        server_handle.join().unwrap()?;
-        receiver_handle.join().unwrap();
-        Ok::<(), std::io::Error>(())
-    })
-    .await
-    {
-        Ok(Ok(_)) => {
-            logi!(LOG_TAG, "Daemon is ");
+    let ring_buffer = output_config.ring_buffer.clone();
+
+    let snapshots = match SnapshotConfig::from_config(&config) {
+        Ok(snapshot_config) => {
+            snapshot_config.map(|c| snapshot::spawn_writer(c, ring_buffer.clone()))
         }
-        Ok(Err(e)) => {
-            loge!(LOG_TAG, "Error exiting: {}", e);
+        Err(e) => {
+            loge!(LOG_TAG, "Invalid snapshot configuration: {}", e);
+            None
         }
+    };
+    output_config.snapshots = snapshots.clone();
+
+    let mut prot_handler = ProtocolHandler::new(tx.clone());
+    prot_handler.set_snapshot_trigger(snapshots);
+
+    let server_handle = match EpollServer::run_with_config(prot_handler, server_config) {
+        Ok(handle) => handle,
         Err(e) => {
-            loge!(LOG_TAG, "Task error exiting: {}", e);
+            loge!(LOG_TAG, "Error starting server: {}", e);
+            return;
         }
+    };
+
+    let receiver_handle = OutputHandler::run_with_config(sink_vec, rx, output_config);
+
+    let supervisor = match Supervisor::from_config(tx.clone(), &config) {
+        Ok(supervisor) => supervisor,
+        Err(e) => {
+            loge!(LOG_TAG, "Invalid supervisor configuration: {}", e);
+            Supervisor::new(tx.clone(), false)
+        }
+    };
+    if let Err(e) = supervisor.start_configured(&config) {
+        loge!(LOG_TAG, "Invalid child configuration: {}", e);
+    }
+
+    if let Err(e) = ControlServer::run(ControlHandler::new(supervisor, ring_buffer)) {
+        loge!(LOG_TAG, "Error starting control server: {}", e);
+    }
+
+    match MetricsHttpConfig::from_config(&config) {
+        Ok(Some(metrics_config)) => {
+            if let Err(e) = MetricsServer::run(metrics_config) {
+                loge!(LOG_TAG, "Error starting metrics server: {}", e);
+            }
+        }
+        Ok(None) => {}
+        Err(e) => {
+            loge!(LOG_TAG, "Invalid metrics configuration: {}", e);
+        }
+    }
+
+    stats::spawn_reporter(tx.clone(), stats::STATS_REPORT_INTERVAL);
+    msg_filter::spawn_ticker(tx.clone(), msg_filter::PIPELINE_TICK_INTERVAL);
+
+    match SourceType::from_config(&config) {
+        Ok(sources) => {
+            for source in sources {
+                msg_source::spawn_source(source, tx.clone());
+            }
+        }
+        Err(e) => {
+            loge!(LOG_TAG, "Invalid source configuration: {}", e);
+        }
+    }
+
+    let mut terminate = match signal(SignalKind::terminate()) {
+        Ok(terminate) => terminate,
+        Err(e) => {
+            loge!(LOG_TAG, "Error installing SIGTERM handler: {}", e);
+            return;
+        }
+    };
+
+    tokio::select! {
+        result = task::spawn_blocking(move || server_handle.join().unwrap()) => {
+            match result {
+                Ok(Ok(_)) => {
+                    logi!(LOG_TAG, "Daemon is ");
+                }
+                Ok(Err(e)) => {
+                    loge!(LOG_TAG, "Error exiting: {}", e);
+                }
+                Err(e) => {
+                    loge!(LOG_TAG, "Task error exiting: {}", e);
+                }
+            }
+        }
+        _ = terminate.recv() => {
+            logi!(LOG_TAG, "Daemon is stopping");
+        }
+    }
+
+    // let the pipelines release what they hold back before the process exits
+    let _ = tx.send(LogRecord::Shutdown);
+    if let Err(e) = task::spawn_blocking(move || receiver_handle.join().unwrap()).await {
+        loge!(LOG_TAG, "Task error exiting: {}", e);
     }
 }
@@ -0,0 +1,204 @@
+use crate::{
+    config::{Config, ConfigError},
+    log::*,
+    log_def::*,
+    msg_filter::metrics,
+    msg_sink::local_file::LOG_DIR,
+    msg_srv::MessageServer,
+    stats,
+};
+use std::{
+    fmt::Write as _,
+    fs,
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{SocketAddr, TcpListener},
+    os::unix::net::UnixListener,
+    path::{Path, PathBuf},
+    sync::{Mutex, atomic::Ordering},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+static METRICS_PATH: &str = "/metrics";
+static METRICS_READ_TIMEOUT: Duration = Duration::from_secs(2);
+static CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+static DIR_USAGE_MAX_AGE: Duration = Duration::from_secs(30);
+
+/// Last walk of the log directory: when, the rotation count then, and the size.
+static DIR_USAGE: Mutex<Option<(Instant, u64, u64)>> = Mutex::new(None);
+
+/// Where the OpenMetrics listener accepts scrapes.
+#[derive(Debug, Clone)]
+pub enum MetricsListen {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+/// The `[metrics_http]` section. `listen` is a loopback TCP address, `socket`
+/// a unix socket path; the listener is off unless one of them is set.
+#[derive(Debug, Clone)]
+pub struct MetricsHttpConfig {
+    pub listen: MetricsListen,
+}
+
+impl MetricsHttpConfig {
+    pub fn from_config(config: &Config) -> Result<Option<Self>, ConfigError> {
+        let Some(section) = config.section("metrics_http") else {
+            return Ok(None);
+        };
+        if section.get_parsed::<bool>("enabled")? == Some(false) {
+            return Ok(None);
+        }
+        let listen = match (
+            section.get_parsed::<SocketAddr>("listen")?,
+            section.get("socket"),
+        ) {
+            (Some(addr), None) if addr.ip().is_loopback() => MetricsListen::Tcp(addr),
+            (Some(addr), None) => return Err(section.invalid("listen", &addr.to_string())),
+            (None, Some(path)) => MetricsListen::Unix(PathBuf::from(path)),
+            (None, None) => return Ok(None),
+            (Some(_), Some(path)) => return Err(section.invalid("socket", path)),
+        };
+        Ok(Some(MetricsHttpConfig { listen }))
+    }
+}
+
+/// Every daemon counter, the log-derived metrics and the log directory usage.
+pub fn openmetrics() -> String {
+    let mut out = String::new();
+    stats::global().openmetrics(&mut out);
+    let _ = writeln!(out, "# TYPE notcat_log_dir_bytes gauge");
+    let _ = writeln!(out, "notcat_log_dir_bytes {}", log_dir_usage());
+    metrics::write_families(&mut out);
+    out.push_str("# EOF\n");
+    out
+}
+
+/// Size of the log directory. It is walked again once the cached size is
+/// `DIR_USAGE_MAX_AGE` old or a log file rotated since, not on every scrape.
+fn log_dir_usage() -> u64 {
+    let rotations = stats::global().rotations.load(Ordering::Relaxed);
+    let mut cached = DIR_USAGE.lock().unwrap();
+    match *cached {
+        Some((walked, seen, bytes))
+            if seen == rotations && walked.elapsed() < DIR_USAGE_MAX_AGE =>
+        {
+            bytes
+        }
+        _ => {
+            let bytes = dir_usage(Path::new(LOG_DIR)).unwrap_or(0);
+            *cached = Some((Instant::now(), rotations, bytes));
+            bytes
+        }
+    }
+}
+
+fn dir_usage(dir: &Path) -> io::Result<u64> {
+    let mut total = 0;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            total += dir_usage(&entry.path())?;
+        } else {
+            total += metadata.len();
+        }
+    }
+    Ok(total)
+}
+
+/// Answers a single HTTP request; only `GET /metrics` is served.
+fn serve<S: Read + Write>(stream: S) -> io::Result<()> {
+    let mut reader = BufReader::new(stream);
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+    let mut parts = request.split_whitespace();
+    let (status, content_type, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some(path)) if path == METRICS_PATH => {
+            ("200 OK", CONTENT_TYPE, openmetrics())
+        }
+        (Some("GET"), Some(_)) => ("404 Not Found", "text/plain", String::from("not found\n")),
+        _ => (
+            "405 Method Not Allowed",
+            "text/plain",
+            String::from("method not allowed\n"),
+        ),
+    };
+    let stream = reader.get_mut();
+    write!(
+        stream,
+        "HTTP/1.0 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        content_type,
+        body.len()
+    )?;
+    stream.write_all(body.as_bytes())
+}
+
+pub struct MetricsServer;
+
+impl MessageServer<MetricsHttpConfig, JoinHandle<io::Result<()>>> for MetricsServer {
+    fn run(config: MetricsHttpConfig) -> io::Result<JoinHandle<io::Result<()>>> {
+        let handle = match config.listen {
+            MetricsListen::Tcp(addr) => {
+                let listener = TcpListener::bind(addr)?;
+                logi!(LOG_TAG, "[MetricsServer] Listening on {}", addr);
+                thread::spawn(move || {
+                    for stream in listener.incoming() {
+                        let result = stream.and_then(|stream| {
+                            stream.set_read_timeout(Some(METRICS_READ_TIMEOUT))?;
+                            serve(stream)
+                        });
+                        if let Err(e) = result {
+                            logw!(LOG_TAG, "[MetricsServer] Error serving scrape: {}", e);
+                        }
+                    }
+                    Ok(())
+                })
+            }
+            MetricsListen::Unix(path) => {
+                let _ = fs::remove_file(&path);
+                let listener = UnixListener::bind(&path)?;
+                logi!(LOG_TAG, "[MetricsServer] Listening on {}", path.display());
+                thread::spawn(move || {
+                    for stream in listener.incoming() {
+                        let result = stream.and_then(|stream| {
+                            stream.set_read_timeout(Some(METRICS_READ_TIMEOUT))?;
+                            serve(stream)
+                        });
+                        if let Err(e) = result {
+                            logw!(LOG_TAG, "[MetricsServer] Error serving scrape: {}", e);
+                        }
+                    }
+                    Ok(())
+                })
+            }
+        };
+        Ok(handle)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn listen(value: &str) -> Result<Option<MetricsHttpConfig>, ConfigError> {
+        let config = Config::parse(&format!("[metrics_http]\nlisten = {}\n", value)).unwrap();
+        MetricsHttpConfig::from_config(&config)
+    }
+
+    #[test]
+    fn listen_must_be_loopback() {
+        for addr in ["127.0.0.1:9464", "[::1]:9464"] {
+            let config = listen(addr).unwrap().unwrap();
+            assert!(matches!(config.listen, MetricsListen::Tcp(a) if a.to_string() == addr));
+        }
+        for addr in ["0.0.0.0:9464", "192.168.1.2:9464", "[::]:9464"] {
+            assert!(listen(addr).is_err(), "{}", addr);
+        }
+    }
+}
@@ -0,0 +1,163 @@
+use crate::config::{ConfigError, Section};
+use crate::log_def::*;
+use crate::msg_filter::{PipelineEntry, Stage};
+
+/// Drops everything below a priority.
+pub struct MinPriority {
+    priority: LogPriority,
+}
+
+impl MinPriority {
+    pub fn from_section(section: &Section) -> Result<Self, ConfigError> {
+        let value = section.get("priority").unwrap_or_default();
+        let priority = value
+            .parse()
+            .map_err(|_| section.invalid("priority", value))?;
+        Ok(MinPriority { priority })
+    }
+}
+
+impl Stage for MinPriority {
+    fn process(&mut self, entry: PipelineEntry, out: &mut Vec<PipelineEntry>) {
+        if entry.message.priority >= self.priority {
+            out.push(entry);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MatchField {
+    Pid,
+    Uid,
+    Tag,
+}
+
+/// Allow or deny list over pids, uids or tags. An allow list drops every
+/// message it does not contain, a deny list drops every message it contains.
+pub struct ListFilter {
+    field: MatchField,
+    allow: bool,
+    values: Vec<String>,
+}
+
+impl ListFilter {
+    pub fn from_section(section: &Section) -> Result<Self, ConfigError> {
+        let field = match section.get("field") {
+            Some("pid") => MatchField::Pid,
+            Some("uid") => MatchField::Uid,
+            Some("tag") => MatchField::Tag,
+            other => return Err(section.invalid("field", other.unwrap_or(""))),
+        };
+        let values: Vec<String> = section
+            .get_list("values")
+            .iter()
+            .map(|v| v.to_string())
+            .collect();
+        if field != MatchField::Tag {
+            if let Some(bad) = values.iter().find(|v| v.parse::<u32>().is_err()) {
+                return Err(section.invalid("values", bad));
+            }
+        }
+        Ok(ListFilter {
+            field,
+            allow: section.get("type") == Some("allow"),
+            values,
+        })
+    }
+
+    fn contains(&self, message: &LogMessage) -> bool {
+        let value = match self.field {
+            MatchField::Pid => Some(message.pid.to_string()),
+            MatchField::Uid => message.uid.map(|uid| uid.to_string()),
+            MatchField::Tag => message.tag.clone(),
+        };
+        value.is_some_and(|v| self.values.contains(&v))
+    }
+}
+
+impl Stage for ListFilter {
+    fn process(&mut self, entry: PipelineEntry, out: &mut Vec<PipelineEntry>) {
+        if self.contains(&entry.message) == self.allow {
+            out.push(entry);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    fn section(text: &str) -> Config {
+        Config::parse(&format!("[stage s]\n{}", text)).unwrap()
+    }
+
+    fn entry(pid: u32, tag: &str, priority: LogPriority) -> PipelineEntry {
+        PipelineEntry {
+            sink_type: u8::MAX,
+            message: LogMessage {
+                pid,
+                uid: Some(pid + 10000),
+                priority,
+                timestamp: LogTimeStamp::now(),
+                tag: Some(tag.to_string()),
+                message: String::from("text"),
+                fields: Vec::new(),
+                trace: None,
+            },
+            unredacted: false,
+        }
+    }
+
+    fn passes(stage: &mut dyn Stage, entry: PipelineEntry) -> bool {
+        let mut out = Vec::new();
+        stage.process(entry, &mut out);
+        out.len() == 1
+    }
+
+    #[test]
+    fn min_priority_drops_lower_priorities() {
+        let config = section("type = min_priority\npriority = warn\n");
+        let mut stage = MinPriority::from_section(config.section("stage").unwrap()).unwrap();
+        assert!(!passes(&mut stage, entry(1, "t", LogPriority::Info)));
+        assert!(passes(&mut stage, entry(1, "t", LogPriority::Warn)));
+        assert!(passes(&mut stage, entry(1, "t", LogPriority::Fatal)));
+
+        let config = section("type = min_priority\npriority = loud\n");
+        assert!(MinPriority::from_section(config.section("stage").unwrap()).is_err());
+    }
+
+    #[test]
+    fn allow_and_deny_lists() {
+        let config = section("type = allow\nfield = pid\nvalues = 1, 2\n");
+        let mut allow = ListFilter::from_section(config.section("stage").unwrap()).unwrap();
+        assert!(passes(&mut allow, entry(2, "t", LogPriority::Info)));
+        assert!(!passes(&mut allow, entry(3, "t", LogPriority::Info)));
+
+        let config = section("type = deny\nfield = tag\nvalues = chatty\n");
+        let mut deny = ListFilter::from_section(config.section("stage").unwrap()).unwrap();
+        assert!(!passes(&mut deny, entry(1, "chatty", LogPriority::Info)));
+        assert!(passes(&mut deny, entry(1, "quiet", LogPriority::Info)));
+
+        let config = section("type = deny\nfield = uid\nvalues = 10001\n");
+        let mut deny = ListFilter::from_section(config.section("stage").unwrap()).unwrap();
+        assert!(!passes(&mut deny, entry(1, "t", LogPriority::Info)));
+        assert!(passes(&mut deny, entry(2, "t", LogPriority::Info)));
+    }
+
+    #[test]
+    fn list_values_are_validated() {
+        for text in [
+            "type = allow\nfield = pid\nvalues = system_server\n",
+            "type = allow\nfield = name\nvalues = x\n",
+            "type = deny\nvalues = 1\n",
+        ] {
+            let config = section(text);
+            assert!(
+                ListFilter::from_section(config.section("stage").unwrap()).is_err(),
+                "{}",
+                text
+            );
+        }
+    }
+}
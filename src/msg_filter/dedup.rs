@@ -0,0 +1,188 @@
+use crate::config::{ConfigError, Section};
+use crate::log_def::*;
+use crate::msg_filter::{PipelineEntry, Stage};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+static DEFAULT_WINDOW: Duration = Duration::from_secs(10);
+
+/// Current run of identical messages from one pid and tag to one set of sinks.
+struct Run {
+    last: PipelineEntry,
+    started: Instant,
+    last_seen: Instant,
+    repeats: u64,
+}
+
+impl Run {
+    fn summary(&self) -> PipelineEntry {
+        let span = self.last_seen.saturating_duration_since(self.started);
+        PipelineEntry {
+            sink_type: self.last.sink_type,
+            unredacted: self.last.unredacted,
+            message: LogMessage {
+                timestamp: LogTimeStamp::now(),
+                message: format!(
+                    "last message repeated {} times over {}.{:03}s",
+                    self.repeats,
+                    span.as_secs(),
+                    span.subsec_millis()
+                ),
+                ..self.last.message.clone()
+            },
+        }
+    }
+}
+
+/// Collapses consecutive identical messages of a pid and tag. Entries routed
+/// to different sinks are tracked as separate runs, so a stream split by an
+/// earlier stage still collapses on each side. The first one passes, the
+/// repeats are counted and replaced by a summary line once a different
+/// message arrives or the window since the first one closes.
+pub struct DedupStage {
+    window: Duration,
+    runs: HashMap<(u32, Option<String>, u8), Run>,
+}
+
+impl DedupStage {
+    pub fn from_section(section: &Section) -> Result<Self, ConfigError> {
+        let window = section
+            .get_parsed::<u64>("window_ms")?
+            .map(Duration::from_millis)
+            .unwrap_or(DEFAULT_WINDOW);
+        Ok(DedupStage {
+            window,
+            runs: HashMap::new(),
+        })
+    }
+}
+
+impl Stage for DedupStage {
+    fn process(&mut self, entry: PipelineEntry, out: &mut Vec<PipelineEntry>) {
+        let now = Instant::now();
+        let key = (
+            entry.message.pid,
+            entry.message.tag.clone(),
+            entry.sink_type,
+        );
+        if let Some(run) = self.runs.get_mut(&key) {
+            let same = run.last.message.message == entry.message.message
+                && run.last.message.priority == entry.message.priority;
+            if same && now.saturating_duration_since(run.started) < self.window {
+                run.repeats += 1;
+                run.last_seen = now;
+                return;
+            }
+            if run.repeats > 0 {
+                out.push(run.summary());
+            }
+        }
+        self.runs.insert(
+            key,
+            Run {
+                last: PipelineEntry {
+                    sink_type: entry.sink_type,
+                    message: entry.message.clone(),
+                    unredacted: entry.unredacted,
+                },
+                started: now,
+                last_seen: now,
+                repeats: 0,
+            },
+        );
+        out.push(entry);
+    }
+
+    fn tick(&mut self, now: Instant, out: &mut Vec<PipelineEntry>) {
+        let window = self.window;
+        self.runs.retain(|_, run| {
+            if now.saturating_duration_since(run.started) < window {
+                return true;
+            }
+            if run.repeats > 0 {
+                out.push(run.summary());
+            }
+            false
+        });
+    }
+
+    fn flush(&mut self, out: &mut Vec<PipelineEntry>) {
+        for (_, run) in self.runs.drain() {
+            if run.repeats > 0 {
+                out.push(run.summary());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(sink_type: u8, text: &str) -> PipelineEntry {
+        PipelineEntry {
+            sink_type,
+            message: LogMessage {
+                pid: 42,
+                uid: None,
+                priority: LogPriority::Info,
+                timestamp: LogTimeStamp::now(),
+                tag: Some(String::from("test")),
+                message: text.to_string(),
+                fields: Vec::new(),
+                trace: None,
+            },
+            unredacted: false,
+        }
+    }
+
+    fn stage() -> DedupStage {
+        DedupStage {
+            window: Duration::from_secs(60),
+            runs: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn collapses_repeats() {
+        let mut stage = stage();
+        let mut out = Vec::new();
+        for _ in 0..3 {
+            stage.process(entry(1, "same"), &mut out);
+        }
+        stage.process(entry(1, "other"), &mut out);
+        let texts: Vec<&str> = out.iter().map(|e| e.message.message.as_str()).collect();
+        assert_eq!(texts.len(), 3);
+        assert_eq!(texts[0], "same");
+        assert!(texts[1].starts_with("last message repeated 2 times"));
+        assert_eq!(texts[2], "other");
+    }
+
+    #[test]
+    fn split_stream_collapses_per_sink() {
+        let mut stage = stage();
+        let mut out = Vec::new();
+        // a split stage upstream interleaves the copies for two sinks
+        for _ in 0..4 {
+            stage.process(entry(1, "same"), &mut out);
+            stage.process(entry(2, "same"), &mut out);
+        }
+        assert_eq!(out.len(), 2);
+        assert_eq!(out[0].sink_type, 1);
+        assert_eq!(out[1].sink_type, 2);
+
+        out.clear();
+        stage.flush(&mut out);
+        out.sort_by_key(|e| e.sink_type);
+        assert_eq!(out.len(), 2);
+        for (summary, sink_type) in out.iter().zip([1, 2]) {
+            assert_eq!(summary.sink_type, sink_type);
+            assert!(
+                summary
+                    .message
+                    .message
+                    .starts_with("last message repeated 3 times")
+            );
+        }
+    }
+}
@@ -0,0 +1,209 @@
+use crate::config::{ConfigError, Section};
+use crate::log_def::*;
+use crate::msg_filter::{PipelineEntry, Stage};
+use regex::Regex;
+use serde_json::Value;
+
+/// `key=value` pairs; values may be double quoted. A `pattern` replaces it.
+static KEY_VALUE_PATTERN: &str =
+    r#"(?:^|[\s,;])(?P<key>[A-Za-z_][\w.\-]*)=(?P<value>"(?:[^"\\]|\\.)*"|[^\s,;]+)"#;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ExtractFormat {
+    KeyValue,
+    Json,
+    Both,
+}
+
+/// Parses `key=value` pairs and embedded JSON objects of the message text into
+/// `LogMessage::fields`. A configured `pattern` finds the pairs through its
+/// `key` and `value` groups. The text itself is left as it is. Only messages
+/// whose tag matches `tag_pattern` and whose pid is in `pids`, when given,
+/// are parsed.
+pub struct ExtractStage {
+    format: ExtractFormat,
+    tag_pattern: Option<Regex>,
+    pids: Vec<u32>,
+    /// Keys to keep; all when empty.
+    keys: Vec<String>,
+    key_value: Regex,
+}
+
+impl ExtractStage {
+    pub fn from_section(section: &Section) -> Result<Self, ConfigError> {
+        let format = match section.get("format") {
+            None | Some("kv") => ExtractFormat::KeyValue,
+            Some("json") => ExtractFormat::Json,
+            Some("both") => ExtractFormat::Both,
+            Some(other) => return Err(section.invalid("format", other)),
+        };
+        let tag_pattern = match section.get("tag_pattern") {
+            Some(pattern) => {
+                Some(Regex::new(pattern).map_err(|_| section.invalid("tag_pattern", pattern))?)
+            }
+            None => None,
+        };
+        let key_value = match section.get("pattern") {
+            Some(pattern) => Regex::new(pattern)
+                .ok()
+                .filter(|regex| {
+                    let names: Vec<_> = regex.capture_names().flatten().collect();
+                    names.contains(&"key") && names.contains(&"value")
+                })
+                .ok_or_else(|| section.invalid("pattern", pattern))?,
+            None => Regex::new(KEY_VALUE_PATTERN).unwrap(),
+        };
+        let mut pids = Vec::new();
+        for pid in section.get_list("pids") {
+            pids.push(pid.parse().map_err(|_| section.invalid("pids", pid))?);
+        }
+        Ok(ExtractStage {
+            format,
+            tag_pattern,
+            pids,
+            keys: section
+                .get_list("keys")
+                .iter()
+                .map(|k| k.to_string())
+                .collect(),
+            key_value,
+        })
+    }
+
+    fn applies(&self, message: &LogMessage) -> bool {
+        (self.pids.is_empty() || self.pids.contains(&message.pid))
+            && self.tag_pattern.as_ref().is_none_or(|pattern| {
+                message
+                    .tag
+                    .as_ref()
+                    .is_some_and(|tag| pattern.is_match(tag))
+            })
+    }
+
+    fn extract_key_values(&self, text: &str, fields: &mut Vec<(String, String)>) {
+        for captures in self.key_value.captures_iter(text) {
+            let (Some(key), Some(value)) = (captures.name("key"), captures.name("value")) else {
+                continue;
+            };
+            let value = value.as_str();
+            let value = match value.strip_prefix('"').and_then(|v| v.strip_suffix('"')) {
+                Some(quoted) => quoted.replace("\\\"", "\"").replace("\\\\", "\\"),
+                None => value.to_string(),
+            };
+            fields.push((key.as_str().to_string(), value));
+        }
+    }
+
+    /// Parses the outermost `{...}` of the text, if it is a JSON object.
+    fn extract_json(&self, text: &str, fields: &mut Vec<(String, String)>) {
+        let (Some(start), Some(end)) = (text.find('{'), text.rfind('}')) else {
+            return;
+        };
+        if end <= start {
+            return;
+        }
+        if let Ok(Value::Object(object)) = serde_json::from_str::<Value>(&text[start..=end]) {
+            flatten("", &Value::Object(object), fields);
+        }
+    }
+}
+
+/// Nested objects become dotted keys, arrays stay JSON text.
+fn flatten(prefix: &str, value: &Value, fields: &mut Vec<(String, String)>) {
+    match value {
+        Value::Object(object) => {
+            for (key, value) in object {
+                let key = if prefix.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{}.{}", prefix, key)
+                };
+                flatten(&key, value, fields);
+            }
+        }
+        Value::String(text) => fields.push((prefix.to_string(), text.clone())),
+        other => fields.push((prefix.to_string(), other.to_string())),
+    }
+}
+
+impl Stage for ExtractStage {
+    fn process(&mut self, mut entry: PipelineEntry, out: &mut Vec<PipelineEntry>) {
+        if self.applies(&entry.message) {
+            let mut fields = Vec::new();
+            if self.format != ExtractFormat::KeyValue {
+                self.extract_json(&entry.message.message, &mut fields);
+            }
+            if self.format != ExtractFormat::Json {
+                self.extract_key_values(&entry.message.message, &mut fields);
+            }
+            fields.retain(|(key, _)| self.keys.is_empty() || self.keys.contains(key));
+            for (key, value) in fields {
+                if !entry.message.fields.iter().any(|(k, _)| *k == key) {
+                    entry.message.fields.push((key, value));
+                }
+            }
+        }
+        out.push(entry);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    fn stage(options: &str) -> Result<ExtractStage, ConfigError> {
+        let config = Config::parse(&format!("[stage e]\ntype = extract\n{}\n", options)).unwrap();
+        ExtractStage::from_section(config.section("stage").unwrap())
+    }
+
+    fn fields(stage: &mut ExtractStage, text: &str) -> Vec<(String, String)> {
+        let entry = PipelineEntry {
+            sink_type: u8::MAX,
+            message: LogMessage {
+                pid: 1,
+                uid: None,
+                priority: LogPriority::Info,
+                timestamp: LogTimeStamp::now(),
+                tag: Some(String::from("netd")),
+                message: text.to_string(),
+                fields: Vec::new(),
+                trace: None,
+            },
+            unredacted: false,
+        };
+        let mut out = Vec::new();
+        stage.process(entry, &mut out);
+        out.remove(0).message.fields
+    }
+
+    fn pairs(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn key_values_and_json() {
+        let mut stage = stage("format = both").unwrap();
+        assert_eq!(
+            fields(
+                &mut stage,
+                r#"dns iface=wlan0 msg="timed out" {"rtt":{"ms":12}}"#
+            ),
+            pairs(&[("rtt.ms", "12"), ("iface", "wlan0"), ("msg", "timed out")])
+        );
+    }
+
+    #[test]
+    fn custom_pattern() {
+        let mut extract = stage(r"pattern = (?P<key>\w+): (?P<value>\d+)").unwrap();
+        assert_eq!(
+            fields(&mut extract, "rx: 10 tx: 20 iface=wlan0"),
+            pairs(&[("rx", "10"), ("tx", "20")])
+        );
+        assert!(stage(r"pattern = (\w+)=(\w+)").is_err());
+        assert!(stage("pattern = (?P<key>").is_err());
+    }
+}
@@ -0,0 +1,430 @@
+use crate::config::{ConfigError, Section};
+use crate::log_def::*;
+use crate::msg_filter::{PipelineEntry, Stage};
+use crate::msg_sink::SinkTypeOrdinal;
+use regex::Regex;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+static DEFAULT_INTERVAL: Duration = Duration::from_secs(60);
+static DEFAULT_BUCKETS: [f64; 8] = [1.0, 5.0, 10.0, 50.0, 100.0, 500.0, 1000.0, 5000.0];
+/// Pids beyond this many are counted under `pid="other"`.
+static MAX_PIDS: usize = 256;
+
+/// Aggregates by stage name. Every pipeline listing a stage gets its own
+/// instance, and all of them share the one entry.
+static REGISTRY: OnceLock<Mutex<BTreeMap<String, Arc<Mutex<Metrics>>>>> = OnceLock::new();
+
+fn registry() -> &'static Mutex<BTreeMap<String, Arc<Mutex<Metrics>>>> {
+    REGISTRY.get_or_init(|| Mutex::new(BTreeMap::new()))
+}
+
+/// OpenMetrics metric names match `[a-zA-Z_:][a-zA-Z0-9_:]*`.
+fn valid_metric_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_' || c == ':')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == ':')
+}
+
+/// Cumulative distribution of the values extracted by one rule.
+struct Histogram {
+    bounds: Vec<f64>,
+    /// Per bucket, not cumulative; the last one is `+Inf`.
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn new(bounds: Vec<f64>) -> Self {
+        let counts = vec![0; bounds.len() + 1];
+        Histogram {
+            bounds,
+            counts,
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    fn observe(&mut self, value: f64) {
+        let bucket = self
+            .bounds
+            .iter()
+            .position(|bound| value <= *bound)
+            .unwrap_or(self.bounds.len());
+        self.counts[bucket] += 1;
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+/// What one extraction rule saw since the last summary record.
+#[derive(Default)]
+struct IntervalValues {
+    count: u64,
+    sum: f64,
+    max: f64,
+}
+
+/// Aggregates kept by a metrics stage, read by the scrape command.
+#[derive(Default)]
+struct Metrics {
+    by_priority: [u64; 6],
+    by_pid: HashMap<u32, [u64; 6]>,
+    other_pids: [u64; 6],
+    histograms: Vec<(String, Histogram)>,
+    interval_messages: u64,
+    interval_errors: u64,
+    interval_values: Vec<IntervalValues>,
+}
+
+static PRIORITIES: [LogPriority; 6] = [
+    LogPriority::Verbose,
+    LogPriority::Debug,
+    LogPriority::Info,
+    LogPriority::Warn,
+    LogPriority::Error,
+    LogPriority::Fatal,
+];
+
+/// Counts messages by priority and pid, and feeds numbers extracted with
+/// regexes into histograms. Entries pass through unchanged; a summary record
+/// with the rates of the last interval is emitted on the pipeline tick.
+/// Unredacted copies are not counted: their redacted copy stands for the
+/// message.
+pub struct MetricsStage {
+    name: String,
+    by_pid: bool,
+    rules: Vec<Regex>,
+    interval: Duration,
+    sink_type: u8,
+    last_summary: Instant,
+    metrics: Arc<Mutex<Metrics>>,
+}
+
+impl MetricsStage {
+    pub fn from_section(section: &Section) -> Result<Self, ConfigError> {
+        let name = section.name.clone().unwrap_or_default();
+        let bounds = if section.get("buckets").is_some() {
+            let mut bounds = Vec::new();
+            for bound in section.get_list("buckets") {
+                bounds.push(
+                    bound
+                        .parse::<f64>()
+                        .map_err(|_| section.invalid("buckets", bound))?,
+                );
+            }
+            bounds.sort_by(f64::total_cmp);
+            bounds
+        } else {
+            DEFAULT_BUCKETS.to_vec()
+        };
+        let mut rules = Vec::new();
+        let mut histograms = Vec::new();
+        for rule in section.get_all("extract") {
+            // metric names may contain ':' but not spaces
+            let (metric, pattern) = rule
+                .split_once(": ")
+                .filter(|(metric, _)| valid_metric_name(metric.trim()))
+                .ok_or_else(|| section.invalid("extract", rule))?;
+            let regex = Regex::new(pattern.trim())
+                .ok()
+                .filter(|regex| regex.captures_len() > 1)
+                .ok_or_else(|| section.invalid("extract", rule))?;
+            rules.push(regex);
+            histograms.push((metric.trim().to_string(), Histogram::new(bounds.clone())));
+        }
+        let interval = section
+            .get_parsed::<u64>("interval_s")?
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_INTERVAL);
+        let metrics = registry()
+            .lock()
+            .unwrap()
+            .entry(name.clone())
+            .or_insert_with(|| {
+                Arc::new(Mutex::new(Metrics {
+                    interval_values: histograms.iter().map(|_| Default::default()).collect(),
+                    histograms,
+                    ..Metrics::default()
+                }))
+            })
+            .clone();
+        Ok(MetricsStage {
+            name,
+            by_pid: section.get_parsed::<bool>("by_pid")?.unwrap_or(true),
+            rules,
+            interval,
+            sink_type: SinkTypeOrdinal::mask_from_section(section, u8::MAX)?,
+            last_summary: Instant::now(),
+            metrics,
+        })
+    }
+
+    fn summary(&self, metrics: &mut Metrics, elapsed: Duration) -> PipelineEntry {
+        let seconds = elapsed.as_secs_f64().max(1.0);
+        let mut text = format!(
+            "metrics {}: messages={} rate={:.1}/s errors={} error_rate={:.3}",
+            self.name,
+            metrics.interval_messages,
+            metrics.interval_messages as f64 / seconds,
+            metrics.interval_errors,
+            if metrics.interval_messages == 0 {
+                0.0
+            } else {
+                metrics.interval_errors as f64 / metrics.interval_messages as f64
+            }
+        );
+        for ((metric, _), values) in metrics.histograms.iter().zip(&metrics.interval_values) {
+            let mean = if values.count == 0 {
+                0.0
+            } else {
+                values.sum / values.count as f64
+            };
+            let _ = write!(
+                text,
+                " {}: count={} mean={:.1} max={}",
+                metric, values.count, mean, values.max
+            );
+        }
+        metrics.interval_messages = 0;
+        metrics.interval_errors = 0;
+        metrics.interval_values.fill_with(Default::default);
+        PipelineEntry {
+            sink_type: self.sink_type,
+            message: LogMessage::daemon(text),
+            unredacted: false,
+        }
+    }
+}
+
+impl Stage for MetricsStage {
+    fn process(&mut self, entry: PipelineEntry, out: &mut Vec<PipelineEntry>) {
+        if entry.unredacted {
+            out.push(entry);
+            return;
+        }
+        let message = &entry.message;
+        let priority = message.priority as usize;
+        {
+            let mut metrics = self.metrics.lock().unwrap();
+            let metrics = &mut *metrics;
+            metrics.by_priority[priority] += 1;
+            metrics.interval_messages += 1;
+            if message.priority >= LogPriority::Error {
+                metrics.interval_errors += 1;
+            }
+            if self.by_pid {
+                let pids = metrics.by_pid.len();
+                match metrics.by_pid.get_mut(&message.pid) {
+                    Some(counts) => counts[priority] += 1,
+                    None if pids < MAX_PIDS => {
+                        let mut counts = [0; 6];
+                        counts[priority] = 1;
+                        metrics.by_pid.insert(message.pid, counts);
+                    }
+                    None => metrics.other_pids[priority] += 1,
+                }
+            }
+            for (index, rule) in self.rules.iter().enumerate() {
+                let Some(value) = rule
+                    .captures(&message.message)
+                    .and_then(|captures| captures.get(1))
+                    .and_then(|value| value.as_str().parse::<f64>().ok())
+                else {
+                    continue;
+                };
+                metrics.histograms[index].1.observe(value);
+                let values = &mut metrics.interval_values[index];
+                values.count += 1;
+                values.sum += value;
+                values.max = values.max.max(value);
+            }
+        }
+        out.push(entry);
+    }
+
+    fn tick(&mut self, now: Instant, out: &mut Vec<PipelineEntry>) {
+        let elapsed = now.saturating_duration_since(self.last_summary);
+        if elapsed < self.interval {
+            return;
+        }
+        self.last_summary = now;
+        let metrics = self.metrics.clone();
+        out.push(self.summary(&mut metrics.lock().unwrap(), elapsed));
+    }
+}
+
+/// Aggregates of every metrics stage in OpenMetrics text, returned by the
+/// `metrics` control command.
+pub fn exposition() -> String {
+    let mut out = String::new();
+    write_families(&mut out);
+    out.push_str("# EOF\n");
+    out
+}
+
+/// Metric families of the metrics stages, without the `# EOF` terminator.
+pub fn write_families(out: &mut String) {
+    let registry = registry().lock().unwrap();
+    let _ = writeln!(out, "# TYPE notcat_log_messages counter");
+    for (stage, metrics) in registry.iter() {
+        let metrics = metrics.lock().unwrap();
+        for (priority, count) in PRIORITIES.iter().zip(metrics.by_priority) {
+            let _ = writeln!(
+                out,
+                "notcat_log_messages_total{{stage=\"{}\",priority=\"{}\"}} {}",
+                stage,
+                priority.name(),
+                count
+            );
+        }
+    }
+    let _ = writeln!(out, "# TYPE notcat_log_pid_messages counter");
+    for (stage, metrics) in registry.iter() {
+        let metrics = metrics.lock().unwrap();
+        let pids: BTreeMap<_, _> = metrics
+            .by_pid
+            .iter()
+            .map(|(pid, counts)| (pid.to_string(), counts))
+            .chain(
+                Some((String::from("other"), &metrics.other_pids))
+                    .filter(|(_, counts)| counts.iter().any(|c| *c > 0)),
+            )
+            .collect();
+        for (pid, counts) in pids {
+            for (priority, count) in PRIORITIES.iter().zip(counts) {
+                if *count > 0 {
+                    let _ = writeln!(
+                        out,
+                        "notcat_log_pid_messages_total{{stage=\"{}\",pid=\"{}\",priority=\"{}\"}} {}",
+                        stage,
+                        pid,
+                        priority.name(),
+                        count
+                    );
+                }
+            }
+        }
+    }
+    for (stage, metrics) in registry.iter() {
+        let metrics = metrics.lock().unwrap();
+        for (metric, histogram) in &metrics.histograms {
+            let _ = writeln!(out, "# TYPE notcat_log_{} histogram", metric);
+            let mut cumulative = 0;
+            for (bound, count) in histogram.bounds.iter().zip(&histogram.counts) {
+                cumulative += count;
+                let _ = writeln!(
+                    out,
+                    "notcat_log_{}_bucket{{stage=\"{}\",le=\"{}\"}} {}",
+                    metric, stage, bound, cumulative
+                );
+            }
+            let _ = writeln!(
+                out,
+                "notcat_log_{}_bucket{{stage=\"{}\",le=\"+Inf\"}} {}",
+                metric, stage, histogram.count
+            );
+            let _ = writeln!(
+                out,
+                "notcat_log_{}_sum{{stage=\"{}\"}} {}",
+                metric, stage, histogram.sum
+            );
+            let _ = writeln!(
+                out,
+                "notcat_log_{}_count{{stage=\"{}\"}} {}",
+                metric, stage, histogram.count
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    fn stage(text: &str) -> Result<MetricsStage, ConfigError> {
+        let config = Config::parse(text).unwrap();
+        MetricsStage::from_section(config.section("stage").unwrap())
+    }
+
+    fn entry(text: &str, unredacted: bool) -> PipelineEntry {
+        PipelineEntry {
+            sink_type: u8::MAX,
+            message: LogMessage {
+                pid: 1,
+                uid: None,
+                priority: LogPriority::Info,
+                timestamp: LogTimeStamp::now(),
+                tag: None,
+                message: String::from(text),
+                fields: Vec::new(),
+                trace: None,
+            },
+            unredacted,
+        }
+    }
+
+    #[test]
+    fn metric_names_are_validated() {
+        for name in ["latency_ms", "_x", "ns:op_total", "A1"] {
+            assert!(valid_metric_name(name), "{}", name);
+        }
+        for name in ["", "1st", "latency-ms", "a b", "é"] {
+            assert!(!valid_metric_name(name), "{}", name);
+        }
+        assert!(
+            stage("[stage bad_name]\ntype = metrics\nextract = req-ms: took (\\d+)ms\n").is_err()
+        );
+        let stage =
+            stage("[stage colon_name]\ntype = metrics\nextract = ns:latency_ms: latency=(\\d+)\n")
+                .unwrap();
+        assert_eq!(
+            stage.metrics.lock().unwrap().histograms[0].0,
+            "ns:latency_ms"
+        );
+        assert_eq!(stage.rules[0].as_str(), "latency=(\\d+)");
+    }
+
+    #[test]
+    fn stage_registered_once_per_name() {
+        let text = "[stage shared_test]\ntype = metrics\nextract = took_ms: took (\\d+)ms\n";
+        let mut first = stage(text).unwrap();
+        let mut second = stage(text).unwrap();
+        let mut out = Vec::new();
+        for stage in [&mut first, &mut second] {
+            stage.process(entry("took 7ms", false), &mut out);
+        }
+        let exposition = exposition();
+        let count = exposition
+            .lines()
+            .filter(|line| line.starts_with("notcat_log_took_ms_count{stage=\"shared_test\"}"))
+            .collect::<Vec<_>>();
+        assert_eq!(count, ["notcat_log_took_ms_count{stage=\"shared_test\"} 2"]);
+        assert_eq!(
+            exposition
+                .matches("# TYPE notcat_log_took_ms histogram")
+                .count(),
+            1
+        );
+    }
+
+    #[test]
+    fn unredacted_copies_are_not_counted() {
+        let mut stage =
+            stage("[stage copies_test]\ntype = metrics\nextract = took_ms: took (\\d+)ms\n")
+                .unwrap();
+        let mut out = Vec::new();
+        stage.process(entry("took 7ms", true), &mut out);
+        stage.process(entry("took 7ms", false), &mut out);
+        assert_eq!(out.len(), 2);
+        let metrics = stage.metrics.lock().unwrap();
+        assert_eq!(metrics.by_priority[LogPriority::Info as usize], 1);
+        assert_eq!(metrics.histograms[0].1.counts.iter().sum::<u64>(), 1);
+    }
+}
@@ -0,0 +1,310 @@
+pub mod basic;
+pub mod dedup;
+pub mod extract;
+pub mod metrics;
+pub mod redact;
+pub mod regex_match;
+pub mod reorder;
+pub mod sample;
+#[cfg(feature = "scripting")]
+pub mod script;
+pub mod stack_group;
+use crate::config::{Config, ConfigError, Section};
+use crate::log::*;
+use crate::log_def::*;
+use crate::msg_proc::LogRecord;
+use crate::record_queue::RecordSender as Sender;
+use std::collections::HashMap;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Short enough for a reorder window of a few hundred milliseconds.
+pub static PIPELINE_TICK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A message on its way through a pipeline, with the sinks it is routed to.
+pub struct PipelineEntry {
+    pub sink_type: u8,
+    pub message: LogMessage,
+    /// Copy kept unredacted for sinks exempt from redaction. It only reaches
+    /// those sinks; the ring buffer, triggers and snapshots see the redacted
+    /// copy of the message.
+    pub unredacted: bool,
+}
+
+/// One step of a pipeline. A stage takes ownership of each entry and pushes
+/// whatever should continue to `out`: nothing to drop it, the entry itself
+/// (possibly rewritten or re-routed), and any extra records it wants to emit.
+pub trait Stage: Send {
+    fn process(&mut self, entry: PipelineEntry, out: &mut Vec<PipelineEntry>);
+    /// Called periodically so stages holding entries back can release them.
+    fn tick(&mut self, _now: Instant, _out: &mut Vec<PipelineEntry>) {}
+    /// Called once at shutdown; everything held back must be released.
+    fn flush(&mut self, _out: &mut Vec<PipelineEntry>) {}
+}
+
+/// Ordered list of stages. Entries emitted by a stage continue through the
+/// stages after it.
+#[derive(Default)]
+pub struct Pipeline {
+    stages: Vec<Box<dyn Stage>>,
+}
+
+impl Pipeline {
+    pub fn new(stages: Vec<Box<dyn Stage>>) -> Self {
+        Pipeline { stages }
+    }
+
+    pub fn process(&mut self, entry: PipelineEntry) -> Vec<PipelineEntry> {
+        self.run_from(0, vec![entry])
+    }
+
+    pub fn tick(&mut self, now: Instant) -> Vec<PipelineEntry> {
+        let mut released = Vec::new();
+        for index in 0..self.stages.len() {
+            let mut emitted = Vec::new();
+            self.stages[index].tick(now, &mut emitted);
+            if !emitted.is_empty() {
+                released.extend(self.run_from(index + 1, emitted));
+            }
+        }
+        released
+    }
+
+    pub fn flush(&mut self) -> Vec<PipelineEntry> {
+        let mut released = Vec::new();
+        for index in 0..self.stages.len() {
+            let mut emitted = Vec::new();
+            self.stages[index].flush(&mut emitted);
+            if !emitted.is_empty() {
+                released.extend(self.run_from(index + 1, emitted));
+            }
+        }
+        released
+    }
+
+    fn run_from(&mut self, start: usize, mut batch: Vec<PipelineEntry>) -> Vec<PipelineEntry> {
+        for stage in self.stages.iter_mut().skip(start) {
+            let mut next = Vec::with_capacity(batch.len());
+            for entry in batch {
+                stage.process(entry, &mut next);
+            }
+            batch = next;
+            if batch.is_empty() {
+                break;
+            }
+        }
+        batch
+    }
+}
+
+/// The global pipeline, run before fan-out, and one pipeline per sink.
+#[derive(Default)]
+pub struct PipelineSet {
+    pub global: Pipeline,
+    pub per_sink: HashMap<&'static str, Pipeline>,
+}
+
+impl PipelineSet {
+    /// Builds pipelines from `[pipeline]` and `[pipeline <sink>]` sections. Their
+    /// `stages` key lists, in order, names of `[stage <name>]` sections. Every
+    /// pipeline gets its own instance of a stage.
+    pub fn from_config(config: &Config, sink_names: &[&'static str]) -> Result<Self, ConfigError> {
+        let definitions: HashMap<&str, &Section> = config
+            .sections("stage")
+            .filter_map(|s| s.name.as_deref().map(|name| (name, s)))
+            .collect();
+        let build = |section: &Section| -> Result<Pipeline, ConfigError> {
+            let mut stages = Vec::new();
+            for name in section.get_list("stages") {
+                let definition = definitions
+                    .get(name)
+                    .ok_or_else(|| section.invalid("stages", name))?;
+                stages.push(build_stage(definition)?);
+            }
+            Ok(Pipeline::new(stages))
+        };
+        let mut set = PipelineSet::default();
+        for section in config.sections("pipeline") {
+            match section.name.as_deref() {
+                None => set.global = build(section)?,
+                Some(sink) => {
+                    let name = sink_names
+                        .iter()
+                        .find(|n| **n == sink)
+                        .ok_or_else(|| section.invalid("pipeline", sink))?;
+                    set.per_sink.insert(name, build(section)?);
+                }
+            }
+        }
+        Ok(set)
+    }
+
+    /// Whether the configuration declares a redact stage. Such a daemon must
+    /// not fall back to pipelines that would let personal data through.
+    pub fn redacts(config: &Config) -> bool {
+        config
+            .sections("stage")
+            .any(|section| section.get("type") == Some("redact"))
+    }
+}
+
+/// Instantiates the stage described by a `[stage <name>]` section.
+pub fn build_stage(section: &Section) -> Result<Box<dyn Stage>, ConfigError> {
+    match section.get("type") {
+        Some("min_priority") => Ok(Box::new(basic::MinPriority::from_section(section)?)),
+        Some("allow") | Some("deny") => Ok(Box::new(basic::ListFilter::from_section(section)?)),
+        Some("dedup") => Ok(Box::new(dedup::DedupStage::from_section(section)?)),
+        Some("extract") => Ok(Box::new(extract::ExtractStage::from_section(section)?)),
+        Some("metrics") => Ok(Box::new(metrics::MetricsStage::from_section(section)?)),
+        Some("redact") => Ok(Box::new(redact::RedactStage::from_section(section)?)),
+        Some("reorder") => Ok(Box::new(reorder::ReorderStage::from_section(section)?)),
+        Some("sample") => Ok(Box::new(sample::SampleStage::from_section(section)?)),
+        #[cfg(feature = "scripting")]
+        Some("script") => Ok(Box::new(script::ScriptStage::from_section(section)?)),
+        #[cfg(not(feature = "scripting"))]
+        Some("script") => Err(ConfigError::Unsupported(section.title(), "scripting")),
+        Some("stack_group") => Ok(Box::new(stack_group::StackGroupStage::from_section(
+            section,
+        )?)),
+        Some("regex") => Ok(Box::new(regex_match::RegexStage::from_section(section)?)),
+        other => Err(section.invalid("type", other.unwrap_or(""))),
+    }
+}
+
+/// Wakes the output handler periodically so stages can release held messages.
+pub fn spawn_ticker(sender: Sender, interval: Duration) -> JoinHandle<()> {
+    thread::spawn(move || {
+        loop {
+            thread::sleep(interval);
+            if sender.send(LogRecord::Tick).is_err() {
+                logd!(LOG_TAG, "[Pipeline] Channel closed, ticker exiting.");
+                break;
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Appends its mark to every message; holds them back when `hold` is set
+    /// until the next tick or flush.
+    struct Mark {
+        mark: &'static str,
+        hold: bool,
+        held: Vec<PipelineEntry>,
+    }
+
+    impl Mark {
+        fn stage(mark: &'static str, hold: bool) -> Box<dyn Stage> {
+            Box::new(Mark {
+                mark,
+                hold,
+                held: Vec::new(),
+            })
+        }
+    }
+
+    impl Stage for Mark {
+        fn process(&mut self, mut entry: PipelineEntry, out: &mut Vec<PipelineEntry>) {
+            entry.message.message.push_str(self.mark);
+            if self.hold {
+                self.held.push(entry);
+            } else {
+                out.push(entry);
+            }
+        }
+
+        fn tick(&mut self, _now: Instant, out: &mut Vec<PipelineEntry>) {
+            out.append(&mut self.held);
+        }
+
+        fn flush(&mut self, out: &mut Vec<PipelineEntry>) {
+            out.append(&mut self.held);
+        }
+    }
+
+    fn entry(text: &str) -> PipelineEntry {
+        PipelineEntry {
+            sink_type: u8::MAX,
+            message: LogMessage {
+                pid: 42,
+                uid: None,
+                priority: LogPriority::Info,
+                timestamp: LogTimeStamp::now(),
+                tag: Some(String::from("test")),
+                message: text.to_string(),
+                fields: Vec::new(),
+                trace: None,
+            },
+            unredacted: false,
+        }
+    }
+
+    fn texts(entries: Vec<PipelineEntry>) -> Vec<String> {
+        entries.into_iter().map(|e| e.message.message).collect()
+    }
+
+    #[test]
+    fn stages_run_in_order() {
+        let mut pipeline = Pipeline::new(vec![Mark::stage("a", false), Mark::stage("b", false)]);
+        assert_eq!(texts(pipeline.process(entry("m"))), ["mab"]);
+        assert_eq!(texts(Pipeline::default().process(entry("m"))), ["m"]);
+    }
+
+    #[test]
+    fn released_entries_continue_after_their_stage() {
+        let mut pipeline = Pipeline::new(vec![
+            Mark::stage("a", false),
+            Mark::stage("b", true),
+            Mark::stage("c", false),
+        ]);
+        assert!(pipeline.process(entry("1")).is_empty());
+        assert_eq!(texts(pipeline.tick(Instant::now())), ["1abc"]);
+        assert!(pipeline.process(entry("2")).is_empty());
+        assert_eq!(texts(pipeline.flush()), ["2abc"]);
+        assert!(pipeline.flush().is_empty());
+    }
+
+    #[test]
+    fn pipelines_are_built_from_stage_sections() {
+        let sinks = ["local_file", "android_native"];
+        let config = Config::parse(
+            "[stage warn]\ntype = min_priority\npriority = warn\n\
+             [pipeline]\nstages = warn\n\
+             [pipeline local_file]\nstages = warn\n",
+        )
+        .unwrap();
+        let mut set = PipelineSet::from_config(&config, &sinks).unwrap();
+        assert!(set.global.process(entry("info")).is_empty());
+        assert_eq!(set.per_sink.len(), 1);
+        assert!(set.per_sink.contains_key("local_file"));
+
+        for text in [
+            "[pipeline]\nstages = missing\n",
+            "[stage x]\ntype = unknown\n[pipeline]\nstages = x\n",
+            "[pipeline nowhere]\nstages =\n",
+        ] {
+            let config = Config::parse(text).unwrap();
+            assert!(
+                PipelineSet::from_config(&config, &sinks).is_err(),
+                "{}",
+                text
+            );
+        }
+    }
+
+    #[cfg(not(feature = "scripting"))]
+    #[test]
+    fn script_stages_need_the_scripting_build() {
+        let config =
+            Config::parse("[stage s]\ntype = script\npath = /x.rhai\n[pipeline]\nstages = s\n")
+                .unwrap();
+        let error = PipelineSet::from_config(&config, &[]).err().unwrap();
+        assert_eq!(
+            error.to_string(),
+            "Cannot use [stage s]: built without scripting"
+        );
+    }
+}
@@ -0,0 +1,309 @@
+use crate::config::{ConfigError, Section};
+use crate::msg_filter::{PipelineEntry, Stage};
+use crate::msg_sink::SinkTypeOrdinal;
+use regex::{Captures, Regex};
+use sha2::{Digest, Sha256};
+use std::fmt::Write;
+use std::fs;
+
+static CUSTOM_LABEL: &str = "redacted";
+static HASH_PREFIX_BYTES: usize = 8;
+
+/// Built-in detectors, applied in this order before the user patterns.
+/// Tokens go first so a key inside a token is not half-masked as something else.
+static BUILTIN_DETECTORS: &[(&str, &str, usize)] = &[
+    (
+        "token",
+        r"(?i)\b(?:bearer|token|api[_-]?key|access[_-]?token|auth|password|passwd|secret)\b\s*[:=]?\s*([A-Za-z0-9._~+/=-]{8,})",
+        1,
+    ),
+    (
+        "token",
+        r"\beyJ[A-Za-z0-9_-]+\.[A-Za-z0-9_-]+\.[A-Za-z0-9_-]+",
+        0,
+    ),
+    (
+        "email",
+        r"[A-Za-z0-9._%+-]+@[A-Za-z0-9.-]+\.[A-Za-z]{2,}",
+        0,
+    ),
+    (
+        "ip",
+        r"\b(?:(?:25[0-5]|2[0-4]\d|1?\d?\d)\.){3}(?:25[0-5]|2[0-4]\d|1?\d?\d)\b",
+        0,
+    ),
+    (
+        "ip",
+        r"\b(?:[0-9A-Fa-f]{1,4}:){7}[0-9A-Fa-f]{1,4}\b|\b(?:[0-9A-Fa-f]{1,4}:){1,6}:(?:[0-9A-Fa-f]{1,4}(?::[0-9A-Fa-f]{1,4}){0,5})?\b",
+        0,
+    ),
+    ("imei", r"\b\d{15}\b", 0),
+    (
+        "phone",
+        r"(?:\+\d{1,3}[\s.-]?)?(?:\(\d{3}\)|\b\d{3})[\s.-]?\d{3}[\s.-]?\d{4}\b",
+        0,
+    ),
+];
+
+struct Detector {
+    label: String,
+    regex: Regex,
+    /// Capture group holding the sensitive part, 0 for the whole match.
+    group: usize,
+}
+
+enum RedactMode {
+    Mask,
+    Hash(Vec<u8>),
+}
+
+/// Replaces personal data in the message text and in the values of its
+/// structured fields with `[label]`, or with
+/// `[label:hash]` where the hash is a salted SHA-256 prefix, so the same value
+/// can still be correlated across messages without being readable. Sinks in
+/// `exempt_sinks` receive the original message as a separate, `unredacted`
+/// copy; the redacted copy is always emitted, even when routed to no sink, so
+/// the ring buffer and triggers see every message once.
+pub struct RedactStage {
+    detectors: Vec<Detector>,
+    mode: RedactMode,
+    exempt_sinks: u8,
+}
+
+impl RedactStage {
+    pub fn from_section(section: &Section) -> Result<Self, ConfigError> {
+        let enabled = section.get_list("detectors");
+        let mut detectors = Vec::new();
+        for name in &enabled {
+            if !BUILTIN_DETECTORS.iter().any(|(label, _, _)| label == name) {
+                return Err(section.invalid("detectors", name));
+            }
+        }
+        for (label, pattern, group) in BUILTIN_DETECTORS {
+            if section.get("detectors").is_none() || enabled.contains(label) {
+                detectors.push(Detector {
+                    label: label.to_string(),
+                    regex: Regex::new(pattern).unwrap(),
+                    group: *group,
+                });
+            }
+        }
+        for pattern in section.get_all("pattern") {
+            detectors.push(Detector {
+                label: CUSTOM_LABEL.to_string(),
+                regex: Regex::new(pattern).map_err(|_| section.invalid("pattern", pattern))?,
+                group: 0,
+            });
+        }
+        let mode = match section.get("mode") {
+            None | Some("mask") => RedactMode::Mask,
+            Some("hash") => RedactMode::Hash(Self::salt(section)?),
+            Some(other) => return Err(section.invalid("mode", other)),
+        };
+        let exempt_sinks = match section.get("exempt_sinks") {
+            Some(_) => {
+                let mut mask = 0;
+                for name in section.get_list("exempt_sinks") {
+                    let ordinal = SinkTypeOrdinal::from_name(name)
+                        .ok_or_else(|| section.invalid("exempt_sinks", name))?;
+                    mask |= ordinal as u8;
+                }
+                mask
+            }
+            None => 0,
+        };
+        Ok(RedactStage {
+            detectors,
+            mode,
+            exempt_sinks,
+        })
+    }
+
+    /// Hashing without a salt would let short values such as phone numbers be
+    /// recovered by brute force, so one is required.
+    fn salt(section: &Section) -> Result<Vec<u8>, ConfigError> {
+        let salt = match (section.get("salt"), section.get("salt_file")) {
+            (_, Some(path)) => fs::read(path)?,
+            (Some(salt), None) => salt.as_bytes().to_vec(),
+            (None, None) => Vec::new(),
+        };
+        if salt.is_empty() {
+            return Err(section.invalid("salt", ""));
+        }
+        Ok(salt)
+    }
+
+    fn replacement(&self, label: &str, value: &str) -> String {
+        match &self.mode {
+            RedactMode::Mask => format!("[{}]", label),
+            RedactMode::Hash(salt) => {
+                let digest = Sha256::new()
+                    .chain_update(salt)
+                    .chain_update(value.as_bytes())
+                    .finalize();
+                let mut out = format!("[{}:", label);
+                for byte in &digest[..HASH_PREFIX_BYTES] {
+                    let _ = write!(out, "{:02x}", byte);
+                }
+                out.push(']');
+                out
+            }
+        }
+    }
+
+    pub fn redact(&self, text: &str) -> String {
+        let mut text = text.to_string();
+        for detector in &self.detectors {
+            if !detector.regex.is_match(&text) {
+                continue;
+            }
+            text = detector
+                .regex
+                .replace_all(&text, |caps: &Captures| {
+                    let whole = caps.get(0).unwrap();
+                    let Some(part) = caps.get(detector.group) else {
+                        return whole.as_str().to_string();
+                    };
+                    if detector.label == "imei" && !luhn_valid(part.as_str()) {
+                        return whole.as_str().to_string();
+                    }
+                    let start = part.start() - whole.start();
+                    let end = part.end() - whole.start();
+                    format!(
+                        "{}{}{}",
+                        &whole.as_str()[..start],
+                        self.replacement(&detector.label, part.as_str()),
+                        &whole.as_str()[end..]
+                    )
+                })
+                .into_owned();
+        }
+        text
+    }
+}
+
+impl Stage for RedactStage {
+    fn process(&mut self, mut entry: PipelineEntry, out: &mut Vec<PipelineEntry>) {
+        let exempt = entry.sink_type & self.exempt_sinks;
+        if exempt != 0 && !entry.unredacted {
+            out.push(PipelineEntry {
+                sink_type: exempt,
+                message: entry.message.clone(),
+                unredacted: true,
+            });
+            entry.sink_type &= !self.exempt_sinks;
+        }
+        if entry.unredacted {
+            out.push(entry);
+            return;
+        }
+        entry.message.message = self.redact(&entry.message.message);
+        for (_, value) in entry.message.fields.iter_mut() {
+            *value = self.redact(value);
+        }
+        out.push(entry);
+    }
+}
+
+/// IMEIs end with a Luhn check digit; other 15 digit numbers are left alone.
+fn luhn_valid(digits: &str) -> bool {
+    let sum: u32 = digits
+        .bytes()
+        .rev()
+        .enumerate()
+        .map(|(i, b)| {
+            let d = (b - b'0') as u32;
+            if i % 2 == 1 {
+                if d * 2 > 9 { d * 2 - 9 } else { d * 2 }
+            } else {
+                d
+            }
+        })
+        .sum();
+    sum.is_multiple_of(10)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::log_def::*;
+
+    fn stage(text: &str) -> RedactStage {
+        let config = Config::parse(text).unwrap();
+        RedactStage::from_section(config.section("stage").unwrap()).unwrap()
+    }
+
+    fn entry(sink_type: u8) -> PipelineEntry {
+        PipelineEntry {
+            sink_type,
+            message: LogMessage {
+                pid: 42,
+                uid: None,
+                priority: LogPriority::Info,
+                timestamp: LogTimeStamp::now(),
+                tag: Some(String::from("test")),
+                message: String::from("mail alice@example.com"),
+                fields: Vec::new(),
+                trace: None,
+            },
+            unredacted: false,
+        }
+    }
+
+    #[test]
+    fn exempt_sinks_get_an_unredacted_copy() {
+        let mut stage = stage("[stage pii]\ntype = redact\nexempt_sinks = local_file\n");
+        let mut out = Vec::new();
+        stage.process(entry(u8::MAX), &mut out);
+        assert_eq!(out.len(), 2);
+        assert!(out[0].unredacted);
+        assert_eq!(out[0].sink_type, SinkTypeOrdinal::LocalFileType as u8);
+        assert_eq!(out[0].message.message, "mail alice@example.com");
+        assert!(!out[1].unredacted);
+        assert_eq!(out[1].message.message, "mail [email]");
+        assert_eq!(out[1].sink_type & out[0].sink_type, 0);
+
+        // a second redact stage leaves the exempt copy alone
+        let copy = out.remove(0);
+        let mut again = Vec::new();
+        stage.process(copy, &mut again);
+        assert_eq!(again.len(), 1);
+        assert_eq!(again[0].message.message, "mail alice@example.com");
+    }
+
+    #[test]
+    fn field_values_are_redacted() {
+        let mut stage = stage("[stage pii]\ntype = redact\n");
+        let mut message = entry(u8::MAX);
+        message
+            .message
+            .fields
+            .push((String::from("user"), String::from("bob@example.com")));
+        message
+            .message
+            .fields
+            .push((String::from("code"), String::from("42")));
+        let mut out = Vec::new();
+        stage.process(message, &mut out);
+        assert_eq!(
+            out[0].message.fields,
+            [
+                (String::from("user"), String::from("[email]")),
+                (String::from("code"), String::from("42")),
+            ]
+        );
+    }
+
+    #[test]
+    fn redacted_copy_kept_when_all_sinks_exempt() {
+        let mut stage = stage("[stage pii]\ntype = redact\nexempt_sinks = local_file\n");
+        let mut out = Vec::new();
+        stage.process(entry(SinkTypeOrdinal::LocalFileType as u8), &mut out);
+        assert_eq!(out.len(), 2);
+        assert!(out[0].unredacted);
+        assert!(!out[1].unredacted);
+        assert_eq!(out[1].sink_type, 0);
+        assert_eq!(out[1].message.message, "mail [email]");
+    }
+}
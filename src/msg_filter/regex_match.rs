@@ -0,0 +1,159 @@
+use crate::config::{ConfigError, Section};
+use crate::msg_filter::{PipelineEntry, Stage};
+use crate::msg_sink::SinkTypeOrdinal;
+use regex::Regex;
+
+enum RegexAction {
+    /// Keep only matching messages.
+    Keep,
+    /// Drop matching messages.
+    Drop,
+    /// Send matching messages to these sinks instead.
+    Route(u8),
+    /// Rewrite every match in the message text.
+    Replace(String),
+}
+
+/// Matches a regex against the message text (or tag) and acts on the result.
+pub struct RegexStage {
+    regex: Regex,
+    on_tag: bool,
+    action: RegexAction,
+}
+
+impl RegexStage {
+    pub fn from_section(section: &Section) -> Result<Self, ConfigError> {
+        let pattern = section.get("pattern").unwrap_or_default();
+        let regex = Regex::new(pattern).map_err(|_| section.invalid("pattern", pattern))?;
+        let on_tag = match section.get("field") {
+            None | Some("message") => false,
+            Some("tag") => true,
+            Some(other) => return Err(section.invalid("field", other)),
+        };
+        let action = match section.get("action") {
+            None | Some("keep") => RegexAction::Keep,
+            Some("drop") => RegexAction::Drop,
+            Some("route") => RegexAction::Route(SinkTypeOrdinal::mask_from_section(section, 0)?),
+            Some("replace") => {
+                RegexAction::Replace(section.get("replace").unwrap_or_default().to_string())
+            }
+            Some(other) => return Err(section.invalid("action", other)),
+        };
+        Ok(RegexStage {
+            regex,
+            on_tag,
+            action,
+        })
+    }
+}
+
+impl Stage for RegexStage {
+    fn process(&mut self, mut entry: PipelineEntry, out: &mut Vec<PipelineEntry>) {
+        let text = if self.on_tag {
+            entry.message.tag.as_deref().unwrap_or_default()
+        } else {
+            entry.message.message.as_str()
+        };
+        let matched = self.regex.is_match(text);
+        match &self.action {
+            RegexAction::Keep if !matched => return,
+            RegexAction::Drop if matched => return,
+            RegexAction::Route(sink_type) if matched => entry.sink_type = *sink_type,
+            RegexAction::Replace(replacement) if matched => {
+                let replaced = self
+                    .regex
+                    .replace_all(text, replacement.as_str())
+                    .to_string();
+                if self.on_tag {
+                    entry.message.tag = Some(replaced);
+                } else {
+                    entry.message.message = replaced;
+                }
+            }
+            _ => (),
+        }
+        out.push(entry);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::log_def::*;
+
+    fn stage(text: &str) -> RegexStage {
+        let config = Config::parse(&format!("[stage s]\ntype = regex\n{}", text)).unwrap();
+        RegexStage::from_section(config.section("stage").unwrap()).unwrap()
+    }
+
+    fn run(stage: &mut RegexStage, tag: &str, text: &str) -> Option<PipelineEntry> {
+        let mut out = Vec::new();
+        stage.process(
+            PipelineEntry {
+                sink_type: u8::MAX,
+                message: LogMessage {
+                    pid: 42,
+                    uid: None,
+                    priority: LogPriority::Info,
+                    timestamp: LogTimeStamp::now(),
+                    tag: Some(tag.to_string()),
+                    message: text.to_string(),
+                    fields: Vec::new(),
+                    trace: None,
+                },
+                unredacted: false,
+            },
+            &mut out,
+        );
+        out.pop()
+    }
+
+    #[test]
+    fn keep_and_drop() {
+        let mut keep = stage("pattern = ^wifi\n");
+        assert!(run(&mut keep, "t", "wifi up").is_some());
+        assert!(run(&mut keep, "t", "radio up").is_none());
+
+        let mut drop = stage("pattern = chatty\naction = drop\nfield = tag\n");
+        assert!(run(&mut drop, "chatty", "wifi up").is_none());
+        assert!(run(&mut drop, "netd", "chatty").is_some());
+    }
+
+    #[test]
+    fn route_changes_the_sinks_of_matches() {
+        let mut route = stage("pattern = ^radio\naction = route\nsinks = android_native\n");
+        let routed = run(&mut route, "t", "radio up").unwrap();
+        assert_eq!(routed.sink_type, SinkTypeOrdinal::AndroidNativeType as u8);
+        assert_eq!(run(&mut route, "t", "wifi up").unwrap().sink_type, u8::MAX);
+    }
+
+    #[test]
+    fn replace_rewrites_every_match() {
+        let mut replace = stage("pattern = \\d+\naction = replace\nreplace = N\n");
+        let replaced = run(&mut replace, "t", "took 12ms of 300ms").unwrap();
+        assert_eq!(replaced.message.message, "took Nms of Nms");
+
+        let mut on_tag = stage("pattern = ^vendor\\.\nfield = tag\naction = replace\n");
+        let replaced = run(&mut on_tag, "vendor.gps", "fix").unwrap();
+        assert_eq!(replaced.message.tag.as_deref(), Some("gps"));
+        assert_eq!(replaced.message.message, "fix");
+    }
+
+    #[test]
+    fn invalid_options_are_rejected() {
+        for text in [
+            "pattern = (\n",
+            "pattern = x\nfield = pid\n",
+            "pattern = x\naction = explode\n",
+            "pattern = x\naction = route\nsinks = nowhere\n",
+        ] {
+            let config = Config::parse(&format!("[stage s]\ntype = regex\n{}", text)).unwrap();
+            assert!(
+                RegexStage::from_section(config.section("stage").unwrap()).is_err(),
+                "{}",
+                text
+            );
+        }
+    }
+}
@@ -0,0 +1,242 @@
+use crate::config::{ConfigError, Section};
+use crate::log_def::*;
+use crate::msg_filter::{PipelineEntry, Stage};
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, VecDeque};
+use std::time::{Duration, Instant};
+
+static DEFAULT_WINDOW: Duration = Duration::from_millis(200);
+static DEFAULT_MAX_HELD: usize = 10000;
+static DEFAULT_MAX_AHEAD: Duration = Duration::from_secs(1);
+static LATE_MARKER: &str = "[late] ";
+
+type SortKey = (u16, u8, u8, u8, u8, u8, u16);
+
+fn sort_key(timestamp: &LogTimeStamp) -> SortKey {
+    (
+        timestamp.year,
+        timestamp.month,
+        timestamp.day,
+        timestamp.hour,
+        timestamp.minute,
+        timestamp.second,
+        timestamp.millisecond,
+    )
+}
+
+/// A held entry; the arrival sequence keeps equal timestamps in arrival order.
+struct Held {
+    key: SortKey,
+    seq: u64,
+    entry: PipelineEntry,
+}
+
+impl PartialEq for Held {
+    fn eq(&self, other: &Self) -> bool {
+        (self.key, self.seq) == (other.key, other.seq)
+    }
+}
+
+impl Eq for Held {}
+
+impl PartialOrd for Held {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Held {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        (self.key, self.seq).cmp(&(other.key, other.seq))
+    }
+}
+
+/// Holds messages for a window and releases them sorted by client timestamp.
+/// A message older than one already released is marked late and passed on at
+/// once; messages at or above `bypass` are never held. Neither is a message
+/// stamped more than `max_ahead` past its receive time, so a bogus future
+/// timestamp cannot mark everything after it late.
+pub struct ReorderStage {
+    window: Duration,
+    bypass: LogPriority,
+    max_held: usize,
+    max_ahead: Duration,
+    held: BinaryHeap<Reverse<Held>>,
+    /// Arrival time and timestamp of the held entries, oldest arrival first.
+    /// Entries released early stay here until their window closes.
+    arrivals: VecDeque<(Instant, SortKey)>,
+    seq: u64,
+    /// Timestamp of the newest message released so far. Only held messages
+    /// move it, so it stays within `max_ahead` of the receive time.
+    released: Option<SortKey>,
+}
+
+impl ReorderStage {
+    pub fn from_section(section: &Section) -> Result<Self, ConfigError> {
+        let bypass = match section.get("bypass") {
+            Some(priority) => priority
+                .parse()
+                .map_err(|_| section.invalid("bypass", priority))?,
+            None => LogPriority::Error,
+        };
+        Ok(ReorderStage {
+            window: section
+                .get_parsed::<u64>("window_ms")?
+                .map(Duration::from_millis)
+                .unwrap_or(DEFAULT_WINDOW),
+            bypass,
+            max_held: section.get_parsed("max_held")?.unwrap_or(DEFAULT_MAX_HELD),
+            max_ahead: section
+                .get_parsed::<u64>("max_ahead_ms")?
+                .map(Duration::from_millis)
+                .unwrap_or(DEFAULT_MAX_AHEAD),
+            held: BinaryHeap::new(),
+            arrivals: VecDeque::new(),
+            seq: 0,
+            released: None,
+        })
+    }
+
+    /// Whether the timestamp is further in the future than reordering allows.
+    fn too_far_ahead(&self, timestamp: &LogTimeStamp) -> bool {
+        timestamp.to_wall_ms() - wall_clock_ms() > self.max_ahead.as_millis() as i64
+    }
+
+    fn release_one(&mut self, out: &mut Vec<PipelineEntry>) {
+        if let Some(Reverse(held)) = self.held.pop() {
+            self.released = self.released.max(Some(held.key));
+            out.push(held.entry);
+        }
+    }
+
+    /// Releases, in timestamp order, everything up to the newest timestamp
+    /// among the entries whose window has closed.
+    fn release_expired(&mut self, now: Instant, out: &mut Vec<PipelineEntry>) {
+        let mut limit = None;
+        while let Some((arrived, key)) = self.arrivals.front() {
+            if now.saturating_duration_since(*arrived) < self.window {
+                break;
+            }
+            limit = limit.max(Some(*key));
+            self.arrivals.pop_front();
+        }
+        let Some(limit) = limit else {
+            return;
+        };
+        while self
+            .held
+            .peek()
+            .is_some_and(|Reverse(held)| held.key <= limit)
+        {
+            self.release_one(out);
+        }
+    }
+}
+
+impl Stage for ReorderStage {
+    fn process(&mut self, mut entry: PipelineEntry, out: &mut Vec<PipelineEntry>) {
+        let now = Instant::now();
+        let key = sort_key(&entry.message.timestamp);
+        if entry.message.priority >= self.bypass || self.too_far_ahead(&entry.message.timestamp) {
+            out.push(entry);
+        } else if self.released.is_some_and(|released| key < released) {
+            entry.message.message.insert_str(0, LATE_MARKER);
+            out.push(entry);
+        } else {
+            self.seq += 1;
+            self.held.push(Reverse(Held {
+                key,
+                seq: self.seq,
+                entry,
+            }));
+            self.arrivals.push_back((now, key));
+            while self.held.len() > self.max_held {
+                self.release_one(out);
+            }
+        }
+        self.release_expired(now, out);
+    }
+
+    fn tick(&mut self, now: Instant, out: &mut Vec<PipelineEntry>) {
+        self.release_expired(now, out);
+    }
+
+    fn flush(&mut self, out: &mut Vec<PipelineEntry>) {
+        while !self.held.is_empty() {
+            self.release_one(out);
+        }
+        self.arrivals.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(offset_ms: i64, text: &str) -> PipelineEntry {
+        let now = wall_clock_ms();
+        PipelineEntry {
+            sink_type: u8::MAX,
+            message: LogMessage {
+                pid: 42,
+                uid: None,
+                priority: LogPriority::Info,
+                timestamp: LogTimeStamp::from_wall_ms(now + offset_ms),
+                tag: Some(String::from("test")),
+                message: text.to_string(),
+                fields: Vec::new(),
+                trace: None,
+            },
+            unredacted: false,
+        }
+    }
+
+    fn stage() -> ReorderStage {
+        ReorderStage {
+            window: Duration::from_secs(60),
+            bypass: LogPriority::Error,
+            max_held: DEFAULT_MAX_HELD,
+            max_ahead: DEFAULT_MAX_AHEAD,
+            held: BinaryHeap::new(),
+            arrivals: VecDeque::new(),
+            seq: 0,
+            released: None,
+        }
+    }
+
+    fn texts(out: &[PipelineEntry]) -> Vec<&str> {
+        out.iter().map(|e| e.message.message.as_str()).collect()
+    }
+
+    #[test]
+    fn releases_in_timestamp_order() {
+        let mut stage = stage();
+        let mut out = Vec::new();
+        stage.process(entry(-100, "b"), &mut out);
+        stage.process(entry(-200, "a"), &mut out);
+        stage.process(entry(-50, "c"), &mut out);
+        assert!(out.is_empty());
+        stage.flush(&mut out);
+        assert_eq!(texts(&out), ["a", "b", "c"]);
+
+        out.clear();
+        stage.process(entry(-300, "old"), &mut out);
+        assert_eq!(texts(&out), ["[late] old"]);
+    }
+
+    #[test]
+    fn far_future_timestamp_is_not_held() {
+        let mut stage = stage();
+        let mut out = Vec::new();
+        stage.process(entry(86_400_000, "future"), &mut out);
+        assert_eq!(texts(&out), ["future"]);
+        assert!(stage.released.is_none());
+
+        out.clear();
+        stage.process(entry(-100, "b"), &mut out);
+        stage.process(entry(-200, "a"), &mut out);
+        stage.flush(&mut out);
+        // the future stamp did not turn these into late messages
+        assert_eq!(texts(&out), ["a", "b"]);
+    }
+}
@@ -0,0 +1,319 @@
+use crate::config::{ConfigError, Section};
+use crate::log_def::*;
+use crate::msg_filter::{PipelineEntry, Stage};
+use crate::msg_sink::SinkTypeOrdinal;
+use crate::stats;
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+
+static DEFAULT_REPORT_INTERVAL: Duration = Duration::from_secs(60);
+static DEFAULT_BACKLOG_LOW: u64 = 1000;
+static DEFAULT_BACKLOG_HIGH: u64 = 10000;
+static DEFAULT_MIN_FACTOR: f64 = 0.01;
+/// Keep rates are applied in millionths so credits add up exactly.
+static RATE_SCALE: u64 = 1_000_000;
+
+/// Scales the Verbose and Debug keep rates down as the channel to the output
+/// handler backs up: unchanged up to `low`, down to `min_factor` at `high`.
+struct Adaptive {
+    low: u64,
+    high: u64,
+    min_factor: f64,
+}
+
+impl Adaptive {
+    fn factor(&self, depth: u64) -> f64 {
+        if depth <= self.low {
+            1.0
+        } else if depth >= self.high {
+            self.min_factor
+        } else {
+            let span = (depth - self.low) as f64 / (self.high - self.low) as f64;
+            1.0 - span * (1.0 - self.min_factor)
+        }
+    }
+}
+
+/// Keeps a fraction of the messages. The rate of a message's tag applies
+/// first, then the rate of its pid, then the rate of its priority. Sampling
+/// is deterministic: each pid and priority accumulates its rate and a
+/// message is kept whenever a whole credit is available. What is left of the
+/// credit carries over, so the kept fraction matches the rate. Unredacted
+/// copies have credits of their own, so both copies of a message share the
+/// same fate.
+pub struct SampleStage {
+    priority_rates: HashMap<LogPriority, f64>,
+    pid_rates: HashMap<u32, f64>,
+    tag_rates: HashMap<String, f64>,
+    adaptive: Option<Adaptive>,
+    credits: HashMap<(u32, LogPriority, bool), u64>,
+    sampled_out: [u64; 6],
+    report_interval: Duration,
+    last_report: Instant,
+    sink_type: u8,
+}
+
+/// Parses `key:rate` pairs with rates between 0 and 1.
+fn parse_rates<K: FromStr + std::hash::Hash + Eq>(
+    section: &Section,
+    key: &str,
+) -> Result<HashMap<K, f64>, ConfigError> {
+    let mut rates = HashMap::new();
+    for pair in section.get_list(key) {
+        let (name, rate) = pair
+            .split_once(':')
+            .ok_or_else(|| section.invalid(key, pair))?;
+        let name = name
+            .trim()
+            .parse()
+            .map_err(|_| section.invalid(key, pair))?;
+        let rate = rate
+            .trim()
+            .parse::<f64>()
+            .ok()
+            .filter(|rate| (0.0..=1.0).contains(rate))
+            .ok_or_else(|| section.invalid(key, pair))?;
+        rates.insert(name, rate);
+    }
+    Ok(rates)
+}
+
+impl SampleStage {
+    pub fn from_section(section: &Section) -> Result<Self, ConfigError> {
+        let adaptive = if section.get_parsed::<bool>("adaptive")?.unwrap_or(false) {
+            let low = section
+                .get_parsed("backlog_low")?
+                .unwrap_or(DEFAULT_BACKLOG_LOW);
+            let high = section
+                .get_parsed("backlog_high")?
+                .unwrap_or(DEFAULT_BACKLOG_HIGH);
+            if high <= low {
+                return Err(section.invalid("backlog_high", &high.to_string()));
+            }
+            let min_factor = section
+                .get_parsed::<f64>("min_factor")?
+                .unwrap_or(DEFAULT_MIN_FACTOR);
+            Some(Adaptive {
+                low,
+                high,
+                min_factor: min_factor.clamp(0.0, 1.0),
+            })
+        } else {
+            None
+        };
+        Ok(SampleStage {
+            priority_rates: parse_rates(section, "priority_rates")?,
+            pid_rates: parse_rates(section, "pid_rates")?,
+            tag_rates: parse_rates(section, "tag_rates")?,
+            adaptive,
+            credits: HashMap::new(),
+            sampled_out: [0; 6],
+            report_interval: section
+                .get_parsed::<u64>("report_s")?
+                .map(Duration::from_secs)
+                .unwrap_or(DEFAULT_REPORT_INTERVAL),
+            last_report: Instant::now(),
+            sink_type: SinkTypeOrdinal::mask_from_section(section, u8::MAX)?,
+        })
+    }
+
+    fn rate(&self, message: &LogMessage) -> f64 {
+        let rate = message
+            .tag
+            .as_ref()
+            .and_then(|tag| self.tag_rates.get(tag))
+            .or_else(|| self.pid_rates.get(&message.pid))
+            .or_else(|| self.priority_rates.get(&message.priority))
+            .copied()
+            .unwrap_or(1.0);
+        match &self.adaptive {
+            Some(adaptive) if message.priority <= LogPriority::Debug => {
+                rate * adaptive.factor(stats::global().channel_depth.load(Ordering::Relaxed))
+            }
+            _ => rate,
+        }
+    }
+
+    /// The redacted copy stands for the message in the report.
+    fn count_sampled_out(&mut self, entry: &PipelineEntry) {
+        if !entry.unredacted {
+            self.sampled_out[entry.message.priority as usize] += 1;
+        }
+    }
+
+    fn report(&mut self) -> Option<PipelineEntry> {
+        let total: u64 = self.sampled_out.iter().sum();
+        if total == 0 {
+            return None;
+        }
+        let mut text = format!("sampled out {} messages", total);
+        let by_priority: Vec<_> = [
+            LogPriority::Verbose,
+            LogPriority::Debug,
+            LogPriority::Info,
+            LogPriority::Warn,
+            LogPriority::Error,
+            LogPriority::Fatal,
+        ]
+        .iter()
+        .zip(self.sampled_out)
+        .filter(|(_, count)| *count > 0)
+        .map(|(priority, count)| format!("{}={}", priority.name(), count))
+        .collect();
+        text.push_str(&format!(" ({})", by_priority.join(" ")));
+        if let Some(adaptive) = &self.adaptive {
+            let depth = stats::global().channel_depth.load(Ordering::Relaxed);
+            text.push_str(&format!(
+                " backlog={} factor={:.2}",
+                depth,
+                adaptive.factor(depth)
+            ));
+        }
+        self.sampled_out = [0; 6];
+        Some(PipelineEntry {
+            sink_type: self.sink_type,
+            message: LogMessage::daemon(text),
+            unredacted: false,
+        })
+    }
+}
+
+impl Stage for SampleStage {
+    fn process(&mut self, entry: PipelineEntry, out: &mut Vec<PipelineEntry>) {
+        let rate = (self.rate(&entry.message) * RATE_SCALE as f64).round() as u64;
+        if rate >= RATE_SCALE {
+            out.push(entry);
+            return;
+        }
+        let key = (entry.message.pid, entry.message.priority, entry.unredacted);
+        if rate == 0 {
+            self.count_sampled_out(&entry);
+            return;
+        }
+        // the first message of a pid and priority is always kept
+        let credit = self.credits.entry(key).or_insert(RATE_SCALE - rate);
+        *credit += rate;
+        if *credit >= RATE_SCALE {
+            *credit -= RATE_SCALE;
+            out.push(entry);
+        } else {
+            self.count_sampled_out(&entry);
+        }
+    }
+
+    fn tick(&mut self, now: Instant, out: &mut Vec<PipelineEntry>) {
+        if now.saturating_duration_since(self.last_report) < self.report_interval {
+            return;
+        }
+        self.last_report = now;
+        // credits of pids that went quiet are not worth keeping
+        self.credits.clear();
+        out.extend(self.report());
+    }
+
+    fn flush(&mut self, out: &mut Vec<PipelineEntry>) {
+        out.extend(self.report());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::msg_filter::PipelineSet;
+
+    fn stage(rates: &str) -> SampleStage {
+        let config = Config::parse(&format!("[stage s]\ntype = sample\n{}\n", rates)).unwrap();
+        SampleStage::from_section(config.section("stage").unwrap()).unwrap()
+    }
+
+    fn entry(pid: u32, priority: LogPriority) -> PipelineEntry {
+        PipelineEntry {
+            sink_type: u8::MAX,
+            message: LogMessage {
+                pid,
+                uid: None,
+                priority,
+                timestamp: LogTimeStamp::now(),
+                tag: None,
+                message: String::from("sampled"),
+                fields: Vec::new(),
+                trace: None,
+            },
+            unredacted: false,
+        }
+    }
+
+    fn kept(stage: &mut SampleStage, pid: u32, priority: LogPriority, count: usize) -> usize {
+        let mut out = Vec::new();
+        for _ in 0..count {
+            stage.process(entry(pid, priority), &mut out);
+        }
+        out.len()
+    }
+
+    #[test]
+    fn kept_fraction_matches_rate() {
+        for (rate, count, expected) in [
+            ("0.7", 1000, 700),
+            ("0.1", 1000, 100),
+            ("0.25", 1000, 250),
+            ("0.3333", 10000, 3333),
+            ("0.999", 1000, 999),
+        ] {
+            let mut stage = stage(&format!("priority_rates = info:{}", rate));
+            assert_eq!(
+                kept(&mut stage, 1, LogPriority::Info, count),
+                expected,
+                "rate {}",
+                rate
+            );
+        }
+    }
+
+    #[test]
+    fn credits_are_per_pid_and_priority() {
+        let mut stage = stage("priority_rates = info:0.5, debug:0");
+        assert_eq!(kept(&mut stage, 1, LogPriority::Info, 3), 2);
+        assert_eq!(kept(&mut stage, 2, LogPriority::Info, 1), 1);
+        assert_eq!(kept(&mut stage, 1, LogPriority::Debug, 10), 0);
+        assert_eq!(kept(&mut stage, 1, LogPriority::Warn, 10), 10);
+        assert_eq!(stage.sampled_out[LogPriority::Debug as usize], 10);
+    }
+
+    #[test]
+    fn both_copies_of_a_redacted_message_are_sampled_alike() {
+        let config = Config::parse(
+            "[stage pii]\ntype = redact\nexempt_sinks = local_file\n\
+             [stage s]\ntype = sample\npriority_rates = debug:0.5\n\
+             [pipeline]\nstages = pii, s\n",
+        )
+        .unwrap();
+        let mut pipeline = PipelineSet::from_config(&config, &["local_file"])
+            .unwrap()
+            .global;
+        let local_file = SinkTypeOrdinal::LocalFileType as u8;
+        let (mut exempt, mut others) = (0, 0);
+        for _ in 0..10 {
+            for entry in pipeline.process(entry(1, LogPriority::Debug)) {
+                if entry.sink_type & local_file != 0 {
+                    exempt += 1;
+                }
+                if entry.sink_type & !local_file != 0 {
+                    others += 1;
+                }
+            }
+        }
+        assert_eq!((exempt, others), (5, 5));
+        // the report counts each message once
+        let report = pipeline.flush();
+        assert!(
+            report[0]
+                .message
+                .message
+                .starts_with("sampled out 5 messages")
+        );
+    }
+}
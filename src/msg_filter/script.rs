@@ -0,0 +1,639 @@
+use crate::config::{ConfigError, Section};
+use crate::log::*;
+use crate::log_def::*;
+use crate::msg_filter::{PipelineEntry, Stage};
+use crate::msg_sink::{SinkTypeOrdinal, local_file};
+use rhai::module_resolvers::DummyModuleResolver;
+use rhai::{AST, Array, CallFnOptions, Dynamic, Engine, EvalAltResult, INT, Map, Scope};
+use serde_json::Value;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+/// Flag of the command line test harness: `notcatd --test-script <script> <fixtures>`.
+pub static TEST_FLAG: &str = "--test-script";
+/// The script function called for every message, with the message as `this`.
+static SCRIPT_FUNCTION: &str = "process";
+static DEFAULT_RELOAD_INTERVAL: Duration = Duration::from_secs(5);
+static DEFAULT_MAX_OPERATIONS: u64 = 100_000;
+static DEFAULT_MAX_CALL_LEVELS: usize = 16;
+static DEFAULT_MAX_STRING_SIZE: usize = 64 * 1024;
+static DEFAULT_MAX_COLLECTION_SIZE: usize = 1024;
+static DEFAULT_MAX_EMITTED: usize = 16;
+static SINK_ORDINALS: [SinkTypeOrdinal; 2] = [
+    SinkTypeOrdinal::LocalFileType,
+    SinkTypeOrdinal::AndroidNativeType,
+];
+
+/// Bounds on a single call of the script. Scripts have no file, process or
+/// network access, cannot `import` modules or `eval` text.
+#[derive(Debug, Clone, Copy)]
+pub struct ScriptLimits {
+    pub max_operations: u64,
+    pub max_call_levels: usize,
+    pub max_string_size: usize,
+    /// Largest array or object map.
+    pub max_collection_size: usize,
+    /// Records one message may `emit`.
+    pub max_emitted: usize,
+}
+
+impl Default for ScriptLimits {
+    fn default() -> Self {
+        ScriptLimits {
+            max_operations: DEFAULT_MAX_OPERATIONS,
+            max_call_levels: DEFAULT_MAX_CALL_LEVELS,
+            max_string_size: DEFAULT_MAX_STRING_SIZE,
+            max_collection_size: DEFAULT_MAX_COLLECTION_SIZE,
+            max_emitted: DEFAULT_MAX_EMITTED,
+        }
+    }
+}
+
+impl ScriptLimits {
+    fn from_section(section: &Section) -> Result<Self, ConfigError> {
+        let defaults = ScriptLimits::default();
+        Ok(ScriptLimits {
+            max_operations: section
+                .get_parsed("max_operations")?
+                .unwrap_or(defaults.max_operations),
+            max_call_levels: section
+                .get_parsed("max_call_levels")?
+                .unwrap_or(defaults.max_call_levels),
+            max_string_size: section
+                .get_parsed("max_string_size")?
+                .unwrap_or(defaults.max_string_size),
+            max_collection_size: section
+                .get_parsed("max_collection_size")?
+                .unwrap_or(defaults.max_collection_size),
+            max_emitted: section
+                .get_parsed("max_emitted")?
+                .unwrap_or(defaults.max_emitted),
+        })
+    }
+}
+
+/// What the script made of one message.
+struct ScriptResult {
+    /// `None` when the script returned `false`.
+    kept: Option<PipelineEntry>,
+    emitted: Vec<PipelineEntry>,
+}
+
+/// Sandboxed engine running the `process` function of a script.
+struct ScriptRunner {
+    engine: Engine,
+    /// Filled by `emit` during a call.
+    emitted: Arc<Mutex<Vec<Map>>>,
+}
+
+impl ScriptRunner {
+    fn new(limits: ScriptLimits) -> Self {
+        let emitted = Arc::new(Mutex::new(Vec::new()));
+        let mut engine = Engine::new();
+        engine
+            .set_max_operations(limits.max_operations)
+            .set_max_call_levels(limits.max_call_levels)
+            .set_max_string_size(limits.max_string_size)
+            .set_max_array_size(limits.max_collection_size)
+            .set_max_map_size(limits.max_collection_size)
+            .set_module_resolver(DummyModuleResolver::new())
+            .disable_symbol("eval")
+            .on_print(|text| logd!(LOG_TAG, "[Script] {}", text))
+            .on_debug(|text, _, position| logd!(LOG_TAG, "[Script] {} {}", position, text));
+        let queue = emitted.clone();
+        let max_emitted = limits.max_emitted;
+        engine.register_fn(
+            "emit",
+            move |record: Map| -> Result<(), Box<EvalAltResult>> {
+                let mut queue = queue.lock().unwrap();
+                if queue.len() >= max_emitted {
+                    return Err(format!("more than {} records emitted", max_emitted).into());
+                }
+                queue.push(record);
+                Ok(())
+            },
+        );
+        ScriptRunner { engine, emitted }
+    }
+
+    fn compile(&self, path: &Path) -> Result<AST, String> {
+        let text = fs::read_to_string(path).map_err(|e| e.to_string())?;
+        let ast = self.engine.compile(text).map_err(|e| e.to_string())?;
+        if !ast
+            .iter_functions()
+            .any(|f| f.name == SCRIPT_FUNCTION && f.params.is_empty())
+        {
+            return Err(format!("no {}() function", SCRIPT_FUNCTION));
+        }
+        Ok(ast)
+    }
+
+    /// Top-level statements of the script are not run, only `process`.
+    fn run(&self, ast: &AST, entry: &PipelineEntry) -> Result<ScriptResult, String> {
+        self.emitted.lock().unwrap().clear();
+        let mut this = Dynamic::from_map(to_map(entry));
+        let options = CallFnOptions::new()
+            .eval_ast(false)
+            .bind_this_ptr(&mut this);
+        let returned = self.engine.call_fn_with_options::<Dynamic>(
+            options,
+            &mut Scope::new(),
+            ast,
+            SCRIPT_FUNCTION,
+            (),
+        );
+        let emitted = std::mem::take(&mut *self.emitted.lock().unwrap());
+        let returned = returned.map_err(|e| e.to_string())?;
+        let kept = if returned.as_bool().unwrap_or(true) {
+            let map = this
+                .try_cast::<Map>()
+                .ok_or_else(|| String::from("this is no longer an object map"))?;
+            Some(from_map(&map, entry)?)
+        } else {
+            None
+        };
+        Ok(ScriptResult {
+            kept,
+            emitted: emitted
+                .iter()
+                .map(|record| from_map(record, entry))
+                .collect::<Result<_, _>>()?,
+        })
+    }
+}
+
+fn sink_names(mask: u8) -> Array {
+    SINK_ORDINALS
+        .iter()
+        .filter(|ordinal| mask & **ordinal as u8 != 0)
+        .map(|ordinal| ordinal.name().into())
+        .collect()
+}
+
+fn known_sinks() -> u8 {
+    SINK_ORDINALS
+        .iter()
+        .fold(0, |mask, ordinal| mask | *ordinal as u8)
+}
+
+/// The object the script sees as `this`.
+fn to_map(entry: &PipelineEntry) -> Map {
+    let message = &entry.message;
+    let optional = |value: Option<Dynamic>| value.unwrap_or(Dynamic::UNIT);
+    let mut map = Map::new();
+    map.insert("pid".into(), (message.pid as INT).into());
+    map.insert(
+        "uid".into(),
+        optional(message.uid.map(|uid| (uid as INT).into())),
+    );
+    map.insert("priority".into(), message.priority.name().into());
+    map.insert("time_ms".into(), message.timestamp.to_unix_ms().into());
+    map.insert("tag".into(), optional(message.tag.clone().map(Into::into)));
+    map.insert("message".into(), message.message.clone().into());
+    let fields: Map = message
+        .fields
+        .iter()
+        .map(|(key, value)| (key.into(), value.clone().into()))
+        .collect();
+    map.insert("fields".into(), Dynamic::from_map(fields));
+    map.insert(
+        "trace_id".into(),
+        optional(message.trace.map(|trace| trace.trace_id_hex().into())),
+    );
+    map.insert(
+        "span_id".into(),
+        optional(message.trace.map(|trace| trace.span_id_hex().into())),
+    );
+    map.insert(
+        "sinks".into(),
+        Dynamic::from_array(sink_names(entry.sink_type)),
+    );
+    map
+}
+
+fn int(key: &str, value: &Dynamic) -> Result<INT, String> {
+    value
+        .as_int()
+        .map_err(|found| format!("{} must be an integer, not {}", key, found))
+}
+
+fn string(key: &str, value: &Dynamic) -> Result<String, String> {
+    value
+        .clone()
+        .into_string()
+        .map_err(|found| format!("{} must be a string, not {}", key, found))
+}
+
+/// Builds an entry from a script object. Keys left out keep the value they
+/// have in `base`, the message the script was called with.
+fn from_map(map: &Map, base: &PipelineEntry) -> Result<PipelineEntry, String> {
+    let mut message = base.message.clone();
+    let mut sink_type = base.sink_type;
+    for (key, value) in map {
+        let key = key.as_str();
+        match key {
+            "pid" => {
+                message.pid =
+                    u32::try_from(int(key, value)?).map_err(|_| format!("invalid pid {}", value))?
+            }
+            "uid" if value.is_unit() => message.uid = None,
+            "uid" => {
+                message.uid = Some(
+                    u32::try_from(int(key, value)?)
+                        .map_err(|_| format!("invalid uid {}", value))?,
+                )
+            }
+            "priority" => {
+                let priority = string(key, value)?;
+                message.priority = priority
+                    .parse()
+                    .map_err(|_| format!("invalid priority {}", priority))?;
+            }
+            "time_ms" => {
+                let time_ms = int(key, value)?;
+                if time_ms != base.message.timestamp.to_unix_ms() {
+                    message.timestamp = LogTimeStamp::from_unix_ms(time_ms);
+                }
+            }
+            "tag" if value.is_unit() => message.tag = None,
+            "tag" => message.tag = Some(string(key, value)?),
+            "message" => message.message = string(key, value)?,
+            "fields" => {
+                let fields = value
+                    .clone()
+                    .try_cast::<Map>()
+                    .ok_or_else(|| String::from("fields must be an object map"))?;
+                message.fields = merge_fields(&base.message.fields, &fields);
+            }
+            "sinks" => {
+                let mask = sink_mask(value)?;
+                if mask != base.sink_type & known_sinks() {
+                    sink_type = mask;
+                }
+            }
+            "trace_id" | "span_id" => {}
+            other => return Err(format!("unknown key {}", other)),
+        }
+    }
+    if map.contains_key("trace_id") || map.contains_key("span_id") {
+        let text = |key: &str| {
+            map.get(key)
+                .filter(|value| !value.is_unit())
+                .map(|value| value.to_string())
+        };
+        message.trace = match (text("trace_id"), text("span_id")) {
+            (None, None) => None,
+            (Some(trace_id), Some(span_id)) => {
+                let flags = base.message.trace.map_or(1, |trace| trace.flags);
+                let traceparent = format!("00-{}-{}-{:02x}", trace_id, span_id, flags);
+                Some(
+                    TraceContext::parse_traceparent(&traceparent)
+                        .ok_or_else(|| format!("invalid trace context {}", traceparent))?,
+                )
+            }
+            _ => return Err(String::from("trace_id and span_id go together")),
+        };
+    }
+    Ok(PipelineEntry {
+        sink_type,
+        message,
+        unredacted: base.unredacted,
+    })
+}
+
+/// Keeps the order of the fields already there; `()` removes a field.
+fn merge_fields(base: &[(String, String)], updated: &Map) -> Vec<(String, String)> {
+    let present = |value: &&Dynamic| !value.is_unit();
+    let mut fields: Vec<(String, String)> = base
+        .iter()
+        .filter_map(|(key, _)| {
+            updated
+                .get(key.as_str())
+                .filter(present)
+                .map(|value| (key.clone(), value.to_string()))
+        })
+        .collect();
+    for (key, value) in updated {
+        if present(&value) && !fields.iter().any(|(k, _)| k == key.as_str()) {
+            fields.push((key.to_string(), value.to_string()));
+        }
+    }
+    fields
+}
+
+fn sink_mask(value: &Dynamic) -> Result<u8, String> {
+    let names = value
+        .clone()
+        .try_cast::<Array>()
+        .ok_or_else(|| String::from("sinks must be an array"))?;
+    let mut mask = 0;
+    for name in names {
+        let name = string("sinks", &name)?;
+        let ordinal =
+            SinkTypeOrdinal::from_name(&name).ok_or_else(|| format!("unknown sink {}", name))?;
+        mask |= ordinal as u8;
+    }
+    Ok(mask)
+}
+
+/// Runs each message through the `process()` function of a Rhai script, with
+/// the message bound to `this`. The script may change any of its keys
+/// (`pid`, `uid`, `priority`, `time_ms`, `tag`, `message`, `fields`,
+/// `trace_id`, `span_id`, `sinks`), return `false` to drop it and call
+/// `emit(#{...})` for extra records, which start as copies of the message.
+/// The file is reloaded when it changes; a version that fails to compile is
+/// ignored. A message the script fails on passes unchanged.
+pub struct ScriptStage {
+    path: PathBuf,
+    runner: ScriptRunner,
+    script: Option<AST>,
+    modified: Option<SystemTime>,
+    reload_interval: Duration,
+    last_check: Instant,
+    /// Failed calls since the script was last loaded.
+    errors: u64,
+}
+
+impl ScriptStage {
+    pub fn from_section(section: &Section) -> Result<Self, ConfigError> {
+        let path = section
+            .get("path")
+            .ok_or_else(|| section.invalid("path", ""))?;
+        let mut stage = ScriptStage {
+            path: PathBuf::from(path),
+            runner: ScriptRunner::new(ScriptLimits::from_section(section)?),
+            script: None,
+            modified: None,
+            reload_interval: section
+                .get_parsed::<u64>("reload_s")?
+                .map(Duration::from_secs)
+                .unwrap_or(DEFAULT_RELOAD_INTERVAL),
+            last_check: Instant::now(),
+            errors: 0,
+        };
+        stage.reload();
+        Ok(stage)
+    }
+
+    fn reload(&mut self) {
+        let modified = fs::metadata(&self.path)
+            .and_then(|metadata| metadata.modified())
+            .ok();
+        if modified.is_none() || modified == self.modified {
+            return;
+        }
+        self.modified = modified;
+        match self.runner.compile(&self.path) {
+            Ok(ast) => {
+                logi!(LOG_TAG, "[Script] Loaded {}", self.path.display());
+                self.script = Some(ast);
+                self.errors = 0;
+            }
+            Err(e) => loge!(
+                LOG_TAG,
+                "[Script] Failed to load {}: {}",
+                self.path.display(),
+                e
+            ),
+        }
+    }
+}
+
+impl Stage for ScriptStage {
+    fn process(&mut self, entry: PipelineEntry, out: &mut Vec<PipelineEntry>) {
+        let Some(script) = &self.script else {
+            out.push(entry);
+            return;
+        };
+        match self.runner.run(script, &entry) {
+            Ok(result) => {
+                out.extend(result.kept);
+                out.extend(result.emitted);
+            }
+            Err(e) => {
+                self.errors += 1;
+                if self.errors == 1 {
+                    logw!(LOG_TAG, "[Script] {}: {}", self.path.display(), e);
+                } else {
+                    logv!(LOG_TAG, "[Script] {}: {}", self.path.display(), e);
+                }
+                out.push(entry);
+            }
+        }
+    }
+
+    fn tick(&mut self, now: Instant, _out: &mut Vec<PipelineEntry>) {
+        if now.saturating_duration_since(self.last_check) >= self.reload_interval {
+            self.last_check = now;
+            self.reload();
+        }
+    }
+}
+
+/// Entry point of `notcatd --test-script <script> <fixtures>`. Each line of
+/// the fixtures file is a JSON message, e.g. `{"pid": 1234, "priority":
+/// "warn", "tag": "Net", "message": "...", "fields": {}, "sinks":
+/// ["local_file"], "traceparent": "..."}`, with an optional `expect` object
+/// (`dropped`, `message`, `tag`, `priority`, `sinks`, `fields`, `emitted`)
+/// checked against the result. Returns the process exit code.
+pub fn test_main(args: &[String]) -> i32 {
+    let [script, fixtures] = args else {
+        eprintln!("usage: notcatd {} <script> <fixtures>", TEST_FLAG);
+        return 2;
+    };
+    let mut runner = ScriptRunner::new(ScriptLimits::default());
+    runner.engine.on_print(|text| println!("print: {}", text));
+    let ast = match runner.compile(Path::new(script)) {
+        Ok(ast) => ast,
+        Err(e) => {
+            eprintln!("{}: {}", script, e);
+            return 2;
+        }
+    };
+    let fixtures = match fs::read_to_string(fixtures) {
+        Ok(text) => text,
+        Err(e) => {
+            eprintln!("{}: {}", fixtures, e);
+            return 2;
+        }
+    };
+    let (mut total, mut failed) = (0, 0);
+    for (number, line) in fixtures.lines().enumerate() {
+        if line.trim().is_empty() || line.trim_start().starts_with('#') {
+            continue;
+        }
+        total += 1;
+        let label = format!("line {}", number + 1);
+        let fixture = match serde_json::from_str::<Value>(line)
+            .map_err(|e| e.to_string())
+            .and_then(|value| fixture_entry(&value).map(|entry| (value, entry)))
+        {
+            Ok(fixture) => fixture,
+            Err(e) => {
+                println!("{}: invalid fixture: {}", label, e);
+                failed += 1;
+                continue;
+            }
+        };
+        let (value, entry) = fixture;
+        let result = match runner.run(&ast, &entry) {
+            Ok(result) => result,
+            Err(e) => {
+                println!("{}: error: {}", label, e);
+                failed += 1;
+                continue;
+            }
+        };
+        match &result.kept {
+            Some(kept) => println!("{}: kept {}", label, describe(kept)),
+            None => println!("{}: dropped", label),
+        }
+        for emitted in &result.emitted {
+            println!("{}: emitted {}", label, describe(emitted));
+        }
+        let mismatches = value
+            .get("expect")
+            .map(|expect| check(expect, &result))
+            .unwrap_or_default();
+        for mismatch in &mismatches {
+            println!("{}: FAIL {}", label, mismatch);
+        }
+        if !mismatches.is_empty() {
+            failed += 1;
+        }
+    }
+    println!("{} fixtures, {} failed", total, failed);
+    if failed == 0 { 0 } else { 1 }
+}
+
+fn describe(entry: &PipelineEntry) -> String {
+    let sinks: Vec<String> = sink_names(entry.sink_type)
+        .iter()
+        .map(|name| name.to_string())
+        .collect();
+    format!(
+        "[{}] {}",
+        sinks.join(","),
+        local_file::format_line(&entry.message)
+    )
+}
+
+fn fixture_entry(value: &Value) -> Result<PipelineEntry, String> {
+    let text = |key: &str| value.get(key).and_then(Value::as_str);
+    let number = |key: &str| value.get(key).and_then(Value::as_u64);
+    let priority = text("priority").unwrap_or("info");
+    let mut message = LogMessage {
+        pid: number("pid").unwrap_or(1000) as u32,
+        uid: number("uid").map(|uid| uid as u32),
+        priority: priority
+            .parse()
+            .map_err(|_| format!("invalid priority {}", priority))?,
+        timestamp: match value.get("time_ms").and_then(Value::as_i64) {
+            Some(time_ms) => LogTimeStamp::from_unix_ms(time_ms),
+            None => LogTimeStamp::now(),
+        },
+        tag: text("tag").map(String::from),
+        message: text("message").unwrap_or_default().to_string(),
+        fields: Vec::new(),
+        trace: text("traceparent").and_then(TraceContext::parse_traceparent),
+    };
+    if let Some(Value::Object(fields)) = value.get("fields") {
+        for (key, value) in fields {
+            let value = value
+                .as_str()
+                .map_or_else(|| value.to_string(), String::from);
+            message.fields.push((key.clone(), value));
+        }
+    }
+    let sink_type = match value.get("sinks").and_then(Value::as_array) {
+        Some(names) => {
+            let mut mask = 0;
+            for name in names {
+                let name = name.as_str().unwrap_or_default();
+                mask |= SinkTypeOrdinal::from_name(name)
+                    .ok_or_else(|| format!("unknown sink {}", name))? as u8;
+            }
+            mask
+        }
+        None => known_sinks(),
+    };
+    Ok(PipelineEntry {
+        sink_type,
+        message,
+        unredacted: false,
+    })
+}
+
+/// Differences between `expect` and what the script produced.
+fn check(expect: &Value, result: &ScriptResult) -> Vec<String> {
+    let mut mismatches = Vec::new();
+    let mut compare = |what: &str, expected: String, actual: String| {
+        if expected != actual {
+            mismatches.push(format!("{}: expected {}, got {}", what, expected, actual));
+        }
+    };
+    if let Some(dropped) = expect.get("dropped").and_then(Value::as_bool) {
+        compare(
+            "dropped",
+            dropped.to_string(),
+            result.kept.is_none().to_string(),
+        );
+    }
+    if let Some(emitted) = expect.get("emitted").and_then(Value::as_u64) {
+        compare(
+            "emitted",
+            emitted.to_string(),
+            result.emitted.len().to_string(),
+        );
+    }
+    let Some(kept) = &result.kept else {
+        return mismatches;
+    };
+    if let Some(message) = expect.get("message").and_then(Value::as_str) {
+        compare(
+            "message",
+            format!("{:?}", message),
+            format!("{:?}", kept.message.message),
+        );
+    }
+    if let Some(tag) = expect.get("tag").and_then(Value::as_str) {
+        compare(
+            "tag",
+            format!("{:?}", tag),
+            format!("{:?}", kept.message.tag.as_deref().unwrap_or_default()),
+        );
+    }
+    if let Some(priority) = expect.get("priority").and_then(Value::as_str) {
+        compare(
+            "priority",
+            priority.to_string(),
+            kept.message.priority.name().to_string(),
+        );
+    }
+    if let Some(Value::Array(sinks)) = expect.get("sinks") {
+        let expected: Vec<&str> = sinks.iter().filter_map(Value::as_str).collect();
+        let actual: Vec<String> = sink_names(kept.sink_type)
+            .iter()
+            .map(|name| name.to_string())
+            .collect();
+        compare("sinks", expected.join(","), actual.join(","));
+    }
+    if let Some(Value::Object(fields)) = expect.get("fields") {
+        for (key, value) in fields {
+            let expected = value
+                .as_str()
+                .map_or_else(|| value.to_string(), String::from);
+            let actual = kept
+                .message
+                .fields
+                .iter()
+                .find(|(k, _)| k == key)
+                .map(|(_, v)| v.clone())
+                .unwrap_or_default();
+            compare(&format!("field {}", key), expected, actual);
+        }
+    }
+    mismatches
+}
@@ -0,0 +1,104 @@
+use crate::config::{ConfigError, Section};
+use crate::msg_filter::{PipelineEntry, Stage};
+use regex::RegexSet;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+static DEFAULT_WINDOW: Duration = Duration::from_millis(200);
+static DEFAULT_MAX_FRAMES: usize = 256;
+/// Java frames, causes and elided frames, then native backtrace frames.
+static DEFAULT_FRAME_PATTERNS: [&str; 4] = [
+    r"^\s+at \S",
+    r"^Caused by: ",
+    r"^\s*\.\.\. \d+ more$",
+    r"^\s*#\d+ pc [0-9a-fA-F]+ ",
+];
+
+/// The last message of a pid, with the frames that followed it so far.
+struct Group {
+    head: PipelineEntry,
+    frames: usize,
+    last_seen: Instant,
+}
+
+/// Joins stack-frame messages into the message of the same pid before them,
+/// so a trace logged one frame at a time becomes one multi-line record. The
+/// last message of each pid is held until a non-frame message from that pid
+/// arrives or `window` passes without frames.
+pub struct StackGroupStage {
+    frames: RegexSet,
+    window: Duration,
+    max_frames: usize,
+    groups: HashMap<u32, Group>,
+}
+
+impl StackGroupStage {
+    pub fn from_section(section: &Section) -> Result<Self, ConfigError> {
+        let patterns: Vec<&str> = section.get_all("frame_pattern").collect();
+        let frames = if patterns.is_empty() {
+            RegexSet::new(DEFAULT_FRAME_PATTERNS)
+        } else {
+            RegexSet::new(&patterns)
+        }
+        .map_err(|_| section.invalid("frame_pattern", &patterns.join(" ")))?;
+        Ok(StackGroupStage {
+            frames,
+            window: section
+                .get_parsed::<u64>("window_ms")?
+                .map(Duration::from_millis)
+                .unwrap_or(DEFAULT_WINDOW),
+            max_frames: section
+                .get_parsed("max_frames")?
+                .unwrap_or(DEFAULT_MAX_FRAMES),
+            groups: HashMap::new(),
+        })
+    }
+}
+
+impl Stage for StackGroupStage {
+    fn process(&mut self, entry: PipelineEntry, out: &mut Vec<PipelineEntry>) {
+        let now = Instant::now();
+        let pid = entry.message.pid;
+        if let Some(group) = self.groups.get_mut(&pid) {
+            let joins = group.frames < self.max_frames
+                && group.head.sink_type == entry.sink_type
+                && group.head.message.tag == entry.message.tag
+                && self.frames.is_match(&entry.message.message);
+            if joins {
+                let head = &mut group.head.message;
+                head.message.push('\n');
+                head.message.push_str(&entry.message.message);
+                head.priority = head.priority.max(entry.message.priority);
+                group.frames += 1;
+                group.last_seen = now;
+                return;
+            }
+        }
+        let group = Group {
+            head: entry,
+            frames: 0,
+            last_seen: now,
+        };
+        if let Some(previous) = self.groups.insert(pid, group) {
+            out.push(previous.head);
+        }
+    }
+
+    fn tick(&mut self, now: Instant, out: &mut Vec<PipelineEntry>) {
+        let expired: Vec<u32> = self
+            .groups
+            .iter()
+            .filter(|(_, group)| now.saturating_duration_since(group.last_seen) >= self.window)
+            .map(|(pid, _)| *pid)
+            .collect();
+        for pid in expired {
+            if let Some(group) = self.groups.remove(&pid) {
+                out.push(group.head);
+            }
+        }
+    }
+
+    fn flush(&mut self, out: &mut Vec<PipelineEntry>) {
+        out.extend(self.groups.drain().map(|(_, group)| group.head));
+    }
+}
@@ -1,61 +1,200 @@
-use crate::LogPacket;
+use crate::config::{Config, ConfigError};
 use crate::log::*;
+use crate::log_def::LogMessage;
 use crate::log_def::LogPriority;
-use crate::log_def::LogTimeStamp;
-use crate::msg_sink::MessageSink;
+use crate::log_def::TraceContext;
+use crate::msg_filter::{PipelineEntry, PipelineSet};
 use crate::msg_sink::SinkType;
+use crate::msg_sink::worker::{QueueConfig, SinkWorker};
+use crate::record_queue::RecordReceiver as Receiver;
+use crate::ring_buffer::RingBuffer;
+use crate::snapshot::SnapshotTrigger;
+use crate::stats;
+use crate::trigger::TriggerEngine;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
 use std::thread;
-use tokio::sync::mpsc::UnboundedReceiver as Receiver;
+use std::time::Instant;
 
+/// Everything travelling from the producers to the `OutputHandler`.
+pub enum LogRecord {
+    /// Validated message from a native client.
+    Client { sink_type: u8, message: LogMessage },
+    /// Already decoded record from an ingest source.
+    Ingested { sink_type: u8, message: LogMessage },
+    /// Periodic wake-up for pipeline stages holding messages back.
+    Tick,
+    /// Daemon is stopping: flush the pipelines and exit.
+    Shutdown,
+}
+
+#[allow(dead_code)]
 pub trait MessageProcessor<M, R, H> {
     fn run(sink_vec: Vec<M>, receiver: R) -> H;
 }
 
+/// Pipelines and sink queue settings of the `OutputHandler`.
+#[derive(Default)]
+pub struct OutputConfig {
+    pub pipelines: PipelineSet,
+    pub queues: HashMap<&'static str, QueueConfig>,
+    pub ring_buffer: Arc<RingBuffer>,
+    pub snapshots: Option<SnapshotTrigger>,
+    pub triggers: TriggerEngine,
+}
+
+impl OutputConfig {
+    pub fn from_config(config: &Config, sink_names: &[&'static str]) -> Result<Self, ConfigError> {
+        Ok(OutputConfig {
+            pipelines: PipelineSet::from_config(config, sink_names)?,
+            queues: QueueConfig::from_config(config, sink_names)?,
+            ring_buffer: Arc::new(RingBuffer::from_config(config)?),
+            snapshots: None,
+            triggers: TriggerEngine::from_config(config)?,
+        })
+    }
+}
+
 pub struct OutputHandler;
 
-impl MessageProcessor<SinkType, Receiver<LogPacket>, thread::JoinHandle<()>> for OutputHandler {
-    fn run(
-        mut sink_vec: Vec<SinkType>,
-        mut receiver: Receiver<LogPacket>,
+impl MessageProcessor<SinkType, Receiver, thread::JoinHandle<()>> for OutputHandler {
+    fn run(sink_vec: Vec<SinkType>, receiver: Receiver) -> thread::JoinHandle<()> {
+        OutputHandler::run_with_config(sink_vec, receiver, OutputConfig::default())
+    }
+}
+
+impl OutputHandler {
+    /// Runs the global pipeline on this thread and hands the result to one
+    /// `SinkWorker` per sink, which runs the sink's own pipeline.
+    pub fn run_with_config(
+        sink_vec: Vec<SinkType>,
+        mut receiver: Receiver,
+        config: OutputConfig,
     ) -> thread::JoinHandle<()> {
-        for sink in &mut sink_vec {
-            if let Err(e) = sink.init() {
-                loge!(LOG_TAG, "[OutputHandler] Sink init failed: {}", e);
-            }
-        }
+        let OutputConfig {
+            pipelines,
+            mut queues,
+            ring_buffer,
+            snapshots,
+            mut triggers,
+        } = config;
+        let mut global = pipelines.global;
+        let mut per_sink = pipelines.per_sink;
+        let workers: Vec<_> = sink_vec
+            .into_iter()
+            .map(|sink| {
+                let name = sink.name();
+                SinkWorker::spawn(
+                    sink,
+                    per_sink.remove(name),
+                    queues.remove(name).unwrap_or_default(),
+                )
+            })
+            .collect();
         thread::spawn(move || {
-            while let Some(data) = receiver.blocking_recv() {
-                let sink_type = data.sink_type;
-                // iterate over sink_vec and send the message to each sink
-                for sink in &mut sink_vec {
-                    if sink_type & (*sink.get_ordinal() as u8) == 0 {
+            while let Some(record) = receiver.blocking_recv() {
+                stats::global()
+                    .channel_depth
+                    .store(receiver.len() as u64, Ordering::Relaxed);
+                let entry = match record {
+                    LogRecord::Tick => {
+                        let now = Instant::now();
+                        for entry in global.tick(now) {
+                            dispatch(
+                                &workers,
+                                &ring_buffer,
+                                &mut triggers,
+                                snapshots.as_ref(),
+                                entry,
+                            );
+                        }
+                        triggers.tick(now);
+                        for worker in &workers {
+                            worker.tick(now);
+                        }
                         continue;
                     }
-                    let client_priority = match data.priority {
-                        0 => LogPriority::Verbose,
-                        1 => LogPriority::Debug,
-                        2 => LogPriority::Info,
-                        3 => LogPriority::Warn,
-                        4 => LogPriority::Error,
-                        _ => LogPriority::Verbose,
-                    };
-                    sink.send_message(crate::log_def::LogMessage {
-                        pid: data.pid,
-                        priority: client_priority,
-                        message: String::from_utf8_lossy(&data.message).to_string(),
-                        timestamp: LogTimeStamp {
-                            year: u16::from_be_bytes([data.timestamp[0], data.timestamp[1]]),
-                            month: data.timestamp[2],
-                            day: data.timestamp[3],
-                            hour: data.timestamp[4],
-                            minute: data.timestamp[5],
-                            second: data.timestamp[6],
-                            millisecond: u16::from_be_bytes([data.timestamp[7], data.timestamp[8]]),
-                        },
-                    });
+                    LogRecord::Shutdown => break,
+                    // the ProtocolHandler requested a snapshot for a Fatal
+                    // client message already
+                    LogRecord::Client { sink_type, message } => pipeline_entry(sink_type, message),
+                    LogRecord::Ingested { sink_type, message } => {
+                        if let Some(snapshots) = snapshots
+                            .as_ref()
+                            .filter(|_| message.priority == LogPriority::Fatal)
+                        {
+                            snapshots.request(
+                                format!("Fatal message from pid {}", message.pid),
+                                Some(message.pid),
+                            );
+                        }
+                        pipeline_entry(sink_type, message)
+                    }
+                };
+                for entry in global.process(entry) {
+                    dispatch(
+                        &workers,
+                        &ring_buffer,
+                        &mut triggers,
+                        snapshots.as_ref(),
+                        entry,
+                    );
                 }
             }
-            logd!(LOG_TAG, "[OutputHandler] Channel closed, exiting.");
+            for entry in global.flush() {
+                dispatch(
+                    &workers,
+                    &ring_buffer,
+                    &mut triggers,
+                    snapshots.as_ref(),
+                    entry,
+                );
+            }
+            for worker in workers {
+                worker.stop();
+            }
+            logd!(LOG_TAG, "[OutputHandler] Pipelines flushed, exiting.");
         })
     }
 }
+
+fn pipeline_entry(sink_type: u8, mut message: LogMessage) -> PipelineEntry {
+    // clients without protocol trace fields may still log a `traceparent=` token
+    if message.trace.is_none() {
+        message.trace = TraceContext::find_in(&message.message);
+    }
+    PipelineEntry {
+        sink_type,
+        message,
+        unredacted: false,
+    }
+}
+
+/// Queues an entry, shared rather than copied, on every sink it is routed to
+/// and keeps it in the ring buffer whatever its sinks. Triggers see it first,
+/// so a raised priority applies to this message. Fatal messages requested
+/// their crash snapshot before the pipeline, which may drop them. An
+/// `unredacted` copy only goes to its sinks: the redacted copy of the same
+/// message is the one stored and evaluated.
+fn dispatch(
+    workers: &[SinkWorker],
+    ring_buffer: &RingBuffer,
+    triggers: &mut TriggerEngine,
+    snapshots: Option<&SnapshotTrigger>,
+    mut entry: PipelineEntry,
+) {
+    if entry.unredacted {
+        let message = Arc::new(entry.message);
+        for worker in workers.iter().filter(|w| w.accepts(entry.sink_type)) {
+            worker.push(message.clone());
+        }
+        return;
+    }
+    triggers.evaluate(&mut entry.message, snapshots);
+    let message = Arc::new(entry.message);
+    ring_buffer.push(message.clone());
+    for worker in workers.iter().filter(|w| w.accepts(entry.sink_type)) {
+        worker.push(message.clone());
+    }
+}
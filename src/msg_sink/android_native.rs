@@ -49,6 +49,7 @@ pub fn log_android_native(prio: AndroidLogPriority, tag: &str, msg: &str) {
 use crate::LogPriority;
 use crate::msg_sink::LogMessage;
 use crate::msg_sink::MessageSink;
+use crate::msg_sink::local_file;
 
 pub struct AndroidLog;
 
@@ -63,18 +64,37 @@ pub fn convert_priority(priority: LogPriority) -> AndroidLogPriority {
     }
 }
 
+/// Inverse of `convert_priority`, for records read back from logd. Silent
+/// records are not meant to be shown and map to `None`.
+pub fn convert_android_priority(priority: u8) -> Option<LogPriority> {
+    match priority {
+        p if p == AndroidLogPriority::Debug as u8 => Some(LogPriority::Debug),
+        p if p == AndroidLogPriority::Info as u8 => Some(LogPriority::Info),
+        p if p == AndroidLogPriority::Warn as u8 => Some(LogPriority::Warn),
+        p if p == AndroidLogPriority::Error as u8 => Some(LogPriority::Error),
+        p if p == AndroidLogPriority::Fatal as u8 => Some(LogPriority::Fatal),
+        p if p >= AndroidLogPriority::Silent as u8 => None,
+        _ => Some(LogPriority::Verbose),
+    }
+}
+
 impl MessageSink for AndroidLog {
     fn init(&mut self) -> Result<(), String> {
         Ok(())
     }
 
-    fn send_message(&mut self, message: LogMessage) {
+    fn send_message(&mut self, message: &LogMessage) -> Result<usize, String> {
         let android_priority = convert_priority(message.priority);
-        log_android_native(
-            android_priority,
-            format!("PID: {}", &message.pid).as_str(),
-            &message.message,
-        );
+        let tag = match &message.tag {
+            Some(tag) => tag.clone(),
+            None => format!("PID: {}", &message.pid),
+        };
+        let text = match &message.trace {
+            Some(trace) => format!("{}{}", local_file::trace_marker(trace), message.message),
+            None => message.message.clone(),
+        };
+        log_android_native(android_priority, tag.as_str(), &text);
+        Ok(text.len())
     }
 
     fn close(&mut self) -> Result<(), String> {
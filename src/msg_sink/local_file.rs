@@ -1,18 +1,60 @@
 use std::fs::{File, OpenOptions};
-use std::io::Write;
+use std::io::{BufRead, BufReader, ErrorKind, Write};
 use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::Ordering;
 
+use crate::config::{Config, ConfigError, Section};
 use crate::log::*;
-use crate::log_def::LogPriority;
+use crate::log_def::{LogPriority, TraceContext};
 use crate::msg_sink::LogMessage;
 use crate::msg_sink::MessageSink;
+use crate::stats;
 
 pub struct LocalFileSink {
     pub log_file: Option<File>,
     local_file_sm: RotatingFileSink,
+    multiline: MultiLineMode,
 }
 
-static LOG_DIR: &str = "/data/misc/notcat";
+/// How a message with embedded newlines is written, so every line of the
+/// file stays a complete record.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MultiLineMode {
+    /// One line, with `\n`, `\r` and `\\` escaped.
+    Escape,
+    /// One line per message line, each with the same header.
+    #[default]
+    Continuation,
+}
+
+impl MultiLineMode {
+    /// The `multiline` key of `[sink local_file]`. Dumps, crash snapshots and
+    /// alerts follow it too, so they read like `notcat.log`.
+    pub fn from_config(config: &Config) -> Result<Self, ConfigError> {
+        let section = config
+            .sections("sink")
+            .find(|s| s.name.as_deref() == Some("local_file"));
+        match section.and_then(|s| s.get("multiline").map(|mode| (s, mode))) {
+            Some((section, mode)) => mode.parse().map_err(|_| section.invalid("multiline", mode)),
+            None => Ok(MultiLineMode::default()),
+        }
+    }
+}
+
+impl FromStr for MultiLineMode {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "escape" => Ok(MultiLineMode::Escape),
+            "continuation" => Ok(MultiLineMode::Continuation),
+            _ => Err(()),
+        }
+    }
+}
+
+pub static LOG_DIR: &str = "/data/misc/notcat";
 static LOG_FILE: &str = "notcat.log";
 static MAX_LOG_FILES_SIZE: u64 = 100 * 1024 * 1024; // 100 MB
 static MAX_LOG_FILES_COUNT: u32 = 5;
@@ -23,7 +65,18 @@ impl LocalFileSink {
         LocalFileSink {
             log_file: None,
             local_file_sm: RotatingFileSink::new(),
+            multiline: MultiLineMode::default(),
+        }
+    }
+
+    /// Reads `multiline` from the `[sink local_file]` section.
+    pub fn configure(&mut self, section: &Section) -> Result<(), ConfigError> {
+        if let Some(mode) = section.get("multiline") {
+            self.multiline = mode
+                .parse()
+                .map_err(|_| section.invalid("multiline", mode))?;
         }
+        Ok(())
     }
 }
 
@@ -33,30 +86,15 @@ impl MessageSink for LocalFileSink {
         Ok(())
     }
 
-    fn send_message(&mut self, message: LogMessage) {
-        let priority_str = match message.priority {
-            LogPriority::Verbose => "V",
-            LogPriority::Debug => "D",
-            LogPriority::Info => "I",
-            LogPriority::Warn => "W",
-            LogPriority::Error => "E",
-            _ => "U", // Unknown
-        };
-        let msg = format!(
-            "[{}] {} {}-{}-{} {}:{}:{}-{} {}",
-            message.pid,
-            priority_str,
-            message.timestamp.year,
-            message.timestamp.month,
-            message.timestamp.day,
-            message.timestamp.hour,
-            message.timestamp.minute,
-            message.timestamp.second,
-            message.timestamp.millisecond,
-            message.message
-        );
+    fn send_message(&mut self, message: &LogMessage) -> Result<usize, String> {
+        let msg = format_record(message, self.multiline);
+        let written = msg.len() + 1;
         self.local_file_sm
             .handle_event(LoggingEvent::SendMessage(msg));
+        match self.local_file_sm.state {
+            LoggingState::Running => Ok(written),
+            state => Err(format!("Rotating file sink is in {:?} state", state)),
+        }
     }
 
     fn close(&mut self) -> Result<(), String> {
@@ -65,6 +103,140 @@ impl MessageSink for LocalFileSink {
     }
 }
 
+/// One line of `notcat.log`, also used when dumping the in-memory buffer.
+pub fn format_line(message: &LogMessage) -> String {
+    format!("{}{}", line_header(message), message.message)
+}
+
+/// ` {key=value ...}` for a message with structured fields, empty otherwise.
+/// Values with spaces, commas or quotes are quoted.
+pub fn format_fields(message: &LogMessage) -> String {
+    if message.fields.is_empty() {
+        return String::new();
+    }
+    let pairs: Vec<String> = message
+        .fields
+        .iter()
+        .map(|(key, value)| {
+            if value.is_empty() || value.contains([' ', '\t', ',', '"', '}']) {
+                format!("{}={:?}", key, value)
+            } else {
+                format!("{}={}", key, value)
+            }
+        })
+        .collect();
+    format!(" {{{}}}", pairs.join(" "))
+}
+
+/// The text of `message` as written to `notcat.log`: `format_line`, unless the
+/// message spans several lines.
+pub fn format_record(message: &LogMessage, mode: MultiLineMode) -> String {
+    if !message.message.contains(['\n', '\r']) {
+        return format_line(message);
+    }
+    let header = line_header(message);
+    match mode {
+        MultiLineMode::Escape => {
+            let mut line = header;
+            for c in message.message.chars() {
+                match c {
+                    '\n' => line.push_str("\\n"),
+                    '\r' => line.push_str("\\r"),
+                    '\\' => line.push_str("\\\\"),
+                    c => line.push(c),
+                }
+            }
+            line
+        }
+        // `\r\n`, `\n` and a lone `\r` all end a line
+        MultiLineMode::Continuation => message
+            .message
+            .replace("\r\n", "\n")
+            .split_terminator(['\n', '\r'])
+            .map(|text| format!("{}{}", header, text))
+            .collect::<Vec<_>>()
+            .join("\n"),
+    }
+}
+
+fn line_header(message: &LogMessage) -> String {
+    let priority_str = match message.priority {
+        LogPriority::Verbose => "V",
+        LogPriority::Debug => "D",
+        LogPriority::Info => "I",
+        LogPriority::Warn => "W",
+        LogPriority::Error => "E",
+        LogPriority::Fatal => "F",
+    };
+    let tag_prefix = match &message.tag {
+        Some(tag) => format!("{}: ", tag),
+        None => String::new(),
+    };
+    let trace_prefix = match &message.trace {
+        Some(trace) => trace_marker(trace),
+        None => String::new(),
+    };
+    format!(
+        "[{}] {} {}-{}-{} {}:{}:{}-{} {}{}",
+        message.pid,
+        priority_str,
+        message.timestamp.year,
+        message.timestamp.month,
+        message.timestamp.day,
+        message.timestamp.hour,
+        message.timestamp.minute,
+        message.timestamp.second,
+        message.timestamp.millisecond,
+        trace_prefix,
+        tag_prefix
+    )
+}
+
+/// `trace=<trace id> span=<span id> `, as written in front of the tag.
+pub fn trace_marker(trace: &TraceContext) -> String {
+    format!(
+        "trace={} span={} ",
+        trace.trace_id_hex(),
+        trace.span_id_hex()
+    )
+}
+
+/// Writes every line of the log files, oldest file first, that belongs to the
+/// trace `trace_id`, given as 32 hex digits or as a whole `traceparent`, to
+/// `out` as soon as it is found. Stops after `limit` lines; returns how many
+/// were written.
+pub fn query_trace(trace_id: &str, limit: usize, out: &mut impl Write) -> Result<usize, String> {
+    let trace_id = match TraceContext::parse_traceparent(trace_id) {
+        Some(trace) => trace.trace_id_hex(),
+        None if trace_id.len() == 32 && trace_id.chars().all(|c| c.is_ascii_hexdigit()) => {
+            trace_id.to_ascii_lowercase()
+        }
+        None => return Err(format!("Invalid trace id: {}", trace_id)),
+    };
+    let needle = format!(" trace={} ", trace_id);
+    let mut found = 0;
+    for i in 0..MAX_LOG_FILES_COUNT {
+        let file_path = PathBuf::from(format!("{}/{}.{}", LOG_DIR, LOG_FILE, i));
+        let file = match File::open(&file_path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => continue,
+            Err(e) => return Err(format!("Failed to read {}: {}", file_path.display(), e)),
+        };
+        for line in BufReader::new(file).lines() {
+            let line =
+                line.map_err(|e| format!("Failed to read {}: {}", file_path.display(), e))?;
+            if line.contains(&needle) {
+                writeln!(out, "{}", line).map_err(|e| format!("Failed to send results: {}", e))?;
+                found += 1;
+                if found == limit {
+                    return Ok(found);
+                }
+            }
+        }
+    }
+    Ok(found)
+}
+
 struct CurrentSinkFileData {
     pub number: u32,
     pub file: Option<File>,
@@ -153,6 +325,7 @@ impl RotatingFileSink {
                 let mut return_state = LoggingState::Running;
                 if self.current_file_data.current_size + 1 + msg.len() as u64 >= MAX_LOG_FILE_SIZE {
                     logv!(LOG_TAG, "Rotating log file due to size limit.");
+                    stats::global().rotations.fetch_add(1, Ordering::Relaxed);
                     if self.current_file_data.number == MAX_LOG_FILES_COUNT - 1 {
                         if let Err(e) = std::fs::remove_file(PathBuf::from(format!(
                             "{}/{}.0",
@@ -243,3 +416,51 @@ impl RotatingFileSink {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::log_def::LogTimeStamp;
+
+    fn message(text: &str) -> LogMessage {
+        LogMessage {
+            pid: 7,
+            uid: None,
+            priority: LogPriority::Info,
+            timestamp: LogTimeStamp {
+                year: 2024,
+                month: 3,
+                day: 9,
+                hour: 14,
+                minute: 5,
+                second: 7,
+                millisecond: 5,
+            },
+            tag: Some(String::from("netd")),
+            message: String::from(text),
+            fields: Vec::new(),
+            trace: None,
+        }
+    }
+
+    #[test]
+    fn continuation_splits_on_every_line_ending() {
+        let record = format_record(&message("a\r\nb\nc\rd\n"), MultiLineMode::Continuation);
+        assert_eq!(
+            record,
+            "[7] I 2024-3-9 14:5:7-5 netd: a\n\
+             [7] I 2024-3-9 14:5:7-5 netd: b\n\
+             [7] I 2024-3-9 14:5:7-5 netd: c\n\
+             [7] I 2024-3-9 14:5:7-5 netd: d"
+        );
+        assert!(!record.contains('\r'));
+    }
+
+    #[test]
+    fn escape_keeps_one_line() {
+        assert_eq!(
+            format_record(&message("a\rb\\c\n"), MultiLineMode::Escape),
+            "[7] I 2024-3-9 14:5:7-5 netd: a\\rb\\\\c\\n"
+        );
+    }
+}
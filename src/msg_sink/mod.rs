@@ -1,10 +1,13 @@
 pub mod android_native;
 pub mod local_file;
+pub mod worker;
+use crate::config::{Config, ConfigError, Section};
 use crate::log_def::*;
 
 pub trait MessageSink {
     fn init(&mut self) -> Result<(), String>;
-    fn send_message(&mut self, message: LogMessage);
+    /// Returns the number of bytes written, or a description of the failure.
+    fn send_message(&mut self, message: &LogMessage) -> Result<usize, String>;
     fn close(&mut self) -> Result<(), String>;
 }
 
@@ -15,6 +18,37 @@ pub enum SinkTypeOrdinal {
     AndroidNativeType = 2,
 }
 
+impl SinkTypeOrdinal {
+    pub fn name(&self) -> &'static str {
+        match self {
+            SinkTypeOrdinal::LocalFileType => "local_file",
+            SinkTypeOrdinal::AndroidNativeType => "android_native",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "local_file" => Some(SinkTypeOrdinal::LocalFileType),
+            "android_native" => Some(SinkTypeOrdinal::AndroidNativeType),
+            _ => None,
+        }
+    }
+
+    /// Parses the comma separated `sinks` key of a section into a sink type mask.
+    pub fn mask_from_section(section: &Section, default: u8) -> Result<u8, ConfigError> {
+        if section.get("sinks").is_none() {
+            return Ok(default);
+        }
+        let mut mask = 0;
+        for sink in section.get_list("sinks") {
+            let ordinal =
+                SinkTypeOrdinal::from_name(sink).ok_or_else(|| section.invalid("sinks", sink))?;
+            mask |= ordinal as u8;
+        }
+        Ok(mask)
+    }
+}
+
 pub enum SinkType {
     LocalFile {
         implem: local_file::LocalFileSink,
@@ -36,7 +70,7 @@ impl MessageSink for SinkType {
         }
     }
 
-    fn send_message(&mut self, message: LogMessage) {
+    fn send_message(&mut self, message: &LogMessage) -> Result<usize, String> {
         match self {
             SinkType::LocalFile { implem, .. } => implem.send_message(message),
             SinkType::AndroidNative { implem, .. } => implem.send_message(message),
@@ -63,6 +97,23 @@ impl SinkType {
             }),
         }
     }
+    /// Applies the sink's `[sink <name>]` section, if any.
+    pub fn configure(&mut self, config: &Config) -> Result<(), ConfigError> {
+        let name = self.name();
+        let Some(section) = config
+            .sections("sink")
+            .find(|s| s.name.as_deref() == Some(name))
+        else {
+            return Ok(());
+        };
+        match self {
+            SinkType::LocalFile { implem, .. } => implem.configure(section),
+            SinkType::AndroidNative { .. } => Ok(()),
+        }
+    }
+    pub fn name(&self) -> &'static str {
+        self.get_ordinal().name()
+    }
     pub fn get_ordinal(&self) -> &SinkTypeOrdinal {
         match self {
             SinkType::LocalFile { ordinal, .. } => ordinal,
@@ -0,0 +1,316 @@
+use crate::config::{Config, ConfigError, Section};
+use crate::log::*;
+use crate::log_def::*;
+use crate::msg_filter::{Pipeline, PipelineEntry};
+use crate::msg_sink::{MessageSink, SinkType};
+use crate::stats::{self, SinkCounters};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Instant;
+
+static DEFAULT_QUEUE_SIZE: usize = 1024;
+
+/// What to do with a message when the queue of a sink is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    DropNewest,
+    DropOldest,
+    /// Wait for the sink, stalling every other sink with it.
+    Block,
+}
+
+#[derive(Debug, Clone)]
+pub struct QueueConfig {
+    pub size: usize,
+    pub overflow: OverflowPolicy,
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        QueueConfig {
+            size: DEFAULT_QUEUE_SIZE,
+            overflow: OverflowPolicy::DropNewest,
+        }
+    }
+}
+
+impl QueueConfig {
+    /// Reads `queue_size` and `overflow` from a `[sink <name>]` section.
+    pub fn from_section(section: &Section) -> Result<Self, ConfigError> {
+        let mut queue = QueueConfig::default();
+        if let Some(size) = section.get_parsed::<usize>("queue_size")? {
+            if size == 0 {
+                return Err(section.invalid("queue_size", "0"));
+            }
+            queue.size = size;
+        }
+        queue.overflow = match section.get("overflow") {
+            None | Some("drop_newest") => OverflowPolicy::DropNewest,
+            Some("drop_oldest") => OverflowPolicy::DropOldest,
+            Some("block") => OverflowPolicy::Block,
+            Some(other) => return Err(section.invalid("overflow", other)),
+        };
+        Ok(queue)
+    }
+
+    /// Queue settings of every `[sink <name>]` section naming one of `sink_names`.
+    pub fn from_config(
+        config: &Config,
+        sink_names: &[&'static str],
+    ) -> Result<HashMap<&'static str, Self>, ConfigError> {
+        let mut queues = HashMap::new();
+        for section in config.sections("sink") {
+            let sink = section.name.as_deref().unwrap_or_default();
+            let name = sink_names
+                .iter()
+                .find(|n| **n == sink)
+                .ok_or_else(|| section.invalid("sink", sink))?;
+            queues.insert(*name, QueueConfig::from_section(section)?);
+        }
+        Ok(queues)
+    }
+}
+
+enum SinkItem {
+    Message(Arc<LogMessage>),
+    Tick(Instant),
+}
+
+struct QueueState {
+    items: VecDeque<SinkItem>,
+    closed: bool,
+}
+
+/// Bounded queue between the output handler and one sink worker.
+struct SinkQueue {
+    config: QueueConfig,
+    state: Mutex<QueueState>,
+    not_empty: Condvar,
+    not_full: Condvar,
+    counters: Arc<SinkCounters>,
+}
+
+impl SinkQueue {
+    fn push(&self, item: SinkItem) {
+        let mut state = self.state.lock().unwrap();
+        while state.items.len() >= self.config.size {
+            match self.config.overflow {
+                OverflowPolicy::DropNewest => {
+                    self.counters.add_overflow();
+                    return;
+                }
+                OverflowPolicy::DropOldest => {
+                    state.items.pop_front();
+                    self.counters.add_overflow();
+                }
+                OverflowPolicy::Block => state = self.not_full.wait(state).unwrap(),
+            }
+        }
+        state.items.push_back(item);
+        self.counters.set_queue_depth(state.items.len());
+        self.not_empty.notify_one();
+    }
+
+    /// Blocks until an item is available; `None` once closed and drained.
+    fn pop(&self) -> Option<SinkItem> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if let Some(item) = state.items.pop_front() {
+                self.counters.set_queue_depth(state.items.len());
+                self.not_full.notify_one();
+                return Some(item);
+            }
+            if state.closed {
+                return None;
+            }
+            state = self.not_empty.wait(state).unwrap();
+        }
+    }
+
+    fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.not_empty.notify_all();
+    }
+}
+
+/// Owns one sink and its pipeline on a dedicated thread, so a sink stuck on
+/// slow storage only delays itself.
+pub struct SinkWorker {
+    name: &'static str,
+    ordinal: u8,
+    has_pipeline: bool,
+    queue: Arc<SinkQueue>,
+    handle: JoinHandle<()>,
+}
+
+impl SinkWorker {
+    pub fn spawn(sink: SinkType, pipeline: Option<Pipeline>, config: QueueConfig) -> Self {
+        let name = sink.name();
+        let ordinal = *sink.get_ordinal() as u8;
+        SinkWorker::spawn_sink(name, ordinal, sink, pipeline, config)
+    }
+
+    fn spawn_sink<S: MessageSink + Send + 'static>(
+        name: &'static str,
+        ordinal: u8,
+        mut sink: S,
+        mut pipeline: Option<Pipeline>,
+        config: QueueConfig,
+    ) -> Self {
+        let counters = stats::global().register_sink(name);
+        let queue = Arc::new(SinkQueue {
+            config,
+            state: Mutex::new(QueueState {
+                items: VecDeque::new(),
+                closed: false,
+            }),
+            not_empty: Condvar::new(),
+            not_full: Condvar::new(),
+            counters: counters.clone(),
+        });
+        let has_pipeline = pipeline.is_some();
+        let worker_queue = queue.clone();
+        let handle = thread::spawn(move || {
+            if let Err(e) = sink.init() {
+                loge!(LOG_TAG, "[SinkWorker] Sink init failed: {}", e);
+            }
+            let send = |sink: &mut S, message: &LogMessage| {
+                counters.set_busy(true);
+                let started = Instant::now();
+                let result = sink.send_message(message);
+                counters.record_latency(started.elapsed());
+                counters.set_busy(false);
+                counters.record(&result);
+            };
+            // the sink's own pipeline may route or redact entries elsewhere
+            let deliver = |sink: &mut S, entries: Vec<PipelineEntry>| {
+                for entry in entries.iter().filter(|e| e.sink_type & ordinal != 0) {
+                    send(sink, &entry.message);
+                }
+            };
+            while let Some(item) = worker_queue.pop() {
+                match (item, pipeline.as_mut()) {
+                    (SinkItem::Message(message), None) => send(&mut sink, &message),
+                    (SinkItem::Message(message), Some(pipeline)) => {
+                        let entry = PipelineEntry {
+                            sink_type: ordinal,
+                            message: Arc::unwrap_or_clone(message),
+                            unredacted: false,
+                        };
+                        deliver(&mut sink, pipeline.process(entry));
+                    }
+                    (SinkItem::Tick(now), Some(pipeline)) => {
+                        deliver(&mut sink, pipeline.tick(now));
+                    }
+                    (SinkItem::Tick(_), None) => (),
+                }
+            }
+            if let Some(pipeline) = pipeline.as_mut() {
+                deliver(&mut sink, pipeline.flush());
+            }
+            if let Err(e) = sink.close() {
+                loge!(LOG_TAG, "[SinkWorker] Sink close failed: {}", e);
+            }
+            counters.set_stopped();
+            logd!(LOG_TAG, "[SinkWorker] {} stopped.", name);
+        });
+        SinkWorker {
+            name,
+            ordinal,
+            has_pipeline,
+            queue,
+            handle,
+        }
+    }
+
+    pub fn accepts(&self, sink_type: u8) -> bool {
+        sink_type & self.ordinal != 0
+    }
+
+    pub fn push(&self, message: Arc<LogMessage>) {
+        self.queue.push(SinkItem::Message(message));
+    }
+
+    /// Forwards the periodic tick to the sink's pipeline, if it has one.
+    pub fn tick(&self, now: Instant) {
+        if self.has_pipeline {
+            self.queue.push(SinkItem::Tick(now));
+        }
+    }
+
+    /// Lets the worker drain its queue and flush its pipeline, then waits for it.
+    pub fn stop(self) {
+        self.queue.close();
+        if self.handle.join().is_err() {
+            loge!(LOG_TAG, "[SinkWorker] {} panicked.", self.name);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::msg_filter::PipelineSet;
+    use crate::msg_sink::SinkTypeOrdinal;
+
+    /// Keeps the text of every message it is sent.
+    struct RecordingSink(Arc<Mutex<Vec<String>>>);
+
+    impl MessageSink for RecordingSink {
+        fn init(&mut self) -> Result<(), String> {
+            Ok(())
+        }
+
+        fn send_message(&mut self, message: &LogMessage) -> Result<usize, String> {
+            self.0.lock().unwrap().push(message.message.clone());
+            Ok(message.message.len())
+        }
+
+        fn close(&mut self) -> Result<(), String> {
+            Ok(())
+        }
+    }
+
+    /// Runs `messages` through a local_file worker whose pipeline is one
+    /// stage with the given options.
+    fn deliver(stage: &str, messages: &[&str]) -> Vec<String> {
+        let config = Config::parse(&format!(
+            "[stage s]\n{}[pipeline local_file]\nstages = s\n",
+            stage
+        ))
+        .unwrap();
+        let mut pipelines = PipelineSet::from_config(&config, &["local_file"]).unwrap();
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let worker = SinkWorker::spawn_sink(
+            "local_file",
+            SinkTypeOrdinal::LocalFileType as u8,
+            RecordingSink(sent.clone()),
+            pipelines.per_sink.remove("local_file"),
+            QueueConfig::default(),
+        );
+        for text in messages {
+            worker.push(Arc::new(LogMessage::daemon(text.to_string())));
+        }
+        worker.stop();
+        Arc::try_unwrap(sent).unwrap().into_inner().unwrap()
+    }
+
+    #[test]
+    fn routed_away_entries_are_not_sent() {
+        let sent = deliver(
+            "type = regex\npattern = ^radio\naction = route\nsinks = android_native\n",
+            &["radio up", "wifi up"],
+        );
+        assert_eq!(sent, ["wifi up"]);
+    }
+
+    #[test]
+    fn exempt_sink_gets_only_the_unredacted_copy() {
+        let sent = deliver(
+            "type = redact\nexempt_sinks = local_file\n",
+            &["mail alice@example.com"],
+        );
+        assert_eq!(sent, ["mail alice@example.com"]);
+    }
+}
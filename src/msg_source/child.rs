@@ -0,0 +1,407 @@
+use crate::config::{Config, ConfigError, Section};
+use crate::log::*;
+use crate::log_def::*;
+use crate::msg_proc::LogRecord;
+use crate::msg_sink::SinkTypeOrdinal;
+use crate::msg_source::{MessageSource, SourceType, spawn_source};
+use crate::record_queue::RecordSender as Sender;
+use nix::sys::signal::{Signal, kill};
+use nix::unistd::Pid;
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::io::{self, BufRead, BufReader, Read};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RestartPolicy {
+    Always,
+    OnFailure,
+    Never,
+}
+
+impl RestartPolicy {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "always" => Some(RestartPolicy::Always),
+            "on-failure" => Some(RestartPolicy::OnFailure),
+            "never" => Some(RestartPolicy::Never),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ChildSpec {
+    pub name: String,
+    pub command: Vec<String>,
+    pub restart: RestartPolicy,
+    pub max_restarts: Option<u32>,
+    pub sink_type: u8,
+}
+
+impl ChildSpec {
+    /// Builds a spec from a `[child <name>]` section. The command is split on
+    /// whitespace, quoting is not supported.
+    pub fn from_section(section: &Section) -> Result<Self, ConfigError> {
+        let name = section
+            .name
+            .as_deref()
+            .ok_or_else(|| section.invalid("name", ""))?;
+        let command: Vec<String> = section
+            .get("command")
+            .unwrap_or_default()
+            .split_whitespace()
+            .map(str::to_string)
+            .collect();
+        if command.is_empty() {
+            return Err(section.invalid("command", ""));
+        }
+        let restart = match section.get("restart") {
+            Some(policy) => RestartPolicy::from_name(policy)
+                .ok_or_else(|| section.invalid("restart", policy))?,
+            None => RestartPolicy::OnFailure,
+        };
+        Ok(ChildSpec {
+            name: name.to_string(),
+            command,
+            restart,
+            max_restarts: section.get_parsed("max_restarts")?,
+            sink_type: SinkTypeOrdinal::mask_from_section(section, u8::MAX)?,
+        })
+    }
+}
+
+/// State shared between a supervised child's thread and the control socket.
+#[derive(Default)]
+struct ChildControl {
+    pid: AtomicU32,
+    restarts: AtomicU32,
+    stop: AtomicBool,
+    finished: AtomicBool,
+}
+
+/// Runs one command at a time and turns its stdout/stderr lines into records.
+pub struct ChildSource {
+    spec: ChildSpec,
+    control: Arc<ChildControl>,
+    finished: bool,
+}
+
+impl ChildSource {
+    fn record(&self, pid: u32, priority: LogPriority, message: String) -> LogRecord {
+        LogRecord::Ingested {
+            sink_type: self.spec.sink_type,
+            message: LogMessage {
+                pid,
+                uid: None,
+                priority,
+                timestamp: LogTimeStamp::now(),
+                tag: Some(self.spec.name.clone()),
+                message,
+                fields: Vec::new(),
+                trace: None,
+            },
+        }
+    }
+
+    /// Counts a run, or a failed start, against the restart policy and
+    /// decides whether another one follows.
+    fn end_attempt(&mut self, success: bool) {
+        let restarts = self.control.restarts.fetch_add(1, Ordering::Relaxed) + 1;
+        self.finished = self.control.stop.load(Ordering::Relaxed)
+            || match self.spec.restart {
+                RestartPolicy::Always => false,
+                RestartPolicy::OnFailure => success,
+                RestartPolicy::Never => true,
+            }
+            || self.spec.max_restarts.is_some_and(|max| restarts > max);
+        self.control
+            .finished
+            .store(self.finished, Ordering::Relaxed);
+    }
+
+    /// Forwards each line of `input`. Returns false once the channel is closed.
+    fn capture<R: Read>(&self, input: R, pid: u32, priority: LogPriority, sender: &Sender) -> bool {
+        let mut reader = BufReader::new(input);
+        let mut line = Vec::new();
+        loop {
+            line.clear();
+            match reader.read_until(b'\n', &mut line) {
+                Ok(0) => return true,
+                Ok(_) => {
+                    let text = String::from_utf8_lossy(&line);
+                    let text = text.trim_end_matches(['\n', '\r']).to_string();
+                    if sender.send(self.record(pid, priority, text)).is_err() {
+                        return false;
+                    }
+                }
+                Err(e) => {
+                    logw!(
+                        LOG_TAG,
+                        "[ChildSource] Error reading output of {}: {}",
+                        self.spec.name,
+                        e
+                    );
+                    return true;
+                }
+            }
+        }
+    }
+}
+
+impl MessageSource for ChildSource {
+    fn name(&self) -> &'static str {
+        "child"
+    }
+
+    fn read_loop(&mut self, sender: &Sender) -> io::Result<()> {
+        if self.control.stop.load(Ordering::Relaxed) {
+            self.finished = true;
+            self.control.finished.store(true, Ordering::Relaxed);
+            return Ok(());
+        }
+        let child = Command::new(&self.spec.command[0])
+            .args(&self.spec.command[1..])
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn();
+        let mut child = match child {
+            Ok(child) => child,
+            Err(e) => {
+                loge!(
+                    LOG_TAG,
+                    "[ChildSource] Failed to start {}: {}",
+                    self.spec.name,
+                    e
+                );
+                let _ = sender.send(self.record(
+                    0,
+                    LogPriority::Error,
+                    format!("failed to start: {}", e),
+                ));
+                self.end_attempt(false);
+                return Err(e);
+            }
+        };
+        let pid = child.id();
+        self.control.pid.store(pid, Ordering::Relaxed);
+        logi!(
+            LOG_TAG,
+            "[ChildSource] Started {} (pid {})",
+            self.spec.name,
+            pid
+        );
+
+        let stdout = child.stdout.take().unwrap();
+        let stderr = child.stderr.take().unwrap();
+        let open = thread::scope(|scope| {
+            let stderr_reader =
+                scope.spawn(|| self.capture(stderr, pid, LogPriority::Error, sender));
+            let stdout_open = self.capture(stdout, pid, LogPriority::Info, sender);
+            stderr_reader.join().unwrap_or(false) && stdout_open
+        });
+        if !open {
+            let _ = child.kill();
+        }
+        let status = child.wait()?;
+        self.control.pid.store(0, Ordering::Relaxed);
+
+        let priority = if status.success() {
+            LogPriority::Info
+        } else {
+            LogPriority::Warn
+        };
+        let _ = sender.send(self.record(pid, priority, format!("process exited: {}", status)));
+
+        self.end_attempt(status.success());
+        Ok(())
+    }
+
+    fn is_finished(&self) -> bool {
+        self.finished
+    }
+}
+
+/// Supervised children by name.
+type ChildTable = HashMap<String, (ChildSpec, Arc<ChildControl>)>;
+
+/// Starts, lists and stops supervised children, from config and from the
+/// control socket.
+#[derive(Clone)]
+pub struct Supervisor {
+    children: Arc<Mutex<ChildTable>>,
+    sender: Sender,
+    allow_control_spawn: bool,
+}
+
+impl Supervisor {
+    pub fn new(sender: Sender, allow_control_spawn: bool) -> Self {
+        Supervisor {
+            children: Arc::new(Mutex::new(HashMap::new())),
+            sender,
+            allow_control_spawn,
+        }
+    }
+
+    /// Reads `allow_control_spawn` from the `[supervisor]` section.
+    pub fn from_config(sender: Sender, config: &Config) -> Result<Self, ConfigError> {
+        let allow_control_spawn = match config.section("supervisor") {
+            Some(section) => section.get_parsed("allow_control_spawn")?.unwrap_or(false),
+            None => false,
+        };
+        Ok(Supervisor::new(sender, allow_control_spawn))
+    }
+
+    /// Starts every child declared with a `[child <name>]` section.
+    pub fn start_configured(&self, config: &Config) -> Result<(), ConfigError> {
+        for section in config.sections("child") {
+            if section.get("enabled") == Some("false") {
+                continue;
+            }
+            let spec = ChildSpec::from_section(section)?;
+            if let Err(e) = self.start(spec) {
+                loge!(LOG_TAG, "[Supervisor] {}", e);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn start(&self, spec: ChildSpec) -> Result<(), String> {
+        let mut children = self.children.lock().unwrap();
+        if let Some((_, control)) = children.get(&spec.name) {
+            if !control.finished.load(Ordering::Relaxed) {
+                return Err(format!("{} is already running", spec.name));
+            }
+        }
+        let control = Arc::new(ChildControl::default());
+        children.insert(spec.name.clone(), (spec.clone(), control.clone()));
+        let source = ChildSource {
+            spec,
+            control,
+            finished: false,
+        };
+        spawn_source(SourceType::Child(source), self.sender.clone());
+        Ok(())
+    }
+
+    pub fn stop(&self, name: &str) -> Result<(), String> {
+        let children = self.children.lock().unwrap();
+        let (_, control) = children
+            .get(name)
+            .ok_or_else(|| format!("no child named {}", name))?;
+        control.stop.store(true, Ordering::Relaxed);
+        let pid = control.pid.load(Ordering::Relaxed);
+        if pid != 0 {
+            kill(Pid::from_raw(pid as i32), Signal::SIGTERM)
+                .map_err(|e| format!("cannot stop {}: {}", name, e))?;
+        }
+        Ok(())
+    }
+
+    /// Handles `spawn <name> [--restart=<policy>] <command> [args...]`.
+    pub fn spawn_command<'a>(&self, mut args: impl Iterator<Item = &'a str>) -> String {
+        if !self.allow_control_spawn {
+            return String::from("spawning from the control socket is disabled\n");
+        }
+        let Some(name) = args.next() else {
+            return String::from("usage: spawn <name> [--restart=<policy>] <command> [args...]\n");
+        };
+        let mut restart = RestartPolicy::OnFailure;
+        let mut command: Vec<String> = Vec::new();
+        for arg in args {
+            match arg.strip_prefix("--restart=") {
+                Some(policy) if command.is_empty() => match RestartPolicy::from_name(policy) {
+                    Some(policy) => restart = policy,
+                    None => return format!("unknown restart policy: {}\n", policy),
+                },
+                _ => command.push(arg.to_string()),
+            }
+        }
+        if command.is_empty() {
+            return String::from("missing command\n");
+        }
+        let spec = ChildSpec {
+            name: name.to_string(),
+            command,
+            restart,
+            max_restarts: None,
+            sink_type: u8::MAX,
+        };
+        match self.start(spec) {
+            Ok(()) => format!("started {}\n", name),
+            Err(e) => format!("{}\n", e),
+        }
+    }
+
+    pub fn list(&self) -> String {
+        let mut out = String::new();
+        let children = self.children.lock().unwrap();
+        let mut names: Vec<_> = children.keys().collect();
+        names.sort();
+        for name in names {
+            let (spec, control) = &children[name];
+            let _ = writeln!(
+                out,
+                "child {} pid={} restarts={} restart={:?} state={} command={}",
+                name,
+                control.pid.load(Ordering::Relaxed),
+                control.restarts.load(Ordering::Relaxed),
+                spec.restart,
+                if control.finished.load(Ordering::Relaxed) {
+                    "stopped"
+                } else {
+                    "running"
+                },
+                spec.command.join(" ")
+            );
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::record_queue::{self, QueueSettings};
+
+    fn missing(restart: RestartPolicy, max_restarts: Option<u32>) -> ChildSource {
+        ChildSource {
+            spec: ChildSpec {
+                name: String::from("missing"),
+                command: vec![String::from("/nonexistent/notcatd-test")],
+                restart,
+                max_restarts,
+                sink_type: u8::MAX,
+            },
+            control: Arc::new(ChildControl::default()),
+            finished: false,
+        }
+    }
+
+    #[test]
+    fn failed_spawn_honours_restart_never() {
+        let (sender, _receiver) = record_queue::channel(QueueSettings::default());
+        let mut source = missing(RestartPolicy::Never, None);
+        assert!(source.read_loop(&sender).is_err());
+        assert!(source.is_finished());
+        assert!(source.control.finished.load(Ordering::Relaxed));
+        assert_eq!(source.control.restarts.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn failed_spawn_counts_against_max_restarts() {
+        let (sender, _receiver) = record_queue::channel(QueueSettings::default());
+        let mut source = missing(RestartPolicy::Always, Some(2));
+        for _ in 0..2 {
+            assert!(source.read_loop(&sender).is_err());
+            assert!(!source.is_finished());
+        }
+        assert!(source.read_loop(&sender).is_err());
+        assert!(source.is_finished());
+        assert_eq!(source.control.restarts.load(Ordering::Relaxed), 3);
+    }
+}
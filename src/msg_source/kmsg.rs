@@ -0,0 +1,406 @@
+use crate::config::{ConfigError, Section};
+use crate::log::*;
+use crate::log_def::*;
+use crate::msg_proc::LogRecord;
+use crate::msg_sink::SinkTypeOrdinal;
+use crate::msg_source::MessageSource;
+use crate::record_queue::RecordSender as Sender;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom};
+use std::os::unix::fs::FileTypeExt;
+use thiserror::Error;
+
+static KMSG_PATH: &str = "/dev/kmsg";
+static KMSG_TAG: &str = "kernel";
+static KMSG_RECORD_MAX_LEN: usize = 8192;
+
+#[derive(Error, Debug, PartialEq)]
+pub enum KmsgError {
+    #[error("Missing ';' separator")]
+    MissingSeparator,
+    #[error("Malformed prefix field: {0}")]
+    MalformedField(String),
+}
+
+/// One `/dev/kmsg` record: `prio,seq,usec,flags[,...];text` followed by
+/// continuation lines (starting with a space) carrying `KEY=value` properties.
+#[derive(Debug, PartialEq)]
+pub struct KmsgRecord {
+    pub facility: u8,
+    pub level: u8,
+    pub sequence: u64,
+    pub usec: u64,
+    pub text: String,
+    pub properties: Vec<String>,
+}
+
+impl KmsgRecord {
+    pub fn parse(record: &str) -> Result<KmsgRecord, KmsgError> {
+        let mut lines = record.lines();
+        let first = lines.next().unwrap_or_default();
+        let (prefix, text) = first.split_once(';').ok_or(KmsgError::MissingSeparator)?;
+        let mut fields = prefix.split(',');
+        let mut next_field = || {
+            let field = fields.next().unwrap_or_default();
+            field
+                .parse::<u64>()
+                .map_err(|_| KmsgError::MalformedField(field.to_string()))
+        };
+        let prio = next_field()?;
+        let sequence = next_field()?;
+        let usec = next_field()?;
+        Ok(KmsgRecord {
+            facility: (prio >> 3) as u8,
+            level: (prio & 7) as u8,
+            sequence,
+            usec,
+            text: unescape(text),
+            properties: lines
+                .filter(|l| l.starts_with(' '))
+                .map(|l| l.trim().to_string())
+                .collect(),
+        })
+    }
+
+    pub fn priority(&self) -> LogPriority {
+        // syslog levels: 0 emerg .. 7 debug
+        match self.level {
+            0..=2 => LogPriority::Fatal,
+            3 => LogPriority::Error,
+            4 => LogPriority::Warn,
+            5 | 6 => LogPriority::Info,
+            _ => LogPriority::Debug,
+        }
+    }
+
+    /// `boot_offset_usec` is the wall-clock time of boot, so that adding the
+    /// record's monotonic timestamp yields its wall-clock time.
+    pub fn into_message(self, boot_offset_usec: i64) -> LogMessage {
+        let wall_usec = boot_offset_usec + self.usec as i64;
+        LogMessage {
+            pid: 0,
+            uid: None,
+            priority: self.priority(),
+            timestamp: LogTimeStamp::from_unix(
+                wall_usec.div_euclid(1_000_000),
+                (wall_usec.rem_euclid(1_000_000) / 1000) as u16,
+            ),
+            tag: Some(KMSG_TAG.to_string()),
+            message: self.text,
+            fields: Vec::new(),
+            trace: None,
+        }
+    }
+}
+
+/// Decodes the `\xNN` escapes the kernel uses for non-printable bytes.
+fn unescape(text: &str) -> String {
+    if !text.contains("\\x") {
+        return text.to_string();
+    }
+    let bytes = text.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'\\' && i + 3 < bytes.len() && bytes[i + 1] == b'x' {
+            let hex = std::str::from_utf8(&bytes[i + 2..i + 4]).unwrap_or_default();
+            if let Ok(b) = u8::from_str_radix(hex, 16) {
+                out.push(b);
+                i += 4;
+                continue;
+            }
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).to_string()
+}
+
+fn clock_usec(clock: libc::clockid_t) -> i64 {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe {
+        libc::clock_gettime(clock, &mut ts);
+    }
+    ts.tv_sec * 1_000_000 + ts.tv_nsec / 1000
+}
+
+/// Wall-clock time of boot in microseconds, as seen by the printk clock.
+pub fn boot_offset_usec() -> i64 {
+    clock_usec(libc::CLOCK_REALTIME) - clock_usec(libc::CLOCK_MONOTONIC)
+}
+
+pub struct KmsgSource {
+    path: String,
+    from_start: bool,
+    sink_type: u8,
+    last_sequence: Option<u64>,
+    /// Reopened after an error: the device is read again from its oldest
+    /// record, and records up to `last_sequence` were already forwarded.
+    replaying: bool,
+    finished: bool,
+}
+
+impl KmsgSource {
+    pub fn from_section(section: &Section) -> Result<Self, ConfigError> {
+        Ok(KmsgSource {
+            path: section.get("path").unwrap_or(KMSG_PATH).to_string(),
+            from_start: section.get_parsed("from_start")?.unwrap_or(false),
+            sink_type: SinkTypeOrdinal::mask_from_section(
+                section,
+                SinkTypeOrdinal::LocalFileType as u8,
+            )?,
+            last_sequence: None,
+            replaying: false,
+            finished: false,
+        })
+    }
+
+    fn send(&self, message: LogMessage, sender: &Sender) -> bool {
+        sender
+            .send(LogRecord::Ingested {
+                sink_type: self.sink_type,
+                message,
+            })
+            .is_ok()
+    }
+
+    fn notice(&self, text: String) -> LogMessage {
+        LogMessage {
+            pid: 0,
+            uid: None,
+            priority: LogPriority::Warn,
+            timestamp: LogTimeStamp::now(),
+            tag: Some(KMSG_TAG.to_string()),
+            message: text,
+            fields: Vec::new(),
+            trace: None,
+        }
+    }
+
+    /// Skips records already forwarded, reports lost records and sequence
+    /// resets, then forwards the record. Returns false once the
+    /// channel is closed.
+    fn forward(&mut self, raw: &str, sender: &Sender) -> bool {
+        let record = match KmsgRecord::parse(raw) {
+            Ok(record) => record,
+            Err(e) => {
+                logw!(LOG_TAG, "[KmsgSource] Skipping record: {}", e);
+                return true;
+            }
+        };
+        if let Some(last) = self.last_sequence {
+            let notice = if record.sequence == last || (self.replaying && record.sequence < last) {
+                // already forwarded
+                return true;
+            } else if record.sequence < last {
+                format!(
+                    "kmsg: sequence reset (seq {} after {})",
+                    record.sequence, last
+                )
+            } else if record.sequence > last + 1 {
+                format!(
+                    "kmsg: {} records lost (seq {}..{})",
+                    record.sequence - last - 1,
+                    last + 1,
+                    record.sequence - 1
+                )
+            } else {
+                String::new()
+            };
+            if !notice.is_empty() && !self.send(self.notice(notice), sender) {
+                return false;
+            }
+        }
+        self.replaying = false;
+        self.last_sequence = Some(record.sequence);
+        self.send(record.into_message(boot_offset_usec()), sender)
+    }
+
+    /// The kmsg device returns exactly one record per read.
+    fn read_device(&mut self, mut file: File, sender: &Sender) -> io::Result<()> {
+        if !self.from_start && self.last_sequence.is_none() {
+            file.seek(SeekFrom::End(0))?;
+        }
+        self.replaying = self.last_sequence.is_some();
+        let mut buf = vec![0u8; KMSG_RECORD_MAX_LEN];
+        loop {
+            match file.read(&mut buf) {
+                Ok(0) => return Ok(()),
+                Ok(n) => {
+                    let raw = String::from_utf8_lossy(&buf[..n]).to_string();
+                    if !self.forward(&raw, sender) {
+                        return Ok(());
+                    }
+                }
+                // records were overwritten before we read them, the sequence
+                // check reports the gap on the next record
+                Err(e) if e.raw_os_error() == Some(libc::EPIPE) => continue,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Recorded kmsg text: a record line followed by its continuation lines.
+    fn read_text<R: Read>(&mut self, input: R, sender: &Sender) -> io::Result<()> {
+        let mut pending = String::new();
+        for line in BufReader::new(input).lines() {
+            let line = line?;
+            if line.starts_with(' ') && !pending.is_empty() {
+                pending.push('\n');
+                pending.push_str(&line);
+                continue;
+            }
+            if !pending.is_empty() && !self.forward(&pending, sender) {
+                return Ok(());
+            }
+            pending = line;
+        }
+        if !pending.is_empty() {
+            self.forward(&pending, sender);
+        }
+        Ok(())
+    }
+}
+
+impl MessageSource for KmsgSource {
+    fn name(&self) -> &'static str {
+        "kmsg"
+    }
+
+    fn read_loop(&mut self, sender: &Sender) -> io::Result<()> {
+        let file = File::open(&self.path)?;
+        let file_type = file.metadata()?.file_type();
+        if file_type.is_char_device() {
+            self.read_device(file, sender)
+        } else {
+            let result = self.read_text(file, sender);
+            self.finished = file_type.is_file() && result.is_ok();
+            result
+        }
+    }
+
+    fn is_finished(&self) -> bool {
+        self.finished
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::record_queue::{self, QueueSettings};
+
+    /// Recorded `/dev/kmsg` text with properties and a gap of two records.
+    static FIXTURE: &str = include_str!("testdata/kmsg.txt");
+    /// A recording spanning a reboot: the sequence starts again from 0.
+    static REBOOT_FIXTURE: &str = include_str!("testdata/kmsg_reboot.txt");
+
+    fn source() -> KmsgSource {
+        KmsgSource {
+            path: String::new(),
+            from_start: true,
+            sink_type: SinkTypeOrdinal::LocalFileType as u8,
+            last_sequence: None,
+            replaying: false,
+            finished: false,
+        }
+    }
+
+    fn read(source: &mut KmsgSource, text: &str) -> Vec<LogMessage> {
+        let (sender, mut receiver) = record_queue::channel(QueueSettings {
+            priority_lanes: false,
+            ..QueueSettings::default()
+        });
+        source.read_text(text.as_bytes(), &sender).unwrap();
+        let mut messages = Vec::new();
+        while receiver.len() > 0 {
+            match receiver.blocking_recv() {
+                Some(LogRecord::Ingested { message, .. }) => messages.push(message),
+                _ => panic!("unexpected record"),
+            }
+        }
+        messages
+    }
+
+    fn texts(messages: &[LogMessage]) -> Vec<&str> {
+        messages.iter().map(|m| m.message.as_str()).collect()
+    }
+
+    #[test]
+    fn parse_record_with_properties() {
+        let record =
+            KmsgRecord::parse("4,1002,5100000,-;thermal: sensor\\x20tz0\n SUBSYSTEM=thermal")
+                .unwrap();
+        assert_eq!((record.facility, record.level), (0, 4));
+        assert_eq!((record.sequence, record.usec), (1002, 5100000));
+        assert_eq!(record.text, "thermal: sensor tz0");
+        assert_eq!(record.properties, ["SUBSYSTEM=thermal"]);
+        assert_eq!(record.priority(), LogPriority::Warn);
+        assert_eq!(
+            KmsgRecord::parse("6,x,0,-;text"),
+            Err(KmsgError::MalformedField("x".into()))
+        );
+        assert_eq!(
+            KmsgRecord::parse("6,1,0,-"),
+            Err(KmsgError::MissingSeparator)
+        );
+    }
+
+    #[test]
+    fn fixture_reports_gap() {
+        let mut source = source();
+        let messages = read(&mut source, FIXTURE);
+        assert_eq!(
+            texts(&messages),
+            [
+                "Booting Linux on physical CPU 0x0",
+                "thermal: sensor tz0 over limit",
+                "usb 1-1: device descriptor read error -71",
+                "kmsg: 2 records lost (seq 1004..1005)",
+                "wlan: connected",
+            ]
+        );
+        assert_eq!(messages[2].priority, LogPriority::Error);
+        assert_eq!(source.last_sequence, Some(1006));
+    }
+
+    #[test]
+    fn replay_after_restart_is_not_duplicated() {
+        let mut source = source();
+        read(&mut source, &FIXTURE[..FIXTURE.find("3,1003").unwrap()]);
+        assert_eq!(source.last_sequence, Some(1002));
+        // the device is read again from its oldest record
+        source.replaying = true;
+        let messages = read(&mut source, FIXTURE);
+        assert_eq!(
+            texts(&messages),
+            [
+                "usb 1-1: device descriptor read error -71",
+                "kmsg: 2 records lost (seq 1004..1005)",
+                "wlan: connected",
+            ]
+        );
+        // reading the same records again outside a replay drops nothing new
+        assert!(read(&mut source, "6,1006,5300000,-;wlan: connected\n").is_empty());
+    }
+
+    #[test]
+    fn sequence_going_back_is_a_reset() {
+        let mut source = source();
+        let messages = read(&mut source, REBOOT_FIXTURE);
+        assert_eq!(
+            texts(&messages),
+            [
+                "last record before reboot",
+                "shutting down",
+                "kmsg: sequence reset (seq 0 after 2001)",
+                "Booting Linux on physical CPU 0x0",
+                "Linux version 6.1",
+            ]
+        );
+        assert_eq!(source.last_sequence, Some(1));
+    }
+}
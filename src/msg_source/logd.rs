@@ -0,0 +1,514 @@
+use crate::config::{ConfigError, Section};
+use crate::log::*;
+use crate::log_def::*;
+use crate::msg_proc::LogRecord;
+use crate::msg_sink::SinkTypeOrdinal;
+use crate::msg_sink::android_native::convert_android_priority;
+use crate::msg_source::MessageSource;
+use crate::record_queue::RecordSender as Sender;
+use nix::sys::socket::{AddressFamily, SockFlag, SockType, UnixAddr, connect, socket};
+use std::fs::File;
+use std::io::{self, BufReader, Read, Write};
+use std::os::fd::AsRawFd;
+use std::os::unix::net::UnixStream;
+use std::process::{Command, Stdio};
+use thiserror::Error;
+
+static LOGDR_SOCKET_PATH: &str = "/dev/socket/logdr";
+static DEFAULT_BUFFERS: &str = "main,system,crash";
+static LOGGER_ENTRY_MAX_LEN: usize = 5 * 1024;
+static LOGGER_ENTRY_V1_HDR_SIZE: usize = 20;
+static LOGGER_ENTRY_PREFIX_SIZE: usize = 4; // len and hdr_size
+
+#[derive(Error, Debug, PartialEq)]
+pub enum LogdError {
+    #[error("Truncated entry: {0} bytes")]
+    Truncated(usize),
+    #[error("Incorrect header size: {0}")]
+    IncorrectHeaderSize(usize),
+    #[error("Binary log buffer: {0}")]
+    BinaryBuffer(u32),
+    #[error("Malformed text payload")]
+    MalformedPayload,
+    #[error("Oversized entry: {0} bytes")]
+    Oversized(usize),
+}
+
+/// Android log buffer ids, see `log_id_t`.
+fn buffer_id(name: &str) -> Option<u32> {
+    match name {
+        "main" => Some(0),
+        "radio" => Some(1),
+        "system" => Some(3),
+        "crash" => Some(4),
+        "kernel" => Some(7),
+        _ => None,
+    }
+}
+
+fn is_text_buffer(lid: u32) -> bool {
+    // events (2), stats (5) and security (6) carry binary payloads
+    !matches!(lid, 2 | 5 | 6)
+}
+
+/// One decoded `struct logger_entry` with its text payload.
+#[derive(Debug, PartialEq)]
+pub struct LogdEntry {
+    pub pid: i32,
+    pub tid: u32,
+    pub sec: u32,
+    pub nsec: u32,
+    pub lid: u32,
+    pub uid: u32,
+    pub priority: u8,
+    pub tag: String,
+    pub message: String,
+}
+
+impl LogdEntry {
+    /// Parses one entry from the start of `buf`, returning it together with
+    /// the number of bytes it occupied. Header fields are little endian.
+    pub fn parse(buf: &[u8]) -> Result<(LogdEntry, usize), LogdError> {
+        if buf.len() < LOGGER_ENTRY_PREFIX_SIZE {
+            return Err(LogdError::Truncated(buf.len()));
+        }
+        let payload_len = u16::from_le_bytes([buf[0], buf[1]]) as usize;
+        let hdr_size = match u16::from_le_bytes([buf[2], buf[3]]) as usize {
+            0 => LOGGER_ENTRY_V1_HDR_SIZE, // v1 entries leave hdr_size unset
+            size if size < LOGGER_ENTRY_V1_HDR_SIZE => {
+                return Err(LogdError::IncorrectHeaderSize(size));
+            }
+            size => size,
+        };
+        let entry_len = hdr_size + payload_len;
+        if entry_len > LOGGER_ENTRY_MAX_LEN {
+            return Err(LogdError::Oversized(entry_len));
+        }
+        if buf.len() < entry_len {
+            return Err(LogdError::Truncated(buf.len()));
+        }
+        let field = |offset: usize| {
+            if offset + 4 <= hdr_size {
+                u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+            } else {
+                0
+            }
+        };
+        let lid = field(20);
+        if !is_text_buffer(lid) {
+            return Err(LogdError::BinaryBuffer(lid));
+        }
+        // payload: priority byte, NUL terminated tag, NUL terminated message
+        let payload = &buf[hdr_size..entry_len];
+        let (&priority, rest) = payload.split_first().ok_or(LogdError::MalformedPayload)?;
+        let tag_end = rest
+            .iter()
+            .position(|&b| b == 0)
+            .ok_or(LogdError::MalformedPayload)?;
+        let message = &rest[tag_end + 1..];
+        let message_end = message
+            .iter()
+            .position(|&b| b == 0)
+            .unwrap_or(message.len());
+        let entry = LogdEntry {
+            pid: field(4) as i32,
+            tid: field(8),
+            sec: field(12),
+            nsec: field(16),
+            lid,
+            uid: field(24),
+            priority,
+            tag: String::from_utf8_lossy(&rest[..tag_end]).to_string(),
+            message: String::from_utf8_lossy(&message[..message_end])
+                .trim_end_matches('\n')
+                .to_string(),
+        };
+        Ok((entry, entry_len))
+    }
+
+    /// Returns `None` for entries that should not be forwarded (silent priority).
+    pub fn into_message(self) -> Option<LogMessage> {
+        Some(LogMessage {
+            pid: self.pid as u32,
+            uid: Some(self.uid),
+            priority: convert_android_priority(self.priority)?,
+            timestamp: LogTimeStamp::from_unix(self.sec as i64, (self.nsec / 1_000_000) as u16),
+            tag: Some(self.tag),
+            message: self.message,
+            fields: Vec::new(),
+            trace: None,
+        })
+    }
+}
+
+/// Reads consecutive entries from a byte stream, such as `logcat -B` output.
+pub struct LogdStreamReader<R: Read> {
+    reader: BufReader<R>,
+    buffer: Vec<u8>,
+}
+
+impl<R: Read> LogdStreamReader<R> {
+    pub fn new(reader: R) -> Self {
+        LogdStreamReader {
+            reader: BufReader::new(reader),
+            buffer: Vec::with_capacity(LOGGER_ENTRY_MAX_LEN),
+        }
+    }
+
+    /// Returns the raw bytes of the next entry, or `None` at end of stream.
+    pub fn next_entry(&mut self) -> io::Result<Option<&[u8]>> {
+        self.buffer.resize(LOGGER_ENTRY_PREFIX_SIZE, 0);
+        match self.reader.read_exact(&mut self.buffer) {
+            Ok(()) => (),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        }
+        let payload_len = u16::from_le_bytes([self.buffer[0], self.buffer[1]]) as usize;
+        let hdr_size = match u16::from_le_bytes([self.buffer[2], self.buffer[3]]) as usize {
+            0 => LOGGER_ENTRY_V1_HDR_SIZE,
+            size if size < LOGGER_ENTRY_V1_HDR_SIZE => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    LogdError::IncorrectHeaderSize(size),
+                ));
+            }
+            size => size,
+        };
+        self.buffer.resize(hdr_size + payload_len, 0);
+        self.reader
+            .read_exact(&mut self.buffer[LOGGER_ENTRY_PREFIX_SIZE..])?;
+        Ok(Some(&self.buffer))
+    }
+}
+
+#[derive(Debug)]
+pub enum LogdInput {
+    /// Streams from the logd reader socket (default `/dev/socket/logdr`).
+    Socket(String),
+    /// Runs `logcat -B` and reads its binary stdout.
+    Logcat,
+    /// Reads binary entries from a file or FIFO. A FIFO is reopened when the
+    /// writer goes away, a regular file is read once.
+    File(String),
+}
+
+pub struct LogdSource {
+    input: LogdInput,
+    buffers: Vec<String>,
+    sink_type: u8,
+    finished: bool,
+}
+
+impl LogdSource {
+    pub fn from_section(section: &Section) -> Result<Self, ConfigError> {
+        let path = section.get("path");
+        let input = match section.get("mode").unwrap_or("logdr") {
+            "logdr" => LogdInput::Socket(path.unwrap_or(LOGDR_SOCKET_PATH).to_string()),
+            "logcat" => LogdInput::Logcat,
+            "file" => LogdInput::File(path.ok_or_else(|| section.invalid("path", ""))?.to_string()),
+            other => return Err(section.invalid("mode", other)),
+        };
+        let mut buffers: Vec<String> = section
+            .get_list("buffers")
+            .iter()
+            .map(|b| b.to_string())
+            .collect();
+        if buffers.is_empty() {
+            buffers = DEFAULT_BUFFERS.split(',').map(str::to_string).collect();
+        }
+        for buffer in &buffers {
+            if buffer_id(buffer).is_none() {
+                return Err(section.invalid("buffers", buffer));
+            }
+        }
+        let mut sink_type =
+            SinkTypeOrdinal::mask_from_section(section, SinkTypeOrdinal::LocalFileType as u8)?;
+        if sink_type & SinkTypeOrdinal::AndroidNativeType as u8 != 0 {
+            // writing logd records back into logd would feed them to us again
+            logw!(
+                LOG_TAG,
+                "[LogdSource] android_native sink ignored for logd input"
+            );
+            sink_type &= !(SinkTypeOrdinal::AndroidNativeType as u8);
+        }
+        Ok(LogdSource {
+            input,
+            buffers,
+            sink_type,
+            finished: false,
+        })
+    }
+
+    /// Forwards one raw entry. Returns false once the channel is closed.
+    fn forward(&self, raw: &[u8], sender: &Sender) -> bool {
+        let entry = match LogdEntry::parse(raw) {
+            Ok((entry, _)) => entry,
+            Err(LogdError::BinaryBuffer(_)) => return true,
+            Err(e) => {
+                logw!(LOG_TAG, "[LogdSource] Skipping entry: {}", e);
+                return true;
+            }
+        };
+        // our own diagnostics already reach the sinks directly
+        if entry.pid as u32 == std::process::id() {
+            return true;
+        }
+        match entry.into_message() {
+            Some(message) => sender
+                .send(LogRecord::Ingested {
+                    sink_type: self.sink_type,
+                    message,
+                })
+                .is_ok(),
+            None => true,
+        }
+    }
+
+    fn read_stream<R: Read>(&self, input: R, sender: &Sender) -> io::Result<()> {
+        let mut reader = LogdStreamReader::new(input);
+        while let Some(raw) = reader.next_entry()? {
+            if !self.forward(raw, sender) {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn read_socket(&self, path: &str, sender: &Sender) -> io::Result<()> {
+        let fd = socket(
+            AddressFamily::Unix,
+            SockType::SeqPacket,
+            SockFlag::SOCK_CLOEXEC,
+            None,
+        )?;
+        connect(fd.as_raw_fd(), &UnixAddr::new(path)?)?;
+        let mut stream = UnixStream::from(fd);
+        let lids: Vec<String> = self
+            .buffers
+            .iter()
+            .filter_map(|b| buffer_id(b))
+            .map(|id| id.to_string())
+            .collect();
+        stream.write_all(format!("stream lids={}", lids.join(",")).as_bytes())?;
+        logi!(
+            LOG_TAG,
+            "[LogdSource] Streaming {} from {}",
+            lids.join(","),
+            path
+        );
+        // every read returns exactly one entry on a SEQPACKET socket
+        let mut buf = vec![0u8; LOGGER_ENTRY_MAX_LEN];
+        loop {
+            let n = stream.read(&mut buf)?;
+            if n == 0 || !self.forward(&buf[..n], sender) {
+                return Ok(());
+            }
+        }
+    }
+}
+
+impl MessageSource for LogdSource {
+    fn name(&self) -> &'static str {
+        "logd"
+    }
+
+    fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    fn read_loop(&mut self, sender: &Sender) -> io::Result<()> {
+        match &self.input {
+            LogdInput::Socket(path) => self.read_socket(path, sender),
+            LogdInput::File(path) => {
+                let file = File::open(path)?;
+                let is_regular = file.metadata()?.is_file();
+                let result = self.read_stream(file, sender);
+                self.finished = is_regular && result.is_ok();
+                result
+            }
+            LogdInput::Logcat => {
+                let mut child = Command::new("logcat")
+                    .args(["-B", "-b", &self.buffers.join(",")])
+                    .stdin(Stdio::null())
+                    .stdout(Stdio::piped())
+                    .stderr(Stdio::null())
+                    .spawn()?;
+                let stdout = child.stdout.take().unwrap();
+                let result = self.read_stream(stdout, sender);
+                let _ = child.kill();
+                let _ = child.wait();
+                result
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::record_queue::{self, QueueSettings};
+    use nix::sys::socket::{accept, bind, listen};
+    use std::os::fd::{FromRawFd, OwnedFd};
+    use std::thread;
+
+    /// `logcat -B` style captures: two text entries with the 24 byte v3
+    /// header; a text entry, an events entry and a text entry with the 28
+    /// byte v4 header.
+    static V3_FIXTURE: &[u8] = include_bytes!("testdata/logd_v3.bin");
+    static V4_FIXTURE: &[u8] = include_bytes!("testdata/logd_v4.bin");
+
+    fn entries(fixture: &[u8]) -> Vec<Vec<u8>> {
+        let mut reader = LogdStreamReader::new(fixture);
+        let mut raw = Vec::new();
+        while let Some(entry) = reader.next_entry().unwrap() {
+            raw.push(entry.to_vec());
+        }
+        raw
+    }
+
+    fn source(input: LogdInput) -> LogdSource {
+        LogdSource {
+            input,
+            buffers: vec!["main".into(), "system".into(), "crash".into()],
+            sink_type: SinkTypeOrdinal::LocalFileType as u8,
+            finished: false,
+        }
+    }
+
+    #[test]
+    fn parse_v3_entries() {
+        let raw = entries(V3_FIXTURE);
+        assert_eq!(raw.len(), 2);
+        let (entry, len) = LogdEntry::parse(&raw[0]).unwrap();
+        assert_eq!(len, raw[0].len());
+        assert_eq!(entry.pid, 612);
+        assert_eq!(entry.tid, 640);
+        assert_eq!(entry.sec, 1760000000);
+        assert_eq!(entry.lid, 0);
+        assert_eq!(entry.uid, 0);
+        assert_eq!(entry.tag, "ActivityManager");
+        assert_eq!(entry.message, "Start proc 4242:com.example/u0a55");
+        let message = LogdEntry::parse(&raw[1]).unwrap().0.into_message().unwrap();
+        assert_eq!(message.priority, LogPriority::Fatal);
+        assert_eq!(message.timestamp.millisecond, 5);
+    }
+
+    #[test]
+    fn parse_v4_entries_and_binary_buffer() {
+        let raw = entries(V4_FIXTURE);
+        assert_eq!(raw.len(), 3);
+        let (entry, _) = LogdEntry::parse(&raw[0]).unwrap();
+        assert_eq!((entry.lid, entry.uid), (3, 1000));
+        assert_eq!(entry.tag, "netd");
+        assert_eq!(entry.message, "dns timeout uid=10055");
+        assert_eq!(LogdEntry::parse(&raw[1]), Err(LogdError::BinaryBuffer(2)));
+        let message = LogdEntry::parse(&raw[2]).unwrap().0.into_message().unwrap();
+        assert_eq!(message.uid, Some(10055));
+        assert_eq!(message.priority, LogPriority::Debug);
+    }
+
+    #[test]
+    fn truncated_entries() {
+        assert_eq!(
+            LogdEntry::parse(&V4_FIXTURE[..3]),
+            Err(LogdError::Truncated(3))
+        );
+        assert_eq!(
+            LogdEntry::parse(&V4_FIXTURE[..40]),
+            Err(LogdError::Truncated(40))
+        );
+        // a stream cut inside an entry fails instead of yielding a partial one
+        let mut reader = LogdStreamReader::new(&V3_FIXTURE[..V3_FIXTURE.len() - 5]);
+        assert!(reader.next_entry().unwrap().is_some());
+        let error = reader.next_entry().unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn malformed_header_and_payload() {
+        let mut raw = entries(V3_FIXTURE).remove(0);
+        raw[2] = 12;
+        assert_eq!(
+            LogdEntry::parse(&raw),
+            Err(LogdError::IncorrectHeaderSize(12))
+        );
+        let mut reader = LogdStreamReader::new(raw.as_slice());
+        assert_eq!(
+            reader.next_entry().unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+        // payload cut before the NUL ending the tag
+        let mut raw = entries(V3_FIXTURE).remove(0);
+        raw.truncate(24 + 4);
+        raw[0..2].copy_from_slice(&4u16.to_le_bytes());
+        assert_eq!(LogdEntry::parse(&raw), Err(LogdError::MalformedPayload));
+    }
+
+    #[test]
+    fn oversized_entry() {
+        let mut raw = entries(V4_FIXTURE).remove(0);
+        let payload_len = LOGGER_ENTRY_MAX_LEN as u16;
+        raw[0..2].copy_from_slice(&payload_len.to_le_bytes());
+        raw.resize(28 + LOGGER_ENTRY_MAX_LEN, b'x');
+        assert_eq!(
+            LogdEntry::parse(&raw),
+            Err(LogdError::Oversized(28 + LOGGER_ENTRY_MAX_LEN))
+        );
+        // the stream reader stays in sync past it
+        let mut stream = raw.clone();
+        stream.extend_from_slice(V3_FIXTURE);
+        let mut reader = LogdStreamReader::new(stream.as_slice());
+        assert_eq!(reader.next_entry().unwrap().unwrap().len(), raw.len());
+        let next = reader.next_entry().unwrap().unwrap();
+        assert_eq!(LogdEntry::parse(next).unwrap().0.pid, 612);
+    }
+
+    #[test]
+    fn read_socket_from_fake_logdr() {
+        let path = std::env::temp_dir().join(format!("notcat_logdr_{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = socket(
+            AddressFamily::Unix,
+            SockType::SeqPacket,
+            SockFlag::SOCK_CLOEXEC,
+            None,
+        )
+        .unwrap();
+        bind(listener.as_raw_fd(), &UnixAddr::new(&path).unwrap()).unwrap();
+        listen(&listener, 1).unwrap();
+        let logdr = thread::spawn(move || {
+            let fd = accept(listener.as_raw_fd()).unwrap();
+            let mut client = UnixStream::from(unsafe { OwnedFd::from_raw_fd(fd) });
+            let mut command = [0u8; 64];
+            let n = client.read(&mut command).unwrap();
+            // one entry per packet, like logd
+            for raw in entries(V4_FIXTURE).iter().chain(entries(V3_FIXTURE).iter()) {
+                client.write_all(raw).unwrap();
+            }
+            String::from_utf8_lossy(&command[..n]).to_string()
+        });
+        let (sender, mut receiver) = record_queue::channel(QueueSettings {
+            priority_lanes: false,
+            ..QueueSettings::default()
+        });
+        let mut source = source(LogdInput::Socket(path.display().to_string()));
+        source.read_loop(&sender).unwrap();
+        assert_eq!(logdr.join().unwrap(), "stream lids=0,3,4");
+        let mut tags = Vec::new();
+        while receiver.len() > 0 {
+            match receiver.blocking_recv() {
+                Some(LogRecord::Ingested { sink_type, message }) => {
+                    assert_eq!(sink_type, SinkTypeOrdinal::LocalFileType as u8);
+                    tags.push(message.tag.unwrap());
+                }
+                _ => panic!("unexpected record"),
+            }
+        }
+        // the events entry is skipped
+        assert_eq!(
+            tags,
+            ["netd", "chatty", "ActivityManager", "AndroidRuntime"]
+        );
+        let _ = std::fs::remove_file(&path);
+    }
+}
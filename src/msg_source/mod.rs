@@ -0,0 +1,122 @@
+pub mod child;
+pub mod kmsg;
+pub mod logd;
+pub mod tail;
+use crate::config::{Config, ConfigError};
+use crate::log::*;
+use crate::log_def::*;
+use crate::record_queue::RecordSender as Sender;
+use std::io;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+static RESTART_DELAY_MIN: Duration = Duration::from_secs(1);
+static RESTART_DELAY_MAX: Duration = Duration::from_secs(30);
+
+/// A producer of records other than native notcat clients.
+pub trait MessageSource {
+    fn name(&self) -> &'static str;
+    /// Reads records until the input ends or fails. Returning is not final,
+    /// the source is restarted with a backoff while the channel is open.
+    fn read_loop(&mut self, sender: &Sender) -> io::Result<()>;
+    /// True once the input can never produce more records, e.g. a fully
+    /// read regular file.
+    fn is_finished(&self) -> bool {
+        false
+    }
+}
+
+pub enum SourceType {
+    Logd(logd::LogdSource),
+    Kmsg(kmsg::KmsgSource),
+    Child(child::ChildSource),
+    Tail(tail::TailSource),
+}
+
+impl MessageSource for SourceType {
+    fn name(&self) -> &'static str {
+        match self {
+            SourceType::Logd(implem) => implem.name(),
+            SourceType::Kmsg(implem) => implem.name(),
+            SourceType::Child(implem) => implem.name(),
+            SourceType::Tail(implem) => implem.name(),
+        }
+    }
+
+    fn read_loop(&mut self, sender: &Sender) -> io::Result<()> {
+        match self {
+            SourceType::Logd(implem) => implem.read_loop(sender),
+            SourceType::Kmsg(implem) => implem.read_loop(sender),
+            SourceType::Child(implem) => implem.read_loop(sender),
+            SourceType::Tail(implem) => implem.read_loop(sender),
+        }
+    }
+
+    fn is_finished(&self) -> bool {
+        match self {
+            SourceType::Logd(implem) => implem.is_finished(),
+            SourceType::Kmsg(implem) => implem.is_finished(),
+            SourceType::Child(implem) => implem.is_finished(),
+            SourceType::Tail(implem) => implem.is_finished(),
+        }
+    }
+}
+
+impl SourceType {
+    /// Builds every source declared with a `[source <name>]` section, plus one
+    /// tail source per `[tail <name>]` section.
+    pub fn from_config(config: &Config) -> Result<Vec<SourceType>, ConfigError> {
+        let mut sources = Vec::new();
+        for section in config.sections("source") {
+            if section.get("enabled") == Some("false") {
+                continue;
+            }
+            match section.name.as_deref() {
+                Some("logd") => {
+                    sources.push(SourceType::Logd(logd::LogdSource::from_section(section)?))
+                }
+                Some("kmsg") => {
+                    sources.push(SourceType::Kmsg(kmsg::KmsgSource::from_section(section)?))
+                }
+                other => return Err(section.invalid("source", other.unwrap_or(""))),
+            }
+        }
+        for section in config.sections("tail") {
+            if section.get("enabled") == Some("false") {
+                continue;
+            }
+            sources.push(SourceType::Tail(tail::TailSource::from_section(section)?));
+        }
+        Ok(sources)
+    }
+}
+
+pub fn spawn_source(mut source: SourceType, sender: Sender) -> JoinHandle<()> {
+    thread::spawn(move || {
+        let mut delay = RESTART_DELAY_MIN;
+        loop {
+            let started = Instant::now();
+            match source.read_loop(&sender) {
+                Ok(()) => logw!(LOG_TAG, "[Source] {} input ended", source.name()),
+                Err(e) => loge!(LOG_TAG, "[Source] {} failed: {}", source.name(), e),
+            }
+            if sender.is_closed() {
+                logd!(
+                    LOG_TAG,
+                    "[Source] {} channel closed, exiting.",
+                    source.name()
+                );
+                break;
+            }
+            if source.is_finished() {
+                logi!(LOG_TAG, "[Source] {} finished", source.name());
+                break;
+            }
+            if started.elapsed() > RESTART_DELAY_MAX {
+                delay = RESTART_DELAY_MIN;
+            }
+            thread::sleep(delay);
+            delay = (delay * 2).min(RESTART_DELAY_MAX);
+        }
+    })
+}
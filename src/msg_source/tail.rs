@@ -0,0 +1,230 @@
+use crate::config::{ConfigError, Section};
+use crate::log::*;
+use crate::log_def::*;
+use crate::msg_proc::LogRecord;
+use crate::msg_sink::SinkTypeOrdinal;
+use crate::msg_source::MessageSource;
+use crate::record_queue::RecordSender as Sender;
+use nix::sys::inotify::{AddWatchFlags, InitFlags, Inotify};
+use regex::Regex;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Seek, SeekFrom};
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::{Path, PathBuf};
+
+/// Turns one text line into a record using an optional regex. Recognized named
+/// groups: `priority`, `tag`, `pid`, `message`, and the timestamp parts `year`,
+/// `month`, `day`, `hour`, `minute`, `second`, `millisecond`.
+pub struct LineParser {
+    pattern: Option<Regex>,
+    tag: String,
+    default_priority: LogPriority,
+}
+
+impl LineParser {
+    pub fn new(pattern: Option<Regex>, tag: &str, default_priority: LogPriority) -> Self {
+        LineParser {
+            pattern,
+            tag: tag.to_string(),
+            default_priority,
+        }
+    }
+
+    pub fn parse(&self, line: &str) -> LogMessage {
+        let now = LogTimeStamp::now();
+        let mut message = LogMessage {
+            pid: 0,
+            uid: None,
+            priority: self.default_priority,
+            timestamp: now.clone(),
+            tag: Some(self.tag.clone()),
+            message: line.to_string(),
+            fields: Vec::new(),
+            trace: None,
+        };
+        let Some(captures) = self.pattern.as_ref().and_then(|p| p.captures(line)) else {
+            return message;
+        };
+        let group = |name: &str| captures.name(name).map(|m| m.as_str());
+        let number = |name: &str| group(name).and_then(|v| v.parse::<u16>().ok());
+        if let Some(priority) = group("priority").and_then(|p| p.parse().ok()) {
+            message.priority = priority;
+        }
+        if let Some(tag) = group("tag") {
+            message.tag = Some(tag.trim().to_string());
+        }
+        if let Some(pid) = group("pid").and_then(|p| p.parse().ok()) {
+            message.pid = pid;
+        }
+        if let Some(text) = group("message") {
+            message.message = text.to_string();
+        }
+        if group("hour").is_some() {
+            message.timestamp = LogTimeStamp {
+                year: number("year").unwrap_or(now.year),
+                month: number("month").map_or(now.month, |v| v as u8),
+                day: number("day").map_or(now.day, |v| v as u8),
+                hour: number("hour").unwrap_or_default() as u8,
+                minute: number("minute").unwrap_or_default() as u8,
+                second: number("second").unwrap_or_default() as u8,
+                millisecond: number("millisecond").unwrap_or_default(),
+            };
+        }
+        message
+    }
+}
+
+/// Follows a text file across rotation and truncation, or drains a FIFO.
+pub struct TailSource {
+    path: PathBuf,
+    parser: LineParser,
+    from_start: bool,
+    sink_type: u8,
+}
+
+impl TailSource {
+    pub fn from_section(section: &Section) -> Result<Self, ConfigError> {
+        let path = section
+            .get("path")
+            .ok_or_else(|| section.invalid("path", ""))?;
+        let pattern = match section.get("pattern") {
+            Some(pattern) => {
+                Some(Regex::new(pattern).map_err(|_| section.invalid("pattern", pattern))?)
+            }
+            None => None,
+        };
+        let default_priority = match section.get("priority") {
+            Some(priority) => priority
+                .parse()
+                .map_err(|_| section.invalid("priority", priority))?,
+            None => LogPriority::Info,
+        };
+        let tag = section
+            .get("tag")
+            .or(section.name.as_deref())
+            .unwrap_or("tail");
+        Ok(TailSource {
+            path: PathBuf::from(path),
+            parser: LineParser::new(pattern, tag, default_priority),
+            from_start: section.get_parsed("from_start")?.unwrap_or(false),
+            sink_type: SinkTypeOrdinal::mask_from_section(
+                section,
+                SinkTypeOrdinal::LocalFileType as u8,
+            )?,
+        })
+    }
+
+    /// Forwards every complete line available from `reader`. A trailing partial
+    /// line stays in `pending` until its newline arrives. Returns false once the
+    /// channel is closed.
+    fn drain<R: BufRead>(
+        &self,
+        reader: &mut R,
+        pending: &mut Vec<u8>,
+        sender: &Sender,
+    ) -> io::Result<bool> {
+        loop {
+            if reader.read_until(b'\n', pending)? == 0 {
+                return Ok(true);
+            }
+            if pending.last() != Some(&b'\n') {
+                continue;
+            }
+            let line = String::from_utf8_lossy(pending);
+            let message = self.parser.parse(line.trim_end_matches(['\n', '\r']));
+            pending.clear();
+            let record = LogRecord::Ingested {
+                sink_type: self.sink_type,
+                message,
+            };
+            if sender.send(record).is_err() {
+                return Ok(false);
+            }
+        }
+    }
+
+    fn tail_fifo(&self, fifo: File, sender: &Sender) -> io::Result<()> {
+        let mut pending = Vec::new();
+        self.drain(&mut BufReader::new(fifo), &mut pending, sender)?;
+        Ok(())
+    }
+
+    fn tail_file(&self, mut file: File, sender: &Sender) -> io::Result<()> {
+        let file_name = self.path.file_name().unwrap_or_default().to_owned();
+        let dir = match self.path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        // watching the directory sees writes as well as the file being
+        // renamed, removed and recreated by a rotation
+        let inotify = Inotify::init(InitFlags::IN_CLOEXEC)?;
+        inotify.add_watch(
+            dir,
+            AddWatchFlags::IN_MODIFY
+                | AddWatchFlags::IN_CREATE
+                | AddWatchFlags::IN_MOVED_TO
+                | AddWatchFlags::IN_CLOSE_WRITE,
+        )?;
+        if !self.from_start {
+            file.seek(SeekFrom::End(0))?;
+        }
+        let mut inode = file.metadata()?.ino();
+        let mut reader = BufReader::new(file);
+        let mut pending = Vec::new();
+        loop {
+            if !self.drain(&mut reader, &mut pending, sender)? {
+                return Ok(());
+            }
+            let events = inotify.read_events()?;
+            if !events
+                .iter()
+                .any(|ev| ev.name.as_deref() == Some(file_name.as_os_str()))
+            {
+                continue;
+            }
+            match std::fs::metadata(&self.path) {
+                Ok(metadata) if metadata.ino() != inode => {
+                    // rotated: finish the old file, then follow the new one
+                    if !self.drain(&mut reader, &mut pending, sender)? {
+                        return Ok(());
+                    }
+                    logi!(
+                        LOG_TAG,
+                        "[TailSource] {} rotated, reopening",
+                        self.path.display()
+                    );
+                    reader = BufReader::new(File::open(&self.path)?);
+                    inode = metadata.ino();
+                    pending.clear();
+                }
+                Ok(metadata) if metadata.len() < reader.stream_position()? => {
+                    logi!(
+                        LOG_TAG,
+                        "[TailSource] {} truncated, rewinding",
+                        self.path.display()
+                    );
+                    reader.seek(SeekFrom::Start(0))?;
+                    pending.clear();
+                }
+                Ok(_) => (),
+                // removed and not recreated yet, wait for IN_CREATE
+                Err(_) => (),
+            }
+        }
+    }
+}
+
+impl MessageSource for TailSource {
+    fn name(&self) -> &'static str {
+        "tail"
+    }
+
+    fn read_loop(&mut self, sender: &Sender) -> io::Result<()> {
+        let file = File::open(&self.path)?;
+        if file.metadata()?.file_type().is_fifo() {
+            self.tail_fifo(file, sender)
+        } else {
+            self.tail_file(file, sender)
+        }
+    }
+}
@@ -1,18 +1,27 @@
-use crate::{log::*, log_def::*, prot_handler::*};
+use crate::{
+    config::{Config, ConfigError},
+    conn_table::{CloseReason, ConnectionLimits, ConnectionTable},
+    log::*,
+    log_def::*,
+    policy::SocketPolicy,
+    prot_handler::*,
+};
 use nix::{
     fcntl::{FcntlArg, FdFlag, OFlag, fcntl},
     sys::epoll::*,
-    sys::socket::{MsgFlags, accept, listen, recv},
-    unistd::close,
+    sys::socket::{MsgFlags, accept, getsockopt, listen, recv, sockopt::PeerCredentials},
 };
 use rustutils::sockets::SocketError;
 use rustutils::sockets::android_get_control_socket;
 use std::{
+    collections::HashMap,
     io,
     os::fd::AsFd,
     os::fd::BorrowedFd,
     os::unix::io::RawFd,
+    sync::Arc,
     thread::{self, JoinHandle},
+    time::{Duration, Instant},
 };
 
 pub trait MessageServer<L, H> {
@@ -22,6 +31,7 @@ pub trait MessageServer<L, H> {
 pub struct EpollServer;
 
 static SOCKET_NAME: &str = "notcat_socket";
+static SYSTEM_SOCKET_NAME: &str = "notcat_socket_sys";
 static MAX_CLIENTS_QUEUE: usize = 16;
 
 struct FdWrapper(RawFd);
@@ -38,23 +48,117 @@ impl AsFd for FdWrapper {
     }
 }
 
+pub struct ServerConfig {
+    pub limits: ConnectionLimits,
+    pub sockets: Vec<SocketPolicy>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            limits: ConnectionLimits::default(),
+            sockets: vec![
+                SocketPolicy::restricted(SOCKET_NAME),
+                SocketPolicy::privileged(SYSTEM_SOCKET_NAME),
+            ],
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Reads the `[server]` section and every `[socket <name>]` section. When no
+    /// socket is configured, the default socket pair is served.
+    pub fn from_config(config: &Config) -> Result<Self, ConfigError> {
+        let mut server_config = ServerConfig::default();
+        if let Some(section) = config.section("server") {
+            let limits = &mut server_config.limits;
+            if let Some(max_clients) = section.get_parsed("max_clients")? {
+                limits.max_clients = max_clients;
+            }
+            if let Some(ms) = section.get_parsed("handshake_timeout_ms")? {
+                limits.handshake_timeout = Duration::from_millis(ms);
+            }
+            if let Some(secs) = section.get_parsed::<u64>("idle_timeout_s")? {
+                limits.idle_timeout = if secs == 0 {
+                    None
+                } else {
+                    Some(Duration::from_secs(secs))
+                };
+            }
+        }
+        let sockets = config
+            .sections("socket")
+            .map(SocketPolicy::from_section)
+            .collect::<Result<Vec<_>, _>>()?;
+        if !sockets.is_empty() {
+            server_config.sockets = sockets;
+        }
+        Ok(server_config)
+    }
+}
+
 impl MessageServer<ProtocolHandler, JoinHandle<io::Result<()>>> for EpollServer {
-    fn run(mut prot_handler: ProtocolHandler) -> io::Result<JoinHandle<io::Result<()>>> {
+    fn run(prot_handler: ProtocolHandler) -> io::Result<JoinHandle<io::Result<()>>> {
+        EpollServer::run_with_config(prot_handler, ServerConfig::default())
+    }
+}
+
+impl EpollServer {
+    pub fn run_with_config(
+        mut prot_handler: ProtocolHandler,
+        config: ServerConfig,
+    ) -> io::Result<JoinHandle<io::Result<()>>> {
         logv!(LOG_TAG, "[EpollServer] Starting...");
 
-        let listener_fd = init_socket_fd()?;
+        let epfd = init_epoll_fd()?;
 
-        let epfd = init_epoll_fd(&listener_fd)?;
+        let mut listeners: HashMap<RawFd, Arc<SocketPolicy>> = HashMap::new();
+        for policy in config.sockets {
+            match init_socket_fd(&policy.socket).and_then(|fd| add_listener_fd(epfd, fd)) {
+                Ok(fd) => {
+                    logi!(
+                        LOG_TAG,
+                        "[EpollServer] Serving socket {} (sinks={:#x}, max_priority={:?}, rate_limit={:?})",
+                        policy.socket,
+                        policy.sink_mask,
+                        policy.max_priority,
+                        policy.rate_limit
+                    );
+                    listeners.insert(fd, Arc::new(policy));
+                }
+                Err(e) => {
+                    loge!(
+                        LOG_TAG,
+                        "[EpollServer] Error setting up socket {}: {}",
+                        policy.socket,
+                        e
+                    );
+                }
+            }
+        }
+        if listeners.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "no listening socket available",
+            ));
+        }
 
         let handle = thread::spawn(move || {
             let mut events = vec![EpollEvent::empty(); 16];
+            let mut conn_table = ConnectionTable::new(epfd, config.limits);
             logv!(LOG_TAG, "[EpollServer] Starting...OK");
             loop {
-                // Wait for events
-                let nfds = epoll_wait(epfd, &mut events, -1)?;
+                // Wait for events or the nearest connection deadline
+                let timeout = conn_table.next_timeout(Instant::now());
+                let nfds = match epoll_wait(epfd, &mut events, timeout) {
+                    Ok(n) => n,
+                    Err(nix::errno::Errno::EINTR) => 0,
+                    Err(e) => return Err(e.into()),
+                };
                 for ev in &events[..nfds] {
                     let fd = ev.data() as RawFd;
-                    if fd == listener_fd {
+                    if let Some(policy) = listeners.get(&fd) {
+                        let listener_fd = fd;
                         loop {
                             match accept(listener_fd) {
                                 Ok(client_fd) => {
@@ -64,28 +168,13 @@ impl MessageServer<ProtocolHandler, JoinHandle<io::Result<()>>> for EpollServer
                                         client_fd
                                     );
                                     fcntl(client_fd, FcntlArg::F_SETFL(OFlag::O_NONBLOCK))?;
-                                    let mut ev = EpollEvent::new(
-                                        EpollFlags::EPOLLIN | EpollFlags::EPOLLET,
-                                        client_fd as u64,
-                                    );
-                                    match epoll_ctl(epfd, EpollOp::EpollCtlAdd, client_fd, &mut ev)
-                                    {
-                                        Ok(_) => {
-                                            logv!(
-                                                LOG_TAG,
-                                                "[EpollServer] Added client {}",
-                                                client_fd
-                                            );
-                                        }
-                                        Err(e) => {
-                                            loge!(
-                                                LOG_TAG,
-                                                "[EpollServer] Error adding client to epoll: {}",
-                                                e
-                                            );
-                                            break;
-                                        }
-                                    };
+                                    if conn_table.accept(client_fd, Instant::now()) {
+                                        let uid =
+                                            getsockopt(&FdWrapper::new(client_fd), PeerCredentials)
+                                                .map(|cred| cred.uid())
+                                                .ok();
+                                        prot_handler.add_fd(client_fd, policy.clone(), uid);
+                                    }
                                 }
                                 Err(nix::errno::Errno::EAGAIN) => break,
                                 Err(e) => {
@@ -101,26 +190,24 @@ impl MessageServer<ProtocolHandler, JoinHandle<io::Result<()>>> for EpollServer
                             match recv(fd, &mut buf, MsgFlags::MSG_DONTWAIT) {
                                 Ok(0) => {
                                     logv!(LOG_TAG, "[EpollServer] Client {} disconnected", fd);
-                                    epoll_ctl(epfd, EpollOp::EpollCtlDel, fd, None)?;
-                                    prot_handler.remove_fd(fd);
-                                    match close(fd) {
-                                        Ok(_) => {
-                                            logv!(LOG_TAG, "[EpollServer] Closed client {}", fd)
-                                        }
-                                        Err(e) => loge!(
-                                            LOG_TAG,
-                                            "[EpollServer] Error closing client {}: {}",
-                                            fd,
-                                            e
-                                        ),
-                                    }
+                                    conn_table.close(
+                                        fd,
+                                        CloseReason::Disconnected,
+                                        &mut prot_handler,
+                                    );
                                     break;
                                 }
                                 Ok(n) => {
                                     input_buffer.extend_from_slice(&buf[..n]);
                                 }
                                 Err(nix::errno::Errno::EAGAIN) => {
-                                    if let Err(e) = prot_handler.process_buffer(fd, &input_buffer) {
+                                    let result = prot_handler.process_buffer(fd, &input_buffer);
+                                    conn_table.touch(
+                                        fd,
+                                        Instant::now(),
+                                        prot_handler.is_registered(fd),
+                                    );
+                                    if let Err(e) = result {
                                         loge!(
                                             LOG_TAG,
                                             "[EpollServer] Error processing buffer for client {}: {:?}",
@@ -128,30 +215,13 @@ impl MessageServer<ProtocolHandler, JoinHandle<io::Result<()>>> for EpollServer
                                             e
                                         );
                                         match e {
-                                            ClientError::InternalError => {
-                                                break;
-                                            }
-                                            ClientError::IncorrectMessageSize(_) => {
-                                                break;
-                                            }
-                                            _ => {
-                                                epoll_ctl(epfd, EpollOp::EpollCtlDel, fd, None)?;
-                                                match close(fd) {
-                                                    Ok(_) => {
-                                                        logv!(
-                                                            LOG_TAG,
-                                                            "[EpollServer] Closed client {}",
-                                                            fd
-                                                        )
-                                                    }
-                                                    Err(e) => loge!(
-                                                        LOG_TAG,
-                                                        "[EpollServer] Error closing client {}: {}",
-                                                        fd,
-                                                        e
-                                                    ),
-                                                }
-                                            }
+                                            ClientError::InternalError => {}
+                                            ClientError::IncorrectMessageSize(_) => {}
+                                            _ => conn_table.close(
+                                                fd,
+                                                CloseReason::ProtocolError,
+                                                &mut prot_handler,
+                                            ),
                                         }
                                     }
                                     break;
@@ -163,43 +233,20 @@ impl MessageServer<ProtocolHandler, JoinHandle<io::Result<()>>> for EpollServer
                                         fd,
                                         e
                                     );
-                                    epoll_ctl(epfd, EpollOp::EpollCtlDel, fd, None)?;
-                                    prot_handler.remove_fd(fd);
-                                    match close(fd) {
-                                        Ok(_) => {
-                                            logv!(LOG_TAG, "[EpollServer] Closed client {}", fd)
-                                        }
-                                        Err(e) => loge!(
-                                            LOG_TAG,
-                                            "[EpollServer] Error closing client {}: {}",
-                                            fd,
-                                            e
-                                        ),
-                                    }
+                                    conn_table.close(fd, CloseReason::ReadError, &mut prot_handler);
                                     break;
                                 }
                             }
                         }
                     } else if ev
                         .events()
-                        .contains(EpollFlags::EPOLLHUP | EpollFlags::EPOLLERR)
+                        .intersects(EpollFlags::EPOLLHUP | EpollFlags::EPOLLERR)
                     {
                         logw!(LOG_TAG, "[EpollServer] Client {} hung up or error", fd);
-                        prot_handler.remove_fd(fd);
-                        match epoll_ctl(epfd, EpollOp::EpollCtlDel, fd, None) {
-                            Ok(_) => logv!(LOG_TAG, "[EpollServer] Removed client {}", fd),
-                            Err(e) => {
-                                loge!(LOG_TAG, "[EpollServer] Error removing client {}: {}", fd, e)
-                            }
-                        }
-                        match close(fd) {
-                            Ok(_) => logv!(LOG_TAG, "[EpollServer] Closed client {}", fd),
-                            Err(e) => {
-                                loge!(LOG_TAG, "[EpollServer] Error closing client {}: {}", fd, e)
-                            }
-                        }
+                        conn_table.close(fd, CloseReason::HungUp, &mut prot_handler);
                     }
                 }
+                conn_table.expire(Instant::now(), &mut prot_handler);
             }
         });
 
@@ -207,8 +254,9 @@ impl MessageServer<ProtocolHandler, JoinHandle<io::Result<()>>> for EpollServer
     }
 }
 
-fn init_socket_fd() -> io::Result<RawFd> {
-    let listener_fd = FdWrapper::new(android_get_control_socket(SOCKET_NAME).unwrap_or_else(|e| {
+/// Fetches an init-created socket by name and starts listening on it.
+pub(crate) fn init_listener_fd(name: &str) -> io::Result<RawFd> {
+    let listener_fd = FdWrapper::new(android_get_control_socket(name).unwrap_or_else(|e| {
         match e {
             SocketError::NulError(name) => {
                 loge!(
@@ -256,10 +304,15 @@ fn init_socket_fd() -> io::Result<RawFd> {
             return Err(e.into());
         }
     }
+    Ok(listener_fd.0)
+}
+
+fn init_socket_fd(name: &str) -> io::Result<RawFd> {
+    let listener_fd = init_listener_fd(name)?;
 
-    match fcntl(listener_fd.0, FcntlArg::F_SETFL(OFlag::O_NONBLOCK)) {
+    match fcntl(listener_fd, FcntlArg::F_SETFL(OFlag::O_NONBLOCK)) {
         Ok(_) => {
-            return Ok(listener_fd.0);
+            return Ok(listener_fd);
         }
         Err(e) => {
             loge!(
@@ -272,17 +325,20 @@ fn init_socket_fd() -> io::Result<RawFd> {
     }
 }
 
-fn init_epoll_fd(fd: &RawFd) -> io::Result<RawFd> {
-    let epfd = match epoll_create1(EpollCreateFlags::empty()) {
-        Ok(fd) => fd,
+fn init_epoll_fd() -> io::Result<RawFd> {
+    match epoll_create1(EpollCreateFlags::empty()) {
+        Ok(fd) => Ok(fd),
         Err(e) => {
             logf!(LOG_TAG, "[EpollServer] Error creating epoll fd: {}", e);
-            return Err(e.into());
+            Err(e.into())
         }
-    };
-    let mut event = EpollEvent::new(EpollFlags::EPOLLIN | EpollFlags::EPOLLET, *fd as u64);
-    match epoll_ctl(epfd, EpollOp::EpollCtlAdd, *fd, &mut event) {
-        Ok(_) => Ok(epfd),
+    }
+}
+
+fn add_listener_fd(epfd: RawFd, fd: RawFd) -> io::Result<RawFd> {
+    let mut event = EpollEvent::new(EpollFlags::EPOLLIN | EpollFlags::EPOLLET, fd as u64);
+    match epoll_ctl(epfd, EpollOp::EpollCtlAdd, fd, &mut event) {
+        Ok(_) => Ok(fd),
         Err(e) => {
             logf!(
                 LOG_TAG,
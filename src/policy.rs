@@ -0,0 +1,267 @@
+use crate::{
+    config::{ConfigError, Section},
+    log_def::*,
+    msg_sink::SinkTypeOrdinal,
+};
+use std::{
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+static RESTRICTED_RATE_LIMIT: u32 = 500; // messages per second
+static DEFAULT_SKEW_THRESHOLD: Duration = Duration::from_secs(2);
+static SKEW_ALPHA: f64 = 0.1;
+/// Consecutive deviating samples after which the client clock is taken to
+/// have stepped rather than a few messages being wrong.
+static SKEW_STEP_SAMPLES: u32 = 3;
+
+/// What to do with a client message whose priority or timestamp is invalid.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MalformedPolicy {
+    /// Drop the message.
+    Reject,
+    /// Clamp every field into its valid range.
+    Clamp,
+    /// Clamp the priority and replace a bad timestamp with the server time.
+    ServerTime,
+}
+
+impl FromStr for MalformedPolicy {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject" => Ok(MalformedPolicy::Reject),
+            "clamp" => Ok(MalformedPolicy::Clamp),
+            "server_time" => Ok(MalformedPolicy::ServerTime),
+            _ => Err(()),
+        }
+    }
+}
+
+/// What to do with client timestamps too far from the receive time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SkewPolicy {
+    /// Keep client timestamps as they are.
+    Off,
+    /// Keep them, but record the offset in the message.
+    Flag,
+    /// Shift them by the client's estimated offset, and record it.
+    Correct,
+}
+
+impl FromStr for SkewPolicy {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(SkewPolicy::Off),
+            "flag" => Ok(SkewPolicy::Flag),
+            "correct" => Ok(SkewPolicy::Correct),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Trust level of a listening socket, inherited by every client accepted on it.
+#[derive(Debug, Clone)]
+pub struct SocketPolicy {
+    pub socket: String,
+    pub sink_mask: u8,
+    pub max_priority: LogPriority,
+    pub rate_limit: Option<u32>,
+    pub malformed: MalformedPolicy,
+    pub clock_skew: SkewPolicy,
+    pub skew_threshold: Duration,
+}
+
+impl SocketPolicy {
+    pub fn privileged(socket: &str) -> Self {
+        SocketPolicy {
+            socket: socket.to_string(),
+            sink_mask: u8::MAX,
+            max_priority: LogPriority::Fatal,
+            rate_limit: None,
+            malformed: MalformedPolicy::Clamp,
+            clock_skew: SkewPolicy::Flag,
+            skew_threshold: DEFAULT_SKEW_THRESHOLD,
+        }
+    }
+
+    pub fn restricted(socket: &str) -> Self {
+        SocketPolicy {
+            socket: socket.to_string(),
+            sink_mask: u8::MAX,
+            max_priority: LogPriority::Error,
+            rate_limit: Some(RESTRICTED_RATE_LIMIT),
+            malformed: MalformedPolicy::Clamp,
+            clock_skew: SkewPolicy::Flag,
+            skew_threshold: DEFAULT_SKEW_THRESHOLD,
+        }
+    }
+
+    /// Builds a policy from a `[socket <name>]` section. `trust` selects the
+    /// defaults, the remaining keys override them.
+    pub fn from_section(section: &Section) -> Result<Self, ConfigError> {
+        let name = section
+            .name
+            .as_deref()
+            .ok_or_else(|| section.invalid("name", ""))?;
+        let mut policy = match section.get("trust") {
+            None | Some("restricted") => SocketPolicy::restricted(name),
+            Some("privileged") => SocketPolicy::privileged(name),
+            Some(other) => return Err(section.invalid("trust", other)),
+        };
+        policy.sink_mask = SinkTypeOrdinal::mask_from_section(section, policy.sink_mask)?;
+        if let Some(priority) = section.get("max_priority") {
+            policy.max_priority = priority
+                .parse()
+                .map_err(|_| section.invalid("max_priority", priority))?;
+        }
+        if let Some(rate) = section.get_parsed::<u32>("rate_limit")? {
+            policy.rate_limit = if rate == 0 { None } else { Some(rate) };
+        }
+        if let Some(malformed) = section.get("malformed") {
+            policy.malformed = malformed
+                .parse()
+                .map_err(|_| section.invalid("malformed", malformed))?;
+        }
+        if let Some(skew) = section.get("clock_skew") {
+            policy.clock_skew = skew
+                .parse()
+                .map_err(|_| section.invalid("clock_skew", skew))?;
+        }
+        if let Some(ms) = section.get_parsed::<u64>("skew_threshold_ms")? {
+            policy.skew_threshold = Duration::from_millis(ms);
+        }
+        Ok(policy)
+    }
+}
+
+/// Token bucket allowing `rate` messages per second with a burst of the same size.
+pub struct RateLimiter {
+    rate: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(rate: u32) -> Self {
+        RateLimiter {
+            rate: rate as f64,
+            tokens: rate as f64,
+            last: Instant::now(),
+        }
+    }
+
+    pub fn allow(&mut self, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.last = now;
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Verdict on one client timestamp.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SkewCheck {
+    Ok,
+    /// The client clock is consistently off by this many milliseconds.
+    Skewed(i64),
+    /// This timestamp alone is off by this many milliseconds.
+    Outlier(i64),
+}
+
+/// Moving estimate of the offset between a client's clock and ours.
+pub struct SkewEstimator {
+    threshold_ms: f64,
+    estimate_ms: Option<f64>,
+    deviations: u32,
+}
+
+impl SkewEstimator {
+    pub fn new(threshold: Duration) -> Self {
+        SkewEstimator {
+            threshold_ms: threshold.as_millis() as f64,
+            estimate_ms: None,
+            deviations: 0,
+        }
+    }
+
+    /// Folds in `offset_ms`, client time minus receive time, unless it is an
+    /// outlier.
+    pub fn check(&mut self, offset_ms: i64) -> SkewCheck {
+        let offset = offset_ms as f64;
+        let estimate = *self.estimate_ms.get_or_insert(offset);
+        if (offset - estimate).abs() > self.threshold_ms {
+            self.deviations += 1;
+            if self.deviations < SKEW_STEP_SAMPLES {
+                return SkewCheck::Outlier(offset_ms);
+            }
+            self.estimate_ms = Some(offset);
+        } else {
+            self.estimate_ms = Some(estimate + (offset - estimate) * SKEW_ALPHA);
+        }
+        self.deviations = 0;
+        let estimate = self.estimate_ms.unwrap_or(offset);
+        if estimate.abs() > self.threshold_ms {
+            SkewCheck::Skewed(estimate.round() as i64)
+        } else {
+            SkewCheck::Ok
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn estimator() -> SkewEstimator {
+        SkewEstimator::new(Duration::from_secs(2))
+    }
+
+    #[test]
+    fn steady_state() {
+        let mut skew = estimator();
+        for offset in [0, 150, -300, 1900, 40] {
+            assert_eq!(skew.check(offset), SkewCheck::Ok);
+        }
+        let mut skewed = estimator();
+        assert_eq!(skewed.check(5000), SkewCheck::Skewed(5000));
+        // small jitter moves the estimate slowly
+        assert_eq!(skewed.check(6000), SkewCheck::Skewed(5100));
+        assert_eq!(skewed.check(5100), SkewCheck::Skewed(5100));
+    }
+
+    #[test]
+    fn lone_outlier_keeps_estimate() {
+        let mut skew = estimator();
+        assert_eq!(skew.check(100), SkewCheck::Ok);
+        assert_eq!(skew.check(3_600_000), SkewCheck::Outlier(3_600_000));
+        assert_eq!(skew.check(-10_000), SkewCheck::Outlier(-10_000));
+        assert_eq!(skew.check(100), SkewCheck::Ok);
+        // the deviation count was reset by the good sample
+        assert_eq!(skew.check(3_600_000), SkewCheck::Outlier(3_600_000));
+    }
+
+    #[test]
+    fn clock_step_is_adopted() {
+        let mut skew = estimator();
+        assert_eq!(skew.check(0), SkewCheck::Ok);
+        for _ in 1..SKEW_STEP_SAMPLES {
+            assert_eq!(skew.check(60_000), SkewCheck::Outlier(60_000));
+        }
+        assert_eq!(skew.check(60_000), SkewCheck::Skewed(60_000));
+        assert_eq!(skew.check(60_000), SkewCheck::Skewed(60_000));
+        // and stepping back is again a step, not a drift
+        for _ in 1..SKEW_STEP_SAMPLES {
+            assert_eq!(skew.check(0), SkewCheck::Outlier(0));
+        }
+        assert_eq!(skew.check(0), SkewCheck::Ok);
+    }
+}
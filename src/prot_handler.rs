@@ -1,8 +1,19 @@
-use crate::{SinkType, log::*, log_def::*};
+use crate::record_queue::RecordSender as Sender;
+use crate::{
+    SinkType,
+    conn_table::CloseReason,
+    log::*,
+    log_def::*,
+    msg_proc::LogRecord,
+    policy::{MalformedPolicy, RateLimiter, SkewCheck, SkewEstimator, SkewPolicy, SocketPolicy},
+    snapshot::SnapshotTrigger,
+    stats::{self, ClientStatsHandle},
+};
 use std::collections::HashMap;
 use std::convert::TryInto;
+use std::sync::{Arc, atomic::Ordering};
+use std::time::Instant;
 use thiserror::Error;
-use tokio::sync::mpsc::UnboundedSender as Sender;
 
 #[derive(Error, Debug, PartialEq)]
 pub enum ClientError {
@@ -16,55 +27,139 @@ pub enum ClientError {
     IncorrectMessageSize(usize),
     #[error("Client is already connected")]
     ClientAlreadyConnected,
+    #[error("Sink type not permitted on this socket: {0}")]
+    SinkNotPermitted(u8),
     #[error("Internal error occurred")]
     InternalError,
 }
 
+impl ClientError {
+    /// Variant name, used as a metrics label.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ClientError::IncorrectMagic(_) => "incorrect_magic",
+            ClientError::IncorrectVersion(_) => "incorrect_version",
+            ClientError::IncorrectHeaderSize(_) => "incorrect_header_size",
+            ClientError::IncorrectMessageSize(_) => "incorrect_message_size",
+            ClientError::ClientAlreadyConnected => "client_already_connected",
+            ClientError::SinkNotPermitted(_) => "sink_not_permitted",
+            ClientError::InternalError => "internal_error",
+        }
+    }
+}
+
+#[derive(Error, Debug, PartialEq)]
+pub enum ConversionError {
+    #[error("Unknown priority: {0}")]
+    UnknownPriority(u8),
+    #[error("Incorrect timestamp size: {0}")]
+    IncorrectTimestampSize(usize),
+    #[error("Invalid timestamp {0}: {1}")]
+    InvalidTimestamp(&'static str, u16),
+}
+
 #[allow(dead_code)]
 pub struct ProtocolHandler {
     fds_pids: HashMap<i32, ClientData>,
-    sender_channel: Sender<LogPacket>,
+    fds_peers: HashMap<i32, PeerData>,
+    sender_channel: Sender,
+    snapshots: Option<SnapshotTrigger>,
 }
 
 pub struct LogPacket {
     pub pid: u32,
+    pub uid: Option<u32>,
     pub version: u8,
     pub sink_type: u8,
     pub priority: u8,
     pub timestamp: Vec<u8>, // 9 bytes for timestamp
+    pub trace: Vec<u8>,     // 25 bytes of trace context from version 2 on
     pub message: Vec<u8>,
 }
 
+/// What is known about a connection before its handshake.
+struct PeerData {
+    policy: Arc<SocketPolicy>,
+    uid: Option<u32>,
+}
+
 struct ClientData {
     version: u8,
     pid: u32,
+    uid: Option<u32>,
     sink_type: u8,
+    stats: ClientStatsHandle,
+    policy: Arc<SocketPolicy>,
+    rate_limiter: Option<RateLimiter>,
+    skew: Option<SkewEstimator>,
 }
 
 static CONN_MAGIC: u32 = 0xb05acafe;
 
-static CURRENT_VERSION: u8 = 1;
+static CURRENT_VERSION: u8 = 2;
 
 static VERSION_1_HSH_SZ: usize = 10; // 4 bytes for magic, 1 byte for version, 4 bytes for pid, 1 byte for sink type
 static VERSION_1_MSG_SZ: usize = 14; // 4 bytes for message size, 1 byte for priority, 9 bytes for timestamp
+static VERSION_2_MSG_SZ: usize = 39; // version 1 header, 16 bytes for trace id, 8 bytes for span id, 1 byte for trace flags
 
 impl ProtocolHandler {
-    pub fn new(sender: Sender<LogPacket>) -> Self {
+    pub fn new(sender: Sender) -> Self {
         ProtocolHandler {
             fds_pids: HashMap::new(),
+            fds_peers: HashMap::new(),
             sender_channel: sender,
+            snapshots: None,
+        }
+    }
+
+    /// Abnormal disconnects and Fatal messages of registered clients will
+    /// request a snapshot.
+    pub fn set_snapshot_trigger(&mut self, snapshots: Option<SnapshotTrigger>) {
+        self.snapshots = snapshots;
+    }
+
+    pub fn report_abnormal_close(&self, fd: i32, reason: CloseReason) {
+        if let (Some(snapshots), Some(client_data)) = (&self.snapshots, self.fds_pids.get(&fd)) {
+            snapshots.request(
+                format!("pid {} disconnected: {:?}", client_data.pid, reason),
+                Some(client_data.pid),
+            );
         }
     }
 
     pub fn process_buffer(&mut self, fd: i32, buffer: &[u8]) -> Result<(), ClientError> {
+        let result = self.parse_buffer(fd, buffer);
+        if let Err(ref e) = result {
+            stats::global().add_client_error(e.kind());
+            match self.fds_pids.get(&fd) {
+                Some(client_data) if *e == ClientError::InternalError => {
+                    client_data.stats.add_drop()
+                }
+                Some(client_data) => client_data.stats.add_protocol_error(),
+                None => {
+                    stats::global()
+                        .total
+                        .protocol_errors
+                        .fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+        result
+    }
+
+    fn parse_buffer(&mut self, fd: i32, buffer: &[u8]) -> Result<(), ClientError> {
         let buffer_len = buffer.len();
         let mut buffer_ptr: usize = 0;
         loop {
             if buffer_ptr >= buffer_len {
                 break Ok(());
             }
-            if self.fds_pids.contains_key(&fd) {
-                if buffer_len - buffer_ptr < VERSION_1_MSG_SZ {
+            if let Some(client_data) = self.fds_pids.get(&fd) {
+                let header_size = match client_data.version {
+                    1 => VERSION_1_MSG_SZ,
+                    _ => VERSION_2_MSG_SZ,
+                };
+                if buffer_len - buffer_ptr < header_size {
                     return Err(ClientError::IncorrectMessageSize(buffer_len - buffer_ptr));
                 }
                 let msg_size =
@@ -74,26 +169,79 @@ impl ProtocolHandler {
                 let client_priority =
                     u8::from_be_bytes(buffer[buffer_ptr..buffer_ptr + 1].try_into().unwrap());
                 buffer_ptr += 1;
-                let client_timestamp = buffer[buffer_ptr..buffer_ptr + 9].to_vec();
+                let client_timestamp = &buffer[buffer_ptr..buffer_ptr + 9];
                 buffer_ptr += 9;
+                let client_trace = &buffer[buffer_ptr..buffer_ptr + header_size - VERSION_1_MSG_SZ];
+                buffer_ptr += client_trace.len();
                 if buffer_len - buffer_ptr < msg_size {
                     return Err(ClientError::IncorrectMessageSize(buffer_len - buffer_ptr));
                 }
-                let client_data = self.fds_pids.get(&fd).unwrap();
+                let client_data = self.fds_pids.get_mut(&fd).unwrap();
+                if let Some(limiter) = client_data.rate_limiter.as_mut() {
+                    if !limiter.allow(Instant::now()) {
+                        client_data.stats.add_drop();
+                        buffer_ptr += msg_size;
+                        continue;
+                    }
+                }
+                let make_packet = |priority: u8, timestamp: Vec<u8>| LogPacket {
+                    pid: client_data.pid,
+                    uid: client_data.uid,
+                    version: client_data.version,
+                    sink_type: client_data.sink_type,
+                    priority,
+                    timestamp,
+                    trace: client_trace.to_vec(),
+                    message: buffer[buffer_ptr..buffer_ptr + msg_size].to_vec(),
+                };
+                let packet = make_packet(client_priority, client_timestamp.to_vec());
+                let sink_type = packet.sink_type;
+                let message = match LogMessage::try_from(packet) {
+                    Ok(message) => Ok(message),
+                    Err(e) => {
+                        client_data.stats.add_malformed();
+                        logv!(
+                            LOG_TAG,
+                            "[ProtocolHandler] Malformed message from pid {}: {}",
+                            client_data.pid,
+                            e
+                        );
+                        match client_data.policy.malformed {
+                            MalformedPolicy::Reject => Err(e),
+                            policy => LogMessage::try_from(make_packet(
+                                repair_priority(client_priority),
+                                repair_timestamp(client_timestamp, policy),
+                            )),
+                        }
+                    }
+                };
+                let Ok(mut message) = message else {
+                    buffer_ptr += msg_size;
+                    continue;
+                };
+                message.priority = message.priority.min(client_data.policy.max_priority);
+                if let Some(skew) = client_data.skew.as_mut() {
+                    apply_skew(&mut message, skew, client_data.policy.clock_skew);
+                }
+                // before the pipeline, which may well filter the message out
+                if let Some(snapshots) = self
+                    .snapshots
+                    .as_ref()
+                    .filter(|_| message.priority == LogPriority::Fatal)
+                {
+                    snapshots.request(
+                        format!("Fatal message from pid {}", message.pid),
+                        Some(message.pid),
+                    );
+                }
                 if self
                     .sender_channel
-                    .send(LogPacket {
-                        pid: client_data.pid,
-                        version: client_data.version,
-                        sink_type: client_data.sink_type,
-                        priority: client_priority,
-                        timestamp: client_timestamp,
-                        message: buffer[buffer_ptr..buffer_ptr + msg_size].to_vec(),
-                    })
+                    .send(LogRecord::Client { sink_type, message })
                     .is_err()
                 {
                     return Err(ClientError::InternalError);
                 }
+                client_data.stats.add_message(msg_size);
                 buffer_ptr += msg_size;
             } else {
                 if buffer.len() < VERSION_1_HSH_SZ {
@@ -104,7 +252,7 @@ impl ProtocolHandler {
                     return Err(ClientError::IncorrectMagic(magic));
                 }
                 let version = u8::from_be_bytes(buffer[4..5].try_into().unwrap());
-                if version != CURRENT_VERSION {
+                if version == 0 || version > CURRENT_VERSION {
                     return Err(ClientError::IncorrectVersion(version));
                 }
                 let pid = u32::from_be_bytes(buffer[5..9].try_into().unwrap());
@@ -113,13 +261,35 @@ impl ProtocolHandler {
                         return Err(ClientError::ClientAlreadyConnected);
                     }
                 }
-                let sink_type = u8::from_be_bytes(buffer[9..10].try_into().unwrap());
+                let requested_sink_type = u8::from_be_bytes(buffer[9..10].try_into().unwrap());
+                let peer = self.fds_peers.get(&fd).ok_or(ClientError::InternalError)?;
+                let (policy, uid) = (peer.policy.clone(), peer.uid);
+                let sink_type = requested_sink_type & policy.sink_mask;
+                if sink_type == 0 {
+                    return Err(ClientError::SinkNotPermitted(requested_sink_type));
+                }
+                if sink_type != requested_sink_type {
+                    logw!(
+                        LOG_TAG,
+                        "[ProtocolHandler] Sink type {:#x} of pid {} limited to {:#x} by socket {}",
+                        requested_sink_type,
+                        pid,
+                        sink_type,
+                        policy.socket
+                    );
+                }
                 self.fds_pids.insert(
                     fd,
                     ClientData {
                         version,
                         pid,
+                        uid,
                         sink_type,
+                        stats: stats::global().register_client(fd, pid),
+                        rate_limiter: policy.rate_limit.map(RateLimiter::new),
+                        skew: (policy.clock_skew != SkewPolicy::Off)
+                            .then(|| SkewEstimator::new(policy.skew_threshold)),
+                        policy,
                     },
                 );
                 logd!(
@@ -133,7 +303,184 @@ impl ProtocolHandler {
         }
     }
 
+    /// Binds a freshly accepted client to the policy of the socket it came from
+    /// and to its peer uid, when the kernel reported one.
+    pub fn add_fd(&mut self, fd: i32, policy: Arc<SocketPolicy>, uid: Option<u32>) {
+        self.fds_peers.insert(fd, PeerData { policy, uid });
+    }
+
+    pub fn is_registered(&self, fd: i32) -> bool {
+        self.fds_pids.contains_key(&fd)
+    }
+
     pub fn remove_fd(&mut self, fd: i32) {
-        self.fds_pids.remove(&fd);
+        self.fds_peers.remove(&fd);
+        if self.fds_pids.remove(&fd).is_some() {
+            stats::global().remove_client(fd);
+        }
+    }
+}
+
+/// Compares the client timestamp with the receive time. When the client clock
+/// is off, or this timestamp alone is, the offset is recorded in the
+/// `clock_skew` field (`clock_skew_corrected` with `SkewPolicy::Correct`, which
+/// also fixes the timestamp: shifted by the client's estimated offset, or
+/// replaced by the receive time for a lone outlier). The text is untouched. Both times are compared on the local wall-clock scale, so no
+/// timezone lookup happens per message.
+fn apply_skew(message: &mut LogMessage, skew: &mut SkewEstimator, policy: SkewPolicy) {
+    let received = wall_clock_ms();
+    let sent = message.timestamp.to_wall_ms();
+    let offset = match skew.check(sent - received) {
+        SkewCheck::Ok => return,
+        SkewCheck::Skewed(offset) => {
+            if policy == SkewPolicy::Correct {
+                message.timestamp = LogTimeStamp::from_wall_ms(sent - offset);
+            }
+            offset
+        }
+        SkewCheck::Outlier(offset) => {
+            if policy == SkewPolicy::Correct {
+                message.timestamp = LogTimeStamp::from_wall_ms(received);
+            }
+            offset
+        }
+    };
+    let key = if policy == SkewPolicy::Correct {
+        "clock_skew_corrected"
+    } else {
+        "clock_skew"
+    };
+    let value = format!(
+        "{}{}.{:03}s",
+        if offset < 0 { "-" } else { "+" },
+        offset.unsigned_abs() / 1000,
+        offset.unsigned_abs() % 1000
+    );
+    message.fields.push((String::from(key), value));
+}
+
+impl TryFrom<LogPacket> for LogMessage {
+    type Error = ConversionError;
+
+    fn try_from(packet: LogPacket) -> Result<Self, Self::Error> {
+        Ok(LogMessage {
+            pid: packet.pid,
+            uid: packet.uid,
+            priority: decode_priority(packet.priority)?,
+            timestamp: decode_timestamp(&packet.timestamp)?,
+            tag: None,
+            message: String::from_utf8_lossy(&packet.message).into_owned(),
+            fields: Vec::new(),
+            trace: match packet.version {
+                1 => None,
+                _ => TraceContext::from_bytes(&packet.trace),
+            },
+        })
+    }
+}
+
+fn decode_priority(priority: u8) -> Result<LogPriority, ConversionError> {
+    match priority {
+        0 => Ok(LogPriority::Verbose),
+        1 => Ok(LogPriority::Debug),
+        2 => Ok(LogPriority::Info),
+        3 => Ok(LogPriority::Warn),
+        4 => Ok(LogPriority::Error),
+        5 => Ok(LogPriority::Fatal),
+        other => Err(ConversionError::UnknownPriority(other)),
+    }
+}
+
+/// Wire priority that will decode: unknown values are logged as Info, so a
+/// garbage byte cannot pass for Fatal.
+fn repair_priority(priority: u8) -> u8 {
+    match decode_priority(priority) {
+        Ok(_) => priority,
+        Err(_) => LogPriority::Info as u8,
+    }
+}
+
+/// Splits the 9 byte wire timestamp into its fields without checking them.
+fn read_timestamp(bytes: &[u8]) -> Result<LogTimeStamp, ConversionError> {
+    if bytes.len() != 9 {
+        return Err(ConversionError::IncorrectTimestampSize(bytes.len()));
+    }
+    Ok(LogTimeStamp {
+        year: u16::from_be_bytes([bytes[0], bytes[1]]),
+        month: bytes[2],
+        day: bytes[3],
+        hour: bytes[4],
+        minute: bytes[5],
+        second: bytes[6],
+        millisecond: u16::from_be_bytes([bytes[7], bytes[8]]),
+    })
+}
+
+fn decode_timestamp(bytes: &[u8]) -> Result<LogTimeStamp, ConversionError> {
+    let timestamp = read_timestamp(bytes)?;
+    match timestamp.invalid_field() {
+        Some((field, value)) => Err(ConversionError::InvalidTimestamp(field, value)),
+        None => Ok(timestamp),
+    }
+}
+
+fn encode_timestamp(timestamp: &LogTimeStamp) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(9);
+    bytes.extend_from_slice(&timestamp.year.to_be_bytes());
+    bytes.extend_from_slice(&[
+        timestamp.month,
+        timestamp.day,
+        timestamp.hour,
+        timestamp.minute,
+        timestamp.second,
+    ]);
+    bytes.extend_from_slice(&timestamp.millisecond.to_be_bytes());
+    bytes
+}
+
+/// Wire timestamp that will decode under `policy`: the clamped client time,
+/// or the server time when asked for or when there is nothing to clamp.
+fn repair_timestamp(bytes: &[u8], policy: MalformedPolicy) -> Vec<u8> {
+    match (policy, read_timestamp(bytes)) {
+        (MalformedPolicy::Clamp, Ok(timestamp)) => encode_timestamp(&timestamp.clamped()),
+        _ if decode_timestamp(bytes).is_ok() => bytes.to_vec(),
+        _ => encode_timestamp(&LogTimeStamp::now()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn message(ahead_ms: i64) -> LogMessage {
+        LogMessage {
+            pid: 42,
+            uid: None,
+            priority: LogPriority::Info,
+            timestamp: LogTimeStamp::from_wall_ms(wall_clock_ms() + ahead_ms),
+            tag: None,
+            message: String::from("hello"),
+            fields: Vec::new(),
+            trace: None,
+        }
+    }
+
+    #[test]
+    fn skew_is_recorded_as_a_field() {
+        let mut skew = SkewEstimator::new(Duration::from_secs(2));
+        let mut skewed = message(3_600_000);
+        apply_skew(&mut skewed, &mut skew, SkewPolicy::Flag);
+        assert_eq!(skewed.message, "hello");
+        assert_eq!(skewed.fields.len(), 1);
+        assert_eq!(skewed.fields[0].0, "clock_skew");
+        let seconds: f64 = skewed.fields[0].1.trim_end_matches('s').parse().unwrap();
+        assert!((seconds - 3600.0).abs() < 1.0, "{}", seconds);
+
+        let mut corrected = message(3_600_000);
+        apply_skew(&mut corrected, &mut skew, SkewPolicy::Correct);
+        assert_eq!(corrected.message, "hello");
+        assert_eq!(corrected.fields[0].0, "clock_skew_corrected");
+        assert!((corrected.timestamp.to_wall_ms() - wall_clock_ms()).abs() < 1000);
     }
 }
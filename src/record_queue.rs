@@ -0,0 +1,331 @@
+use crate::config::{Config, ConfigError};
+use crate::log_def::LogPriority;
+use crate::msg_proc::LogRecord;
+use crate::stats;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+
+/// Error and Fatal, then Info and Warn, then Verbose and Debug.
+static LANE_COUNT: usize = 3;
+static LOW_LANE: usize = LANE_COUNT - 1;
+static DEFAULT_LOW_LANE_EVERY: usize = 16;
+static DEFAULT_LOW_LANE_MAX: usize = 100_000;
+
+/// How records from the producers are queued for the `OutputHandler`.
+#[derive(Debug, Clone, Copy)]
+pub struct QueueSettings {
+    /// One lane per priority band, served highest first. Otherwise all
+    /// records share a single lane in arrival order.
+    pub priority_lanes: bool,
+    /// With priority lanes, a waiting Verbose or Debug record is served after
+    /// this many records from the higher lanes, so a steady stream of Info
+    /// cannot starve it. 0 never serves it out of turn.
+    pub low_lane_every: usize,
+    /// Verbose and Debug records beyond this many waiting are dropped and
+    /// counted. 0 leaves the lane unbounded.
+    pub low_lane_max: usize,
+}
+
+impl Default for QueueSettings {
+    fn default() -> Self {
+        QueueSettings {
+            priority_lanes: true,
+            low_lane_every: DEFAULT_LOW_LANE_EVERY,
+            low_lane_max: DEFAULT_LOW_LANE_MAX,
+        }
+    }
+}
+
+impl QueueSettings {
+    /// Reads the `[channel]` section.
+    pub fn from_config(config: &Config) -> Result<Self, ConfigError> {
+        let mut settings = QueueSettings::default();
+        if let Some(section) = config.section("channel") {
+            if let Some(priority_lanes) = section.get_parsed("priority_lanes")? {
+                settings.priority_lanes = priority_lanes;
+            }
+            if let Some(low_lane_every) = section.get_parsed("low_lane_every")? {
+                settings.low_lane_every = low_lane_every;
+            }
+            if let Some(low_lane_max) = section.get_parsed("low_lane_max")? {
+                settings.low_lane_max = low_lane_max;
+            }
+        }
+        Ok(settings)
+    }
+}
+
+struct Lanes {
+    queues: [VecDeque<LogRecord>; LANE_COUNT],
+    /// Records served from the higher lanes while the low lane was waiting.
+    low_lane_passed: usize,
+    receiver_dropped: bool,
+}
+
+impl Lanes {
+    /// The oldest record of the highest non-empty lane, or of the low lane
+    /// once it has been passed over `low_lane_every` times.
+    fn pop(&mut self, low_lane_every: usize) -> Option<LogRecord> {
+        let low_lane_due = low_lane_every > 0
+            && self.low_lane_passed >= low_lane_every
+            // Shutdown must not overtake the records queued before it
+            && self.queues[LOW_LANE]
+                .front()
+                .is_some_and(|record| !matches!(record, LogRecord::Shutdown));
+        let lane = if low_lane_due {
+            LOW_LANE
+        } else {
+            self.queues.iter().position(|queue| !queue.is_empty())?
+        };
+        if lane == LOW_LANE {
+            self.low_lane_passed = 0;
+        } else if !self.queues[LOW_LANE].is_empty() {
+            self.low_lane_passed += 1;
+        }
+        self.queues[lane].pop_front()
+    }
+}
+
+struct Shared {
+    lanes: Mutex<Lanes>,
+    ready: Condvar,
+    senders: AtomicUsize,
+    priority_lanes: bool,
+    low_lane_every: usize,
+    low_lane_max: usize,
+}
+
+impl Shared {
+    /// Ticks go first so held messages are released on time, Shutdown last
+    /// so everything queued before it is still written.
+    fn lane(&self, record: &LogRecord) -> usize {
+        if !self.priority_lanes {
+            return LOW_LANE;
+        }
+        match record {
+            LogRecord::Tick => 0,
+            LogRecord::Shutdown => LOW_LANE,
+            LogRecord::Client { message, .. } | LogRecord::Ingested { message, .. } => {
+                match message.priority {
+                    LogPriority::Error | LogPriority::Fatal => 0,
+                    LogPriority::Info | LogPriority::Warn => 1,
+                    LogPriority::Verbose | LogPriority::Debug => LOW_LANE,
+                }
+            }
+        }
+    }
+}
+
+/// Channel from the producers to the `OutputHandler`. Each lane is first in,
+/// first out, so the records of a client keep their order within a priority
+/// band; a higher band overtakes whatever waits in the lower ones, except that
+/// Verbose and Debug get a turn every `low_lane_every` records. Only the
+/// Verbose and Debug lane is bounded.
+pub fn channel(settings: QueueSettings) -> (RecordSender, RecordReceiver) {
+    let shared = Arc::new(Shared {
+        lanes: Mutex::new(Lanes {
+            queues: Default::default(),
+            low_lane_passed: 0,
+            receiver_dropped: false,
+        }),
+        ready: Condvar::new(),
+        senders: AtomicUsize::new(1),
+        priority_lanes: settings.priority_lanes,
+        low_lane_every: settings.low_lane_every,
+        low_lane_max: settings.low_lane_max,
+    });
+    (
+        RecordSender {
+            shared: shared.clone(),
+        },
+        RecordReceiver { shared },
+    )
+}
+
+pub struct RecordSender {
+    shared: Arc<Shared>,
+}
+
+/// The receiver is gone; the record was dropped.
+#[derive(Debug, PartialEq, Eq)]
+pub struct ChannelClosed;
+
+impl RecordSender {
+    /// Fails once the receiver is gone. A Verbose or Debug record finding its
+    /// lane full is dropped.
+    pub fn send(&self, record: LogRecord) -> Result<(), ChannelClosed> {
+        let lane = self.shared.lane(&record);
+        let mut lanes = self.shared.lanes.lock().unwrap();
+        if lanes.receiver_dropped {
+            return Err(ChannelClosed);
+        }
+        if self.shared.priority_lanes
+            && lane == LOW_LANE
+            && self.shared.low_lane_max > 0
+            && lanes.queues[LOW_LANE].len() >= self.shared.low_lane_max
+            && !matches!(record, LogRecord::Shutdown)
+        {
+            stats::global()
+                .channel_dropped
+                .fetch_add(1, Ordering::Relaxed);
+            return Ok(());
+        }
+        lanes.queues[lane].push_back(record);
+        drop(lanes);
+        self.shared.ready.notify_one();
+        Ok(())
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.shared.lanes.lock().unwrap().receiver_dropped
+    }
+}
+
+impl Clone for RecordSender {
+    fn clone(&self) -> Self {
+        self.shared.senders.fetch_add(1, Ordering::Relaxed);
+        RecordSender {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl Drop for RecordSender {
+    fn drop(&mut self) {
+        if self.shared.senders.fetch_sub(1, Ordering::AcqRel) == 1 {
+            // take the lock so a receiver about to wait sees the last sender go
+            let _lanes = self.shared.lanes.lock().unwrap();
+            self.shared.ready.notify_all();
+        }
+    }
+}
+
+pub struct RecordReceiver {
+    shared: Arc<Shared>,
+}
+
+impl RecordReceiver {
+    /// Blocks until a record is queued and returns the next one by lane
+    /// priority, or `None` once all senders are dropped and nothing is left.
+    pub fn blocking_recv(&mut self) -> Option<LogRecord> {
+        let mut lanes = self.shared.lanes.lock().unwrap();
+        loop {
+            if let Some(record) = lanes.pop(self.shared.low_lane_every) {
+                return Some(record);
+            }
+            if self.shared.senders.load(Ordering::Acquire) == 0 {
+                return None;
+            }
+            lanes = self.shared.ready.wait(lanes).unwrap();
+        }
+    }
+
+    /// Records waiting in all lanes.
+    pub fn len(&self) -> usize {
+        let lanes = self.shared.lanes.lock().unwrap();
+        lanes.queues.iter().map(VecDeque::len).sum()
+    }
+}
+
+impl Drop for RecordReceiver {
+    fn drop(&mut self) {
+        let mut lanes = self.shared.lanes.lock().unwrap();
+        lanes.receiver_dropped = true;
+        for queue in lanes.queues.iter_mut() {
+            queue.clear();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::log_def::*;
+
+    fn record(priority: LogPriority, text: &str) -> LogRecord {
+        LogRecord::Client {
+            sink_type: u8::MAX,
+            message: LogMessage {
+                pid: 1,
+                uid: None,
+                priority,
+                timestamp: LogTimeStamp::now(),
+                tag: None,
+                message: text.to_string(),
+                fields: Vec::new(),
+                trace: None,
+            },
+        }
+    }
+
+    fn send(sender: &RecordSender, record: LogRecord) {
+        assert!(sender.send(record).is_ok());
+    }
+
+    fn text(record: Option<LogRecord>) -> String {
+        match record {
+            Some(LogRecord::Client { message, .. }) => message.message,
+            Some(LogRecord::Shutdown) => String::from("shutdown"),
+            Some(_) => String::from("other"),
+            None => String::from("none"),
+        }
+    }
+
+    #[test]
+    fn higher_lanes_overtake() {
+        let (sender, mut receiver) = channel(QueueSettings::default());
+        send(&sender, record(LogPriority::Debug, "d"));
+        send(&sender, record(LogPriority::Info, "i"));
+        send(&sender, record(LogPriority::Error, "e"));
+        let order: Vec<_> = (0..3).map(|_| text(receiver.blocking_recv())).collect();
+        assert_eq!(order, ["e", "i", "d"]);
+    }
+
+    #[test]
+    fn low_lane_gets_a_turn() {
+        let (sender, mut receiver) = channel(QueueSettings {
+            low_lane_every: 2,
+            ..QueueSettings::default()
+        });
+        send(&sender, record(LogPriority::Debug, "d1"));
+        send(&sender, record(LogPriority::Debug, "d2"));
+        for i in 0..5 {
+            send(&sender, record(LogPriority::Info, &format!("i{}", i)));
+        }
+        let order: Vec<_> = (0..7).map(|_| text(receiver.blocking_recv())).collect();
+        assert_eq!(order, ["i0", "i1", "d1", "i2", "i3", "d2", "i4"]);
+    }
+
+    #[test]
+    fn shutdown_is_not_served_early() {
+        let (sender, mut receiver) = channel(QueueSettings {
+            low_lane_every: 1,
+            ..QueueSettings::default()
+        });
+        send(&sender, LogRecord::Shutdown);
+        send(&sender, record(LogPriority::Info, "i0"));
+        send(&sender, record(LogPriority::Info, "i1"));
+        let order: Vec<_> = (0..3).map(|_| text(receiver.blocking_recv())).collect();
+        assert_eq!(order, ["i0", "i1", "shutdown"]);
+    }
+
+    #[test]
+    fn full_low_lane_drops_and_counts() {
+        let (sender, mut receiver) = channel(QueueSettings {
+            low_lane_max: 2,
+            ..QueueSettings::default()
+        });
+        let dropped = stats::global().channel_dropped.load(Ordering::Relaxed);
+        for i in 0..4 {
+            send(&sender, record(LogPriority::Verbose, &format!("v{}", i)));
+        }
+        send(&sender, record(LogPriority::Info, "i"));
+        send(&sender, LogRecord::Shutdown);
+        assert_eq!(receiver.len(), 4);
+        // other tests may drop concurrently
+        assert!(stats::global().channel_dropped.load(Ordering::Relaxed) >= dropped + 2);
+        let order: Vec<_> = (0..4).map(|_| text(receiver.blocking_recv())).collect();
+        assert_eq!(order, ["i", "v0", "v1", "shutdown"]);
+    }
+}
@@ -0,0 +1,240 @@
+use crate::config::{Config, ConfigError};
+use crate::log_def::*;
+use crate::msg_sink::local_file::{MultiLineMode, format_fields, format_record};
+use regex::Regex;
+use std::collections::VecDeque;
+use std::fmt::Write;
+use std::mem;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+static DEFAULT_BUFFER_KB: usize = 2048;
+
+struct RingState {
+    messages: VecDeque<(Instant, Arc<LogMessage>)>,
+    bytes: usize,
+}
+
+/// The most recent messages, up to a memory budget, kept regardless of the
+/// sinks they were routed to so they can be dumped over the control socket.
+pub struct RingBuffer {
+    max_bytes: usize,
+    multiline: MultiLineMode,
+    state: Mutex<RingState>,
+}
+
+impl Default for RingBuffer {
+    fn default() -> Self {
+        RingBuffer::new(DEFAULT_BUFFER_KB * 1024)
+    }
+}
+
+impl RingBuffer {
+    /// A zero sized buffer keeps nothing.
+    pub fn new(max_bytes: usize) -> Self {
+        RingBuffer {
+            max_bytes,
+            multiline: MultiLineMode::default(),
+            state: Mutex::new(RingState {
+                messages: VecDeque::new(),
+                bytes: 0,
+            }),
+        }
+    }
+
+    /// Reads `size_kb` from the `[buffer]` section; messages are formatted
+    /// with the multi-line mode of the local file sink.
+    pub fn from_config(config: &Config) -> Result<Self, ConfigError> {
+        let size_kb = match config.section("buffer") {
+            Some(section) => section.get_parsed::<usize>("size_kb")?,
+            None => None,
+        };
+        Ok(RingBuffer {
+            multiline: MultiLineMode::from_config(config)?,
+            ..RingBuffer::new(size_kb.unwrap_or(DEFAULT_BUFFER_KB) * 1024)
+        })
+    }
+
+    /// A dumped message as in `notcat.log`, followed by its structured fields.
+    pub fn format(&self, message: &LogMessage) -> String {
+        format!(
+            "{}{}",
+            format_record(message, self.multiline),
+            format_fields(message)
+        )
+    }
+
+    fn footprint(message: &LogMessage) -> usize {
+        mem::size_of::<LogMessage>()
+            + message.message.len()
+            + message.tag.as_ref().map_or(0, |tag| tag.len())
+            + message
+                .fields
+                .iter()
+                .map(|(key, value)| key.len() + value.len())
+                .sum::<usize>()
+    }
+
+    pub fn push(&self, message: Arc<LogMessage>) {
+        if self.max_bytes == 0 {
+            return;
+        }
+        let size = Self::footprint(&message);
+        let mut state = self.state.lock().unwrap();
+        state.bytes += size;
+        state.messages.push_back((Instant::now(), message));
+        while state.bytes > self.max_bytes {
+            match state.messages.pop_front() {
+                Some((_, old)) => state.bytes -= Self::footprint(&old),
+                None => break,
+            }
+        }
+    }
+
+    /// Matching messages, oldest first, limited to the newest `filter.count`.
+    pub fn dump(&self, filter: &DumpFilter) -> Vec<Arc<LogMessage>> {
+        let state = self.state.lock().unwrap();
+        let mut matched: Vec<_> = state
+            .messages
+            .iter()
+            .rev()
+            .take_while(|(arrived, _)| filter.since.is_none_or(|since| *arrived >= since))
+            .filter(|(_, message)| filter.matches(message))
+            .take(filter.count.unwrap_or(usize::MAX))
+            .map(|(_, message)| message.clone())
+            .collect();
+        matched.reverse();
+        matched
+    }
+
+    /// Response of the `dump` control command.
+    pub fn dump_command<'a>(&self, args: impl Iterator<Item = &'a str>) -> String {
+        let filter = match DumpFilter::parse(args) {
+            Ok(filter) => filter,
+            Err(e) => return format!("{}\n{}\n", e, DUMP_USAGE),
+        };
+        let mut out = String::new();
+        for message in self.dump(&filter) {
+            let _ = writeln!(out, "{}", self.format(&message));
+        }
+        out
+    }
+}
+
+static DUMP_USAGE: &str = "usage: dump [-n <count>] [-p <min priority>] [-t <tag>] [--pid <pid>] [-e <regex>] [-f <key>=<value>]";
+
+/// Filters of the `dump` command, modelled on `logcat -d`.
+#[derive(Default)]
+pub struct DumpFilter {
+    pub count: Option<usize>,
+    pub priority: Option<LogPriority>,
+    pub tag: Option<String>,
+    pub pid: Option<u32>,
+    pub pattern: Option<Regex>,
+    /// Structured field that must be present with this value.
+    pub field: Option<(String, String)>,
+    /// Only messages received after this instant.
+    pub since: Option<Instant>,
+}
+
+impl DumpFilter {
+    pub fn parse<'a>(mut args: impl Iterator<Item = &'a str>) -> Result<Self, String> {
+        let mut filter = DumpFilter::default();
+        while let Some(flag) = args.next() {
+            let value = args
+                .next()
+                .ok_or_else(|| format!("missing value for {}", flag))?;
+            let invalid = || format!("invalid value for {}: {}", flag, value);
+            match flag {
+                "-n" => filter.count = Some(value.parse().map_err(|_| invalid())?),
+                "-p" => filter.priority = Some(value.parse().map_err(|_| invalid())?),
+                "-t" => filter.tag = Some(value.to_string()),
+                "--pid" => filter.pid = Some(value.parse().map_err(|_| invalid())?),
+                "-e" => filter.pattern = Some(Regex::new(value).map_err(|_| invalid())?),
+                "-f" => {
+                    let (key, value) = value.split_once('=').ok_or_else(invalid)?;
+                    filter.field = Some((key.to_string(), value.to_string()));
+                }
+                _ => return Err(format!("unknown option: {}", flag)),
+            }
+        }
+        Ok(filter)
+    }
+
+    pub fn matches(&self, message: &LogMessage) -> bool {
+        self.priority.is_none_or(|p| message.priority >= p)
+            && self.pid.is_none_or(|pid| message.pid == pid)
+            && self
+                .tag
+                .as_ref()
+                .is_none_or(|tag| message.tag.as_ref() == Some(tag))
+            && self
+                .pattern
+                .as_ref()
+                .is_none_or(|pattern| pattern.is_match(&message.message))
+            && self
+                .field
+                .as_ref()
+                .is_none_or(|field| message.fields.contains(field))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(text: &str, fields: Vec<(String, String)>) -> Arc<LogMessage> {
+        Arc::new(LogMessage {
+            pid: 7,
+            uid: None,
+            priority: LogPriority::Info,
+            timestamp: LogTimeStamp {
+                year: 2024,
+                month: 3,
+                day: 9,
+                hour: 14,
+                minute: 5,
+                second: 7,
+                millisecond: 5,
+            },
+            tag: Some(String::from("netd")),
+            message: String::from(text),
+            fields,
+            trace: None,
+        })
+    }
+
+    #[test]
+    fn dump_shows_fields() {
+        let buffer = RingBuffer::default();
+        buffer.push(message(
+            "dns failed",
+            vec![
+                (String::from("iface"), String::from("wlan0")),
+                (String::from("msg"), String::from("timed out")),
+            ],
+        ));
+        assert_eq!(
+            buffer.dump_command(["-f", "iface=wlan0"].into_iter()),
+            "[7] I 2024-3-9 14:5:7-5 netd: dns failed {iface=wlan0 msg=\"timed out\"}\n"
+        );
+    }
+
+    #[test]
+    fn dump_writes_multi_line_messages_like_the_log_file() {
+        let buffer = RingBuffer::default();
+        buffer.push(message("crash\n  at main", vec![]));
+        assert_eq!(
+            buffer.dump_command(std::iter::empty()),
+            "[7] I 2024-3-9 14:5:7-5 netd: crash\n[7] I 2024-3-9 14:5:7-5 netd:   at main\n"
+        );
+
+        let config = Config::parse("[sink local_file]\nmultiline = escape\n").unwrap();
+        let buffer = RingBuffer::from_config(&config).unwrap();
+        buffer.push(message("crash\n  at main", vec![]));
+        assert_eq!(
+            buffer.dump_command(std::iter::empty()),
+            "[7] I 2024-3-9 14:5:7-5 netd: crash\\n  at main\n"
+        );
+    }
+}
@@ -0,0 +1,300 @@
+use crate::config::{Config, ConfigError};
+use crate::log::*;
+use crate::log_def::*;
+use crate::msg_sink::local_file::LOG_DIR;
+use crate::ring_buffer::{DumpFilter, RingBuffer};
+use crate::stats;
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+static CRASH_DIR: &str = "crash";
+static SNAPSHOT_PREFIX: &str = "snapshot-";
+
+/// Why a snapshot is taken and which client it is about.
+pub struct SnapshotRequest {
+    pub reason: String,
+    pub pid: Option<u32>,
+}
+
+/// Handle used by the output handler and the protocol handler to ask for a
+/// snapshot. Requests are written on the snapshot thread, never inline.
+#[derive(Clone)]
+pub struct SnapshotTrigger(Sender<SnapshotRequest>);
+
+impl SnapshotTrigger {
+    pub fn request(&self, reason: String, pid: Option<u32>) {
+        let _ = self.0.send(SnapshotRequest { reason, pid });
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SnapshotConfig {
+    pub dir: PathBuf,
+    /// How much of every client's recent history to include.
+    pub window: Duration,
+    /// How many of the crashing client's own records to include.
+    pub client_records: usize,
+    /// Oldest snapshots are deleted beyond this total size.
+    pub max_dir_bytes: u64,
+    /// Requests for a pid arriving sooner than this after its last snapshot
+    /// are ignored, so a crash loop does not rewrite the directory
+    /// continuously. Other pids are not held back.
+    pub min_interval: Duration,
+    /// Lets the crashing client's last messages reach the ring buffer first.
+    pub delay: Duration,
+}
+
+impl Default for SnapshotConfig {
+    fn default() -> Self {
+        SnapshotConfig {
+            dir: Path::new(LOG_DIR).join(CRASH_DIR),
+            window: Duration::from_secs(30),
+            client_records: 200,
+            max_dir_bytes: 10 * 1024 * 1024,
+            min_interval: Duration::from_secs(10),
+            delay: Duration::from_millis(200),
+        }
+    }
+}
+
+impl SnapshotConfig {
+    /// Reads the `[snapshot]` section; `None` unless it sets `enabled = true`.
+    pub fn from_config(config: &Config) -> Result<Option<Self>, ConfigError> {
+        let mut snapshot = SnapshotConfig::default();
+        let Some(section) = config.section("snapshot") else {
+            return Ok(None);
+        };
+        if section.get_parsed::<bool>("enabled")? != Some(true) {
+            return Ok(None);
+        }
+        if let Some(window) = section.get_parsed::<u64>("window_s")? {
+            snapshot.window = Duration::from_secs(window);
+        }
+        if let Some(records) = section.get_parsed::<usize>("client_records")? {
+            snapshot.client_records = records;
+        }
+        if let Some(max_kb) = section.get_parsed::<u64>("max_dir_kb")? {
+            snapshot.max_dir_bytes = max_kb * 1024;
+        }
+        if let Some(interval) = section.get_parsed::<u64>("min_interval_s")? {
+            snapshot.min_interval = Duration::from_secs(interval);
+        }
+        if let Some(delay) = section.get_parsed::<u64>("delay_ms")? {
+            snapshot.delay = Duration::from_millis(delay);
+        }
+        Ok(Some(snapshot))
+    }
+}
+
+/// Starts the snapshot thread and returns the handle used to trigger it.
+pub fn spawn_writer(config: SnapshotConfig, ring_buffer: Arc<RingBuffer>) -> SnapshotTrigger {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || run_writer(config, ring_buffer, receiver));
+    SnapshotTrigger(sender)
+}
+
+/// Time of the last snapshot of every pid, or of requests without one.
+struct Cooldowns {
+    interval: Duration,
+    last: HashMap<Option<u32>, Instant>,
+}
+
+impl Cooldowns {
+    fn new(interval: Duration) -> Self {
+        Cooldowns {
+            interval,
+            last: HashMap::new(),
+        }
+    }
+
+    /// Whether a snapshot for `pid` may be taken now; if so, starts its
+    /// cooldown.
+    fn allow(&mut self, pid: Option<u32>, now: Instant) -> bool {
+        let interval = self.interval;
+        self.last
+            .retain(|_, last| now.saturating_duration_since(*last) < interval);
+        if self.last.contains_key(&pid) {
+            return false;
+        }
+        self.last.insert(pid, now);
+        true
+    }
+}
+
+fn run_writer(
+    config: SnapshotConfig,
+    ring_buffer: Arc<RingBuffer>,
+    receiver: Receiver<SnapshotRequest>,
+) {
+    let mut cooldowns = Cooldowns::new(config.min_interval);
+    while let Ok(request) = receiver.recv() {
+        if !cooldowns.allow(request.pid, Instant::now()) {
+            logd!(
+                LOG_TAG,
+                "[Snapshot] Skipping snapshot for {}: too soon after the previous one",
+                request.reason
+            );
+            continue;
+        }
+        thread::sleep(config.delay);
+        match write_snapshot(&config, &ring_buffer, &request) {
+            Ok(path) => {
+                logi!(
+                    LOG_TAG,
+                    "[Snapshot] {} written to {}",
+                    request.reason,
+                    path.display()
+                );
+            }
+            Err(e) => {
+                loge!(LOG_TAG, "[Snapshot] Failed to write snapshot: {}", e);
+            }
+        }
+        if let Err(e) = enforce_dir_limit(&config.dir, config.max_dir_bytes) {
+            loge!(
+                LOG_TAG,
+                "[Snapshot] Failed to trim {}: {}",
+                config.dir.display(),
+                e
+            );
+        }
+    }
+}
+
+fn write_snapshot(
+    config: &SnapshotConfig,
+    ring_buffer: &RingBuffer,
+    request: &SnapshotRequest,
+) -> io::Result<PathBuf> {
+    let now = LogTimeStamp::now();
+    let mut out = String::new();
+    let _ = writeln!(out, "reason: {}", request.reason);
+    if let Some(pid) = request.pid {
+        let _ = writeln!(out, "pid: {}", pid);
+    }
+    let _ = writeln!(
+        out,
+        "time: {}-{:02}-{:02} {:02}:{:02}:{:02}.{:03}",
+        now.year, now.month, now.day, now.hour, now.minute, now.second, now.millisecond
+    );
+    let _ = writeln!(out, "\n--- stats ---");
+    out.push_str(&stats::global().report());
+    if let Some(pid) = request.pid {
+        let _ = writeln!(
+            out,
+            "\n--- last {} records of pid {} ---",
+            config.client_records, pid
+        );
+        let filter = DumpFilter {
+            pid: Some(pid),
+            count: Some(config.client_records),
+            ..DumpFilter::default()
+        };
+        for message in ring_buffer.dump(&filter) {
+            let _ = writeln!(out, "{}", ring_buffer.format(&message));
+        }
+    }
+    let _ = writeln!(
+        out,
+        "\n--- all records of the last {}s ---",
+        config.window.as_secs()
+    );
+    let filter = DumpFilter {
+        since: Instant::now().checked_sub(config.window),
+        ..DumpFilter::default()
+    };
+    for message in ring_buffer.dump(&filter) {
+        let _ = writeln!(out, "{}", ring_buffer.format(&message));
+    }
+
+    fs::create_dir_all(&config.dir)?;
+    let path = config.dir.join(format!(
+        "{}{:04}{:02}{:02}-{:02}{:02}{:02}-{:03}-{}.txt",
+        SNAPSHOT_PREFIX,
+        now.year,
+        now.month,
+        now.day,
+        now.hour,
+        now.minute,
+        now.second,
+        now.millisecond,
+        request.pid.unwrap_or(0)
+    ));
+    fs::write(&path, out)?;
+    Ok(path)
+}
+
+/// Deletes the oldest snapshots until the directory fits in `max_bytes`. The
+/// names sort chronologically, so the oldest come first. The newest snapshot
+/// is always kept, even when it alone exceeds the limit.
+fn enforce_dir_limit(dir: &Path, max_bytes: u64) -> io::Result<()> {
+    let mut snapshots = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry
+            .file_name()
+            .to_string_lossy()
+            .starts_with(SNAPSHOT_PREFIX)
+        {
+            snapshots.push((entry.path(), entry.metadata()?.len()));
+        }
+    }
+    snapshots.sort();
+    let mut total: u64 = snapshots.iter().map(|(_, len)| len).sum();
+    snapshots.pop();
+    for (path, len) in snapshots {
+        if total <= max_bytes {
+            break;
+        }
+        fs::remove_file(&path)?;
+        total -= len;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cooldown_is_per_pid() {
+        let mut cooldowns = Cooldowns::new(Duration::from_secs(10));
+        let start = Instant::now();
+        assert!(cooldowns.allow(Some(7), start));
+        assert!(!cooldowns.allow(Some(7), start + Duration::from_secs(5)));
+        // another client's hang-up does not hold back this Fatal message
+        assert!(cooldowns.allow(None, start + Duration::from_secs(5)));
+        assert!(cooldowns.allow(Some(8), start + Duration::from_secs(5)));
+        assert!(cooldowns.allow(Some(7), start + Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn dir_limit_deletes_oldest_and_keeps_newest() {
+        let dir = std::env::temp_dir().join(format!("notcat_snapshots_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        for (name, len) in [
+            ("snapshot-20240101-000000-000-1.txt", 40),
+            ("snapshot-20240101-000001-000-1.txt", 40),
+            ("snapshot-20240101-000002-000-1.txt", 100),
+            ("notes.txt", 1000),
+        ] {
+            fs::write(dir.join(name), vec![b'x'; len]).unwrap();
+        }
+        enforce_dir_limit(&dir, 90).unwrap();
+        let mut left: Vec<_> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        left.sort();
+        let _ = fs::remove_dir_all(&dir);
+        assert_eq!(left, ["notes.txt", "snapshot-20240101-000002-000-1.txt"]);
+    }
+}
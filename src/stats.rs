@@ -0,0 +1,528 @@
+use crate::record_queue::RecordSender as Sender;
+use crate::{log::*, log_def::*, msg_proc::LogRecord};
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    sync::{
+        Arc, Mutex, OnceLock,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+static MAX_PID_ENTRIES: usize = 256;
+pub static STATS_REPORT_INTERVAL: Duration = Duration::from_secs(300);
+static SINK_STALL_TIMEOUT: Duration = Duration::from_secs(5);
+/// Upper bounds, in milliseconds, of the sink write latency buckets.
+pub static LATENCY_BUCKETS_MS: [u64; 8] = [1, 2, 5, 10, 50, 100, 500, 1000];
+
+static STATS: OnceLock<DaemonStats> = OnceLock::new();
+
+/// Picks one counter of a client or a sink for an exported family.
+type ClientCounterField = fn(&ClientCounters) -> &AtomicU64;
+type SinkCounterField = fn(&SinkCounters) -> &AtomicU64;
+
+/// Process-wide statistics registry, shared by the server, the protocol
+/// handler, the output handler and the control socket.
+pub fn global() -> &'static DaemonStats {
+    STATS.get_or_init(DaemonStats::new)
+}
+
+#[derive(Default)]
+pub struct ClientCounters {
+    pub messages: AtomicU64,
+    pub bytes: AtomicU64,
+    pub protocol_errors: AtomicU64,
+    pub malformed: AtomicU64,
+    pub drops: AtomicU64,
+}
+
+impl ClientCounters {
+    fn format(&self) -> String {
+        format!(
+            "messages={} bytes={} protocol_errors={} malformed={} drops={}",
+            self.messages.load(Ordering::Relaxed),
+            self.bytes.load(Ordering::Relaxed),
+            self.protocol_errors.load(Ordering::Relaxed),
+            self.malformed.load(Ordering::Relaxed),
+            self.drops.load(Ordering::Relaxed)
+        )
+    }
+}
+
+#[derive(Default)]
+pub struct ServerCounters {
+    pub accepted: AtomicU64,
+    pub rejected: AtomicU64,
+    pub closed: AtomicU64,
+    pub timeouts: AtomicU64,
+}
+
+#[derive(Default)]
+pub struct SinkCounters {
+    pub messages: AtomicU64,
+    pub bytes: AtomicU64,
+    pub errors: AtomicU64,
+    pub overflows: AtomicU64,
+    pub queue_depth: AtomicU64,
+    consecutive_errors: AtomicU64,
+    busy_since_ms: AtomicU64, // uptime in ms + 1 when a send started, 0 when idle
+    stopped: AtomicBool,
+    last_error: Mutex<Option<String>>,
+    /// Writes per `LATENCY_BUCKETS_MS` bucket, the last one is unbounded.
+    latency_buckets: [AtomicU64; 9],
+    latency_sum_us: AtomicU64,
+}
+
+impl SinkCounters {
+    pub fn record(&self, result: &Result<usize, String>) {
+        match result {
+            Ok(bytes) => {
+                self.messages.fetch_add(1, Ordering::Relaxed);
+                self.bytes.fetch_add(*bytes as u64, Ordering::Relaxed);
+                self.consecutive_errors.store(0, Ordering::Relaxed);
+            }
+            Err(e) => {
+                self.errors.fetch_add(1, Ordering::Relaxed);
+                self.consecutive_errors.fetch_add(1, Ordering::Relaxed);
+                *self.last_error.lock().unwrap() = Some(e.clone());
+            }
+        }
+    }
+
+    pub fn record_latency(&self, latency: Duration) {
+        // in microseconds, so 1.5ms does not count as within the 1ms bucket
+        let us = latency.as_micros() as u64;
+        let bucket = LATENCY_BUCKETS_MS
+            .iter()
+            .position(|bound| us <= *bound * 1000)
+            .unwrap_or(LATENCY_BUCKETS_MS.len());
+        self.latency_buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.latency_sum_us
+            .fetch_add(latency.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub fn add_overflow(&self) {
+        self.overflows.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn set_queue_depth(&self, depth: usize) {
+        self.queue_depth.store(depth as u64, Ordering::Relaxed);
+    }
+
+    pub fn set_busy(&self, busy: bool) {
+        let value = if busy { global().uptime_ms() + 1 } else { 0 };
+        self.busy_since_ms.store(value, Ordering::Relaxed);
+    }
+
+    pub fn set_stopped(&self) {
+        self.stopped.store(true, Ordering::Relaxed);
+    }
+
+    /// `ok`, `failing` when the last write failed, `stalled` when a write has
+    /// been running for too long, or `stopped` once the worker is gone.
+    pub fn health(&self) -> &'static str {
+        let busy_since = self.busy_since_ms.load(Ordering::Relaxed);
+        if self.stopped.load(Ordering::Relaxed) {
+            "stopped"
+        } else if busy_since != 0
+            && global().uptime_ms() + 1 - busy_since > SINK_STALL_TIMEOUT.as_millis() as u64
+        {
+            "stalled"
+        } else if self.consecutive_errors.load(Ordering::Relaxed) > 0 {
+            "failing"
+        } else {
+            "ok"
+        }
+    }
+}
+
+/// Counters of a single connection, its pid and the daemon totals, updated together.
+#[derive(Clone)]
+pub struct ClientStatsHandle {
+    connection: Arc<ClientCounters>,
+    pid: Arc<ClientCounters>,
+}
+
+impl ClientStatsHandle {
+    pub fn add_message(&self, bytes: usize) {
+        for counters in [&*self.connection, &*self.pid, &global().total] {
+            counters.messages.fetch_add(1, Ordering::Relaxed);
+            counters.bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+        }
+    }
+
+    pub fn add_protocol_error(&self) {
+        for counters in [&*self.connection, &*self.pid, &global().total] {
+            counters.protocol_errors.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn add_malformed(&self) {
+        for counters in [&*self.connection, &*self.pid, &global().total] {
+            counters.malformed.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn add_drop(&self) {
+        for counters in [&*self.connection, &*self.pid, &global().total] {
+            counters.drops.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+struct ClientEntry {
+    pid: u32,
+    counters: Arc<ClientCounters>,
+}
+
+pub struct DaemonStats {
+    started: Instant,
+    pub total: ClientCounters,
+    pub server: ServerCounters,
+    /// Records waiting in the channel to the output handler.
+    pub channel_depth: AtomicU64,
+    /// Verbose and Debug records dropped because their lane was full.
+    pub channel_dropped: AtomicU64,
+    /// Log file rotations of the local file sink.
+    pub rotations: AtomicU64,
+    client_errors: Mutex<HashMap<&'static str, u64>>,
+    clients: Mutex<HashMap<i32, ClientEntry>>,
+    pids: Mutex<HashMap<u32, Arc<ClientCounters>>>,
+    sinks: Mutex<Vec<(&'static str, Arc<SinkCounters>)>>,
+}
+
+impl DaemonStats {
+    fn new() -> Self {
+        DaemonStats {
+            started: Instant::now(),
+            total: ClientCounters::default(),
+            server: ServerCounters::default(),
+            channel_depth: AtomicU64::new(0),
+            channel_dropped: AtomicU64::new(0),
+            rotations: AtomicU64::new(0),
+            client_errors: Mutex::new(HashMap::new()),
+            clients: Mutex::new(HashMap::new()),
+            pids: Mutex::new(HashMap::new()),
+            sinks: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn register_client(&self, fd: i32, pid: u32) -> ClientStatsHandle {
+        let connection = Arc::new(ClientCounters::default());
+        let mut clients = self.clients.lock().unwrap();
+        clients.insert(
+            fd,
+            ClientEntry {
+                pid,
+                counters: connection.clone(),
+            },
+        );
+        let mut pids = self.pids.lock().unwrap();
+        if pids.len() >= MAX_PID_ENTRIES && !pids.contains_key(&pid) {
+            // forget pids that are no longer connected
+            pids.retain(|p, _| clients.values().any(|c| c.pid == *p));
+        }
+        let pid_counters = pids.entry(pid).or_default().clone();
+        ClientStatsHandle {
+            connection,
+            pid: pid_counters,
+        }
+    }
+
+    pub fn remove_client(&self, fd: i32) {
+        self.clients.lock().unwrap().remove(&fd);
+    }
+
+    /// Counts a protocol error by `ClientError` variant.
+    pub fn add_client_error(&self, kind: &'static str) {
+        *self.client_errors.lock().unwrap().entry(kind).or_default() += 1;
+    }
+
+    pub fn uptime_ms(&self) -> u64 {
+        self.started.elapsed().as_millis() as u64
+    }
+
+    pub fn register_sink(&self, name: &'static str) -> Arc<SinkCounters> {
+        let counters = Arc::new(SinkCounters::default());
+        self.sinks.lock().unwrap().push((name, counters.clone()));
+        counters
+    }
+
+    /// One line summary, written periodically into the logs.
+    pub fn summary(&self) -> String {
+        let sinks = self.sinks.lock().unwrap();
+        let sink_errors: u64 = sinks
+            .iter()
+            .map(|(_, s)| s.errors.load(Ordering::Relaxed))
+            .sum();
+        let sink_overflows: u64 = sinks
+            .iter()
+            .map(|(_, s)| s.overflows.load(Ordering::Relaxed))
+            .sum();
+        let unhealthy: Vec<_> = sinks
+            .iter()
+            .filter(|(_, s)| s.health() != "ok")
+            .map(|(name, s)| format!("{}:{}", name, s.health()))
+            .collect();
+        format!(
+            "stats: uptime={}s clients={} {} sink_errors={} sink_overflows={} unhealthy_sinks={}",
+            self.started.elapsed().as_secs(),
+            self.clients.lock().unwrap().len(),
+            self.total.format(),
+            sink_errors,
+            sink_overflows,
+            if unhealthy.is_empty() {
+                String::from("-")
+            } else {
+                unhealthy.join(",")
+            }
+        )
+    }
+
+    /// Full report, returned by the `stats` control command.
+    pub fn report(&self) -> String {
+        let mut out = String::new();
+        let _ = writeln!(out, "uptime {}s", self.started.elapsed().as_secs());
+        let _ = writeln!(out, "total {}", self.total.format());
+        let _ = writeln!(
+            out,
+            "server accepted={} rejected={} closed={} timeouts={}",
+            self.server.accepted.load(Ordering::Relaxed),
+            self.server.rejected.load(Ordering::Relaxed),
+            self.server.closed.load(Ordering::Relaxed),
+            self.server.timeouts.load(Ordering::Relaxed)
+        );
+        let mut clients: Vec<_> = self
+            .clients
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(fd, c)| (*fd, c.pid, c.counters.format()))
+            .collect();
+        clients.sort_by_key(|(fd, _, _)| *fd);
+        for (fd, pid, counters) in clients {
+            let _ = writeln!(out, "client fd={} pid={} {}", fd, pid, counters);
+        }
+        let mut pids: Vec<_> = self
+            .pids
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(pid, c)| (*pid, c.format()))
+            .collect();
+        pids.sort_by_key(|(pid, _)| *pid);
+        for (pid, counters) in pids {
+            let _ = writeln!(out, "pid {} {}", pid, counters);
+        }
+        for (name, sink) in self.sinks.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "sink {} health={} messages={} bytes={} errors={} queue={} overflows={} last_error={}",
+                name,
+                sink.health(),
+                sink.messages.load(Ordering::Relaxed),
+                sink.bytes.load(Ordering::Relaxed),
+                sink.errors.load(Ordering::Relaxed),
+                sink.queue_depth.load(Ordering::Relaxed),
+                sink.overflows.load(Ordering::Relaxed),
+                sink.last_error.lock().unwrap().as_deref().unwrap_or("-")
+            );
+        }
+        out
+    }
+}
+
+impl DaemonStats {
+    /// Counters in OpenMetrics text, without the `# EOF` terminator.
+    pub fn openmetrics(&self, out: &mut String) {
+        let _ = writeln!(out, "# TYPE notcat_uptime_seconds gauge");
+        let _ = writeln!(
+            out,
+            "notcat_uptime_seconds {}",
+            self.started.elapsed().as_secs()
+        );
+        let _ = writeln!(out, "# TYPE notcat_connected_clients gauge");
+        let _ = writeln!(
+            out,
+            "notcat_connected_clients {}",
+            self.clients.lock().unwrap().len()
+        );
+        let mut pids: Vec<_> = self
+            .pids
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(pid, c)| (*pid, c.clone()))
+            .collect();
+        pids.sort_by_key(|(pid, _)| *pid);
+        let families: [(&str, ClientCounterField); 5] = [
+            ("messages", |c| &c.messages),
+            ("bytes", |c| &c.bytes),
+            ("protocol_errors", |c| &c.protocol_errors),
+            ("malformed", |c| &c.malformed),
+            ("drops", |c| &c.drops),
+        ];
+        for (family, counter) in families {
+            let _ = writeln!(out, "# TYPE notcat_client_{} counter", family);
+            for (pid, counters) in &pids {
+                let _ = writeln!(
+                    out,
+                    "notcat_client_{}_total{{pid=\"{}\"}} {}",
+                    family,
+                    pid,
+                    counter(counters).load(Ordering::Relaxed)
+                );
+            }
+        }
+        let _ = writeln!(out, "# TYPE notcat_protocol_errors counter");
+        let mut errors: Vec<_> = self
+            .client_errors
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(kind, count)| (*kind, *count))
+            .collect();
+        errors.sort();
+        for (kind, count) in errors {
+            let _ = writeln!(
+                out,
+                "notcat_protocol_errors_total{{error=\"{}\"}} {}",
+                kind, count
+            );
+        }
+        let _ = writeln!(out, "# TYPE notcat_connections counter");
+        for (event, counter) in [
+            ("accepted", &self.server.accepted),
+            ("rejected", &self.server.rejected),
+            ("closed", &self.server.closed),
+            ("timeout", &self.server.timeouts),
+        ] {
+            let _ = writeln!(
+                out,
+                "notcat_connections_total{{event=\"{}\"}} {}",
+                event,
+                counter.load(Ordering::Relaxed)
+            );
+        }
+        let _ = writeln!(out, "# TYPE notcat_channel_depth gauge");
+        let _ = writeln!(
+            out,
+            "notcat_channel_depth {}",
+            self.channel_depth.load(Ordering::Relaxed)
+        );
+        let _ = writeln!(out, "# TYPE notcat_channel_dropped counter");
+        let _ = writeln!(
+            out,
+            "notcat_channel_dropped_total {}",
+            self.channel_dropped.load(Ordering::Relaxed)
+        );
+        let _ = writeln!(out, "# TYPE notcat_log_rotations counter");
+        let _ = writeln!(
+            out,
+            "notcat_log_rotations_total {}",
+            self.rotations.load(Ordering::Relaxed)
+        );
+        let sinks = self.sinks.lock().unwrap();
+        let families: [(&str, SinkCounterField); 5] = [
+            ("messages", |s| &s.messages),
+            ("bytes", |s| &s.bytes),
+            ("errors", |s| &s.errors),
+            ("overflows", |s| &s.overflows),
+            ("queue_depth", |s| &s.queue_depth),
+        ];
+        for (family, counter) in families {
+            let (kind, suffix) = if family == "queue_depth" {
+                ("gauge", "")
+            } else {
+                ("counter", "_total")
+            };
+            let _ = writeln!(out, "# TYPE notcat_sink_{} {}", family, kind);
+            for (name, sink) in sinks.iter() {
+                let _ = writeln!(
+                    out,
+                    "notcat_sink_{}{}{{sink=\"{}\"}} {}",
+                    family,
+                    suffix,
+                    name,
+                    counter(sink).load(Ordering::Relaxed)
+                );
+            }
+        }
+        let _ = writeln!(out, "# TYPE notcat_sink_write_seconds histogram");
+        for (name, sink) in sinks.iter() {
+            let mut cumulative = 0;
+            for (bound, count) in LATENCY_BUCKETS_MS.iter().zip(&sink.latency_buckets) {
+                cumulative += count.load(Ordering::Relaxed);
+                let _ = writeln!(
+                    out,
+                    "notcat_sink_write_seconds_bucket{{sink=\"{}\",le=\"{}\"}} {}",
+                    name,
+                    *bound as f64 / 1000.0,
+                    cumulative
+                );
+            }
+            cumulative += sink.latency_buckets[LATENCY_BUCKETS_MS.len()].load(Ordering::Relaxed);
+            let _ = writeln!(
+                out,
+                "notcat_sink_write_seconds_bucket{{sink=\"{}\",le=\"+Inf\"}} {}",
+                name, cumulative
+            );
+            let _ = writeln!(
+                out,
+                "notcat_sink_write_seconds_sum{{sink=\"{}\"}} {}",
+                name,
+                sink.latency_sum_us.load(Ordering::Relaxed) as f64 / 1_000_000.0
+            );
+            let _ = writeln!(
+                out,
+                "notcat_sink_write_seconds_count{{sink=\"{}\"}} {}",
+                name, cumulative
+            );
+        }
+    }
+}
+
+/// Periodically injects the stats summary as a daemon record into every sink.
+pub fn spawn_reporter(sender: Sender, interval: Duration) -> JoinHandle<()> {
+    thread::spawn(move || {
+        loop {
+            thread::sleep(interval);
+            let message = LogMessage::daemon(global().summary());
+            let record = LogRecord::Ingested {
+                sink_type: u8::MAX,
+                message,
+            };
+            if sender.send(record).is_err() {
+                logd!(LOG_TAG, "[Stats] Channel closed, reporter exiting.");
+                break;
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn latency_buckets_are_upper_bounds() {
+        let counters = SinkCounters::default();
+        counters.record_latency(Duration::from_micros(1000));
+        counters.record_latency(Duration::from_micros(1500));
+        counters.record_latency(Duration::from_micros(1999));
+        counters.record_latency(Duration::from_millis(2));
+        counters.record_latency(Duration::from_secs(5));
+        let counts: Vec<u64> = counters
+            .latency_buckets
+            .iter()
+            .map(|count| count.load(Ordering::Relaxed))
+            .collect();
+        assert_eq!(counts, [1, 3, 0, 0, 0, 0, 0, 0, 1]);
+        assert_eq!(
+            counters.latency_sum_us.load(Ordering::Relaxed),
+            1000 + 1500 + 1999 + 2000 + 5_000_000
+        );
+    }
+}
@@ -0,0 +1,514 @@
+use crate::config::{Config, ConfigError, Section};
+use crate::log::*;
+use crate::log_def::*;
+use crate::msg_sink::local_file::{LOG_DIR, MultiLineMode, format_record};
+use crate::snapshot::SnapshotTrigger;
+use regex::Regex;
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::process::{Command, Stdio};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+static ALERT_FILE: &str = "alerts.log";
+static MAX_ALERT_FILE_SIZE: u64 = 1024 * 1024;
+static DEFAULT_PRIORITY_DURATION: Duration = Duration::from_secs(60);
+static ACTION_QUEUE_SIZE: usize = 256;
+
+/// What a rule does once its condition holds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TriggerAction {
+    Snapshot,
+    Exec,
+    Alert,
+    Priority,
+}
+
+impl TriggerAction {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "snapshot" => Some(TriggerAction::Snapshot),
+            "exec" => Some(TriggerAction::Exec),
+            "alert" => Some(TriggerAction::Alert),
+            "priority" => Some(TriggerAction::Priority),
+            _ => None,
+        }
+    }
+}
+
+/// Hook runs and alert writes, handed to the action thread.
+enum ActionJob {
+    Hook {
+        name: String,
+        command: Vec<String>,
+        pid: u32,
+        message: String,
+    },
+    Alert {
+        path: String,
+        text: String,
+    },
+}
+
+/// Runs hooks and writes alerts on a dedicated thread, so a noisy rule or
+/// slow storage only delays other actions. Jobs beyond the queue size are
+/// dropped.
+struct ActionWorker(SyncSender<ActionJob>);
+
+impl ActionWorker {
+    fn spawn() -> Self {
+        let (sender, receiver) = mpsc::sync_channel(ACTION_QUEUE_SIZE);
+        thread::spawn(move || run_actions(receiver));
+        ActionWorker(sender)
+    }
+
+    fn push(&self, job: ActionJob) {
+        if let Err(TrySendError::Full(_)) = self.0.try_send(job) {
+            logw!(LOG_TAG, "[Trigger] Action queue full, dropping an action");
+        }
+    }
+}
+
+fn run_actions(receiver: Receiver<ActionJob>) {
+    while let Ok(job) = receiver.recv() {
+        match job {
+            ActionJob::Hook {
+                name,
+                command,
+                pid,
+                message,
+            } => run_hook(&name, &command, pid, &message),
+            ActionJob::Alert { path, text } => {
+                if let Err(e) = write_alert(&path, &text) {
+                    loge!(LOG_TAG, "[Trigger] Failed to write alert: {}", e);
+                }
+            }
+        }
+    }
+}
+
+/// One `[trigger <name>]` section: a match condition, a rate over a window
+/// and the actions to run when the rate is exceeded.
+struct TriggerRule {
+    name: String,
+    pattern: Option<Regex>,
+    min_priority: Option<LogPriority>,
+    pids: Vec<u32>,
+    tags: Vec<String>,
+    /// Fires when more than `count` messages matched within `window`.
+    count: usize,
+    window: Duration,
+    cooldown: Duration,
+    actions: Vec<TriggerAction>,
+    command: Vec<String>,
+    alert_file: String,
+    set_priority: LogPriority,
+    priority_duration: Duration,
+    /// Match times per pid, newest last.
+    hits: HashMap<u32, VecDeque<Instant>>,
+    last_fired: HashMap<u32, Instant>,
+}
+
+impl TriggerRule {
+    fn from_section(section: &Section) -> Result<Self, ConfigError> {
+        let name = section
+            .name
+            .as_deref()
+            .ok_or_else(|| section.invalid("name", ""))?;
+        let pattern = match section.get("pattern") {
+            Some(pattern) => {
+                Some(Regex::new(pattern).map_err(|_| section.invalid("pattern", pattern))?)
+            }
+            None => None,
+        };
+        let min_priority = match section.get("priority") {
+            Some(priority) => Some(
+                priority
+                    .parse()
+                    .map_err(|_| section.invalid("priority", priority))?,
+            ),
+            None => None,
+        };
+        let mut pids = Vec::new();
+        for pid in section.get_list("pids") {
+            pids.push(pid.parse().map_err(|_| section.invalid("pids", pid))?);
+        }
+        let mut actions = Vec::new();
+        for action in section.get_list("actions") {
+            actions.push(
+                TriggerAction::from_name(action)
+                    .ok_or_else(|| section.invalid("actions", action))?,
+            );
+        }
+        if actions.is_empty() {
+            return Err(section.invalid("actions", ""));
+        }
+        let command: Vec<String> = section
+            .get("exec")
+            .unwrap_or_default()
+            .split_whitespace()
+            .map(str::to_string)
+            .collect();
+        if actions.contains(&TriggerAction::Exec) && command.is_empty() {
+            return Err(section.invalid("exec", ""));
+        }
+        let set_priority = match section.get("set_priority") {
+            Some(priority) => priority
+                .parse()
+                .map_err(|_| section.invalid("set_priority", priority))?,
+            None => LogPriority::Warn,
+        };
+        let window = Duration::from_secs(section.get_parsed("window_s")?.unwrap_or(10));
+        Ok(TriggerRule {
+            name: name.to_string(),
+            pattern,
+            min_priority,
+            pids,
+            tags: section
+                .get_list("tags")
+                .iter()
+                .map(|t| t.to_string())
+                .collect(),
+            count: section.get_parsed("count")?.unwrap_or(0),
+            window,
+            cooldown: section
+                .get_parsed("cooldown_s")?
+                .map(Duration::from_secs)
+                .unwrap_or(window),
+            actions,
+            command,
+            alert_file: section
+                .get("alert_file")
+                .map(str::to_string)
+                .unwrap_or_else(|| format!("{}/{}", LOG_DIR, ALERT_FILE)),
+            set_priority,
+            priority_duration: section
+                .get_parsed("priority_duration_s")?
+                .map(Duration::from_secs)
+                .unwrap_or(DEFAULT_PRIORITY_DURATION),
+            hits: HashMap::new(),
+            last_fired: HashMap::new(),
+        })
+    }
+
+    fn matches(&self, message: &LogMessage) -> bool {
+        self.min_priority.is_none_or(|p| message.priority >= p)
+            && (self.pids.is_empty() || self.pids.contains(&message.pid))
+            && (self.tags.is_empty() || message.tag.as_ref().is_some_and(|t| self.tags.contains(t)))
+            && self
+                .pattern
+                .as_ref()
+                .is_none_or(|pattern| pattern.is_match(&message.message))
+    }
+
+    /// Records a match and returns the number of matches in the window when
+    /// the rule fires. Only the last `count + 1` matches are kept, which is
+    /// all it takes to fire once the cooldown is over.
+    fn hit(&mut self, pid: u32, now: Instant) -> Option<usize> {
+        let hits = self.hits.entry(pid).or_default();
+        hits.push_back(now);
+        while hits.len() > self.count + 1
+            || hits
+                .front()
+                .is_some_and(|t| now.saturating_duration_since(*t) > self.window)
+        {
+            hits.pop_front();
+        }
+        let count = hits.len();
+        if count <= self.count {
+            return None;
+        }
+        if self
+            .last_fired
+            .get(&pid)
+            .is_some_and(|t| now.saturating_duration_since(*t) < self.cooldown)
+        {
+            return None;
+        }
+        self.last_fired.insert(pid, now);
+        hits.clear();
+        Some(count)
+    }
+
+    /// Forgets pids that have been quiet for longer than the window.
+    fn expire(&mut self, now: Instant) {
+        let keep = self.window.max(self.cooldown);
+        self.hits.retain(|_, hits| {
+            hits.back()
+                .is_some_and(|t| now.saturating_duration_since(*t) <= keep)
+        });
+        self.last_fired
+            .retain(|_, t| now.saturating_duration_since(*t) <= keep);
+    }
+}
+
+/// Evaluates the `[trigger <name>]` rules on every message leaving the global
+/// pipeline. Snapshots and priority raises happen inline; hooks and alerts go
+/// to the action thread.
+#[derive(Default)]
+pub struct TriggerEngine {
+    rules: Vec<TriggerRule>,
+    /// How multi-line messages are written to the alerts file.
+    multiline: MultiLineMode,
+    /// Raised priorities per pid and when they lapse.
+    raised: HashMap<u32, (LogPriority, Instant)>,
+    /// Started when a rule runs hooks or writes alerts.
+    actions: Option<ActionWorker>,
+}
+
+impl TriggerEngine {
+    pub fn from_config(config: &Config) -> Result<Self, ConfigError> {
+        let mut rules = Vec::new();
+        for section in config.sections("trigger") {
+            rules.push(TriggerRule::from_section(section)?);
+        }
+        let actions = rules
+            .iter()
+            .flat_map(|rule| &rule.actions)
+            .any(|action| matches!(action, TriggerAction::Exec | TriggerAction::Alert))
+            .then(ActionWorker::spawn);
+        Ok(TriggerEngine {
+            rules,
+            multiline: MultiLineMode::from_config(config)?,
+            raised: HashMap::new(),
+            actions,
+        })
+    }
+
+    /// Applies raised priorities to `message` and fires the rules it completes.
+    pub fn evaluate(&mut self, message: &mut LogMessage, snapshots: Option<&SnapshotTrigger>) {
+        let now = Instant::now();
+        if let Some((priority, until)) = self.raised.get(&message.pid) {
+            if now < *until {
+                message.priority = message.priority.max(*priority);
+            } else {
+                self.raised.remove(&message.pid);
+            }
+        }
+        for index in 0..self.rules.len() {
+            if !self.rules[index].matches(message) {
+                continue;
+            }
+            if let Some(count) = self.rules[index].hit(message.pid, now) {
+                self.fire(index, message, count, snapshots, now);
+            }
+        }
+    }
+
+    /// Drops state of idle pids; called on every pipeline tick.
+    pub fn tick(&mut self, now: Instant) {
+        for rule in &mut self.rules {
+            rule.expire(now);
+        }
+        self.raised.retain(|_, (_, until)| now < *until);
+    }
+
+    fn fire(
+        &mut self,
+        index: usize,
+        message: &LogMessage,
+        count: usize,
+        snapshots: Option<&SnapshotTrigger>,
+        now: Instant,
+    ) {
+        let rule = &self.rules[index];
+        logi!(
+            LOG_TAG,
+            "[Trigger] {} fired for pid {} ({} matches)",
+            rule.name,
+            message.pid,
+            count
+        );
+        for action in &rule.actions {
+            match action {
+                TriggerAction::Snapshot => match snapshots {
+                    Some(snapshots) => snapshots.request(
+                        format!("trigger {} ({} matches)", rule.name, count),
+                        Some(message.pid),
+                    ),
+                    None => logw!(LOG_TAG, "[Trigger] {}: snapshots are disabled", rule.name),
+                },
+                TriggerAction::Exec => {
+                    if let Some(actions) = &self.actions {
+                        actions.push(ActionJob::Hook {
+                            name: rule.name.clone(),
+                            command: rule.command.clone(),
+                            pid: message.pid,
+                            message: message.message.clone(),
+                        });
+                    }
+                }
+                TriggerAction::Alert => {
+                    // every line of a multi-line record names its trigger
+                    let text = format_record(message, self.multiline)
+                        .lines()
+                        .map(|line| format!("trigger={} matches={} {}", rule.name, count, line))
+                        .collect::<Vec<_>>()
+                        .join("\n");
+                    if let Some(actions) = &self.actions {
+                        actions.push(ActionJob::Alert {
+                            path: rule.alert_file.clone(),
+                            text,
+                        });
+                    }
+                }
+                TriggerAction::Priority => {
+                    self.raised.insert(
+                        message.pid,
+                        (rule.set_priority, now + rule.priority_duration),
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// Runs the hook without waiting for it; the trigger details are passed in
+/// the environment.
+fn run_hook(name: &str, command: &[String], pid: u32, message: &str) {
+    let spawned = Command::new(&command[0])
+        .args(&command[1..])
+        .env("NOTCAT_TRIGGER", name)
+        .env("NOTCAT_PID", pid.to_string())
+        .env("NOTCAT_MESSAGE", message)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn();
+    match spawned {
+        Ok(mut child) => {
+            thread::spawn(move || child.wait());
+        }
+        Err(e) => loge!(
+            LOG_TAG,
+            "[Trigger] Failed to run hook {}: {}",
+            command[0],
+            e
+        ),
+    }
+}
+
+/// Appends to the alerts file, keeping one previous generation.
+fn write_alert(path: &str, alert: &str) -> io::Result<()> {
+    if fs::metadata(path).is_ok_and(|m| m.len() >= MAX_ALERT_FILE_SIZE) {
+        fs::rename(path, format!("{}.1", path))?;
+    }
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(file, "{}", alert)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn engine(text: &str) -> TriggerEngine {
+        TriggerEngine::from_config(&Config::parse(text).unwrap()).unwrap()
+    }
+
+    fn message(pid: u32, priority: LogPriority, text: &str) -> LogMessage {
+        LogMessage {
+            pid,
+            uid: None,
+            priority,
+            timestamp: LogTimeStamp::now(),
+            tag: Some(String::from("netd")),
+            message: String::from(text),
+            fields: Vec::new(),
+            trace: None,
+        }
+    }
+
+    #[test]
+    fn alerts_are_written_off_the_calling_thread() {
+        let path = std::env::temp_dir().join(format!("notcat_alerts_{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let mut engine = engine(&format!(
+            "[trigger oom]\npattern = out of memory\nactions = alert\nalert_file = {}\n",
+            path.display()
+        ));
+        engine.evaluate(
+            &mut message(7, LogPriority::Error, "out of memory\nat alloc"),
+            None,
+        );
+        let mut alert = String::new();
+        for _ in 0..100 {
+            alert = fs::read_to_string(&path).unwrap_or_default();
+            if alert.ends_with('\n') {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        let _ = fs::remove_file(&path);
+        let lines: Vec<_> = alert.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("trigger=oom matches=1 [7] E "));
+        assert!(lines[0].ends_with("netd: out of memory"));
+        assert!(lines[1].ends_with("netd: at alloc"));
+    }
+
+    fn rule(text: &str) -> TriggerRule {
+        let config = Config::parse(text).unwrap();
+        TriggerRule::from_section(config.section("trigger").unwrap()).unwrap()
+    }
+
+    #[test]
+    fn rules_match_priority_pid_tag_and_pattern() {
+        let rule = rule(
+            "[trigger t]\npattern = ^oom\npriority = warn\npids = 7, 8\ntags = netd\n\
+             actions = priority\n",
+        );
+        assert!(rule.matches(&message(7, LogPriority::Error, "oom killer")));
+        assert!(!rule.matches(&message(7, LogPriority::Info, "oom killer")));
+        assert!(!rule.matches(&message(9, LogPriority::Error, "oom killer")));
+        assert!(!rule.matches(&message(8, LogPriority::Error, "no oom")));
+        let mut other_tag = message(8, LogPriority::Error, "oom");
+        other_tag.tag = Some(String::from("vold"));
+        assert!(!rule.matches(&other_tag));
+    }
+
+    #[test]
+    fn hits_fire_above_count_within_window_and_cooldown() {
+        let mut rule =
+            rule("[trigger t]\ncount = 2\nwindow_s = 10\ncooldown_s = 30\nactions = priority\n");
+        let start = Instant::now();
+        let at = |s: u64| start + Duration::from_secs(s);
+        assert_eq!(rule.hit(1, at(0)), None);
+        assert_eq!(rule.hit(1, at(1)), None);
+        // the first hit has left the window
+        assert_eq!(rule.hit(1, at(11)), None);
+        assert_eq!(rule.hit(1, at(11)), Some(3));
+        // pids are counted apart
+        assert_eq!(rule.hit(2, at(11)), None);
+        // hits during the cooldown are capped and fire once it is over
+        for s in 13..20 {
+            assert_eq!(rule.hit(1, at(s)), None);
+            assert!(rule.hits[&1].len() <= 3);
+        }
+        assert_eq!(rule.hit(1, at(42)), None);
+        assert_eq!(rule.hit(1, at(42)), None);
+        assert_eq!(rule.hit(1, at(43)), Some(3));
+    }
+
+    #[test]
+    fn priority_action_raises_later_messages_of_the_pid() {
+        let mut engine = engine(
+            "[trigger t]\npattern = oom\nactions = priority\nset_priority = error\n\
+             priority_duration_s = 60\n",
+        );
+        let mut first = message(7, LogPriority::Debug, "oom");
+        engine.evaluate(&mut first, None);
+        assert_eq!(first.priority, LogPriority::Debug);
+        let mut later = message(7, LogPriority::Debug, "later");
+        engine.evaluate(&mut later, None);
+        assert_eq!(later.priority, LogPriority::Error);
+        let mut other = message(8, LogPriority::Debug, "later");
+        engine.evaluate(&mut other, None);
+        assert_eq!(other.priority, LogPriority::Debug);
+        engine.tick(Instant::now() + Duration::from_secs(61));
+        let mut lapsed = message(7, LogPriority::Debug, "later");
+        engine.evaluate(&mut lapsed, None);
+        assert_eq!(lapsed.priority, LogPriority::Debug);
+    }
+}